@@ -1,56 +1,350 @@
 use tauri::{AppHandle, State};
 
 use crate::api::events::emit_state_snapshot;
-use crate::api::types::{AppState, StateSnapshot};
+use crate::api::types::{
+    ActionInfo, ActionOutcome, AppState, ItemsPage, MetricsSummary, SpellInfo, StateSnapshot,
+    UiAction,
+};
 use crate::core::app;
-use crate::core::state::EscapeResult;
+use crate::core::settings::Settings;
+use crate::core::state::{EscapeResult, QueryResult};
 
 #[tauri::command]
 pub fn get_state_snapshot(state: State<AppState>) -> StateSnapshot {
     state.snapshot()
 }
 
+#[tauri::command]
+pub fn get_metrics_summary(state: State<AppState>) -> MetricsSummary {
+    state.metrics_summary()
+}
+
+#[tauri::command]
+pub fn get_items_page(offset: usize, limit: usize, state: State<AppState>) -> ItemsPage {
+    state.items_page(offset, limit)
+}
+
+#[tauri::command]
+pub fn list_actions(state: State<AppState>) -> Result<Vec<ActionInfo>, String> {
+    state.list_actions()
+}
+
+#[tauri::command]
+pub fn list_spells(
+    include_provider: Option<bool>,
+    state: State<AppState>,
+) -> Result<Vec<SpellInfo>, String> {
+    state.list_spells(include_provider.unwrap_or(false))
+}
+
+#[tauri::command]
+pub fn set_spell_enabled(
+    spell_id: String,
+    enabled: bool,
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    app::set_spell_enabled(&state, &spell_id, enabled)?;
+    emit_state_snapshot(&handle, state.snapshot())
+        .map_err(|err| format!("failed to emit snapshot: {err}"))
+}
+
 #[tauri::command]
 pub async fn start_app(handle: AppHandle) -> Result<(), String> {
     app::initialize(&handle)
 }
 
+#[tauri::command]
+pub fn reload_spells(handle: AppHandle) -> Result<(), String> {
+    app::reload_spells(&handle)
+}
+
+#[tauri::command]
+pub fn refresh(handle: AppHandle, state: State<'_, AppState>) -> Result<(), String> {
+    let resources_dir = app::resolve_resources_dir(&handle);
+    state.refresh(&resources_dir, &handle)
+}
+
+#[tauri::command]
+pub fn cancel_loading(handle: AppHandle, state: State<'_, AppState>) {
+    if state.cancel_loading() {
+        let _ = emit_state_snapshot(&handle, state.snapshot());
+    }
+}
+
 #[tauri::command]
 pub fn set_query(query: String, handle: AppHandle, state: State<'_, AppState>) {
-    state.set_query(query);
+    let result = state.set_query(query);
     let _ = emit_state_snapshot(&handle, state.snapshot());
+    if let QueryResult::PushedSpell = result {
+        let resources_dir = app::resolve_resources_dir(&handle);
+        state.load_current_frame(&resources_dir, &handle);
+        return;
+    }
+
+    let is_dynamic = state
+        .get_current_spell()
+        .and_then(|s| s.is_dynamic)
+        .unwrap_or(false);
+    let generation = state.next_filter_generation();
     let state = state.inner().clone();
-    tauri::async_runtime::spawn(async move {
-        if state.filter_items() {
-            let _ = emit_state_snapshot(&handle, state.snapshot());
+
+    if is_dynamic {
+        let resources_dir = app::resolve_resources_dir(&handle);
+        tauri::async_runtime::spawn(async move {
+            if state.reload_dynamic_items(generation, &resources_dir).await {
+                emit_with_preview(&handle, &state);
+            }
+        });
+        return;
+    }
+
+    tauri::async_runtime::spawn_blocking(move || {
+        if state.filter_items_debounced(generation) {
+            emit_with_preview(&handle, &state);
         }
     });
 }
 
+// For pastes: resolves once the list reflects `query`, so a following
+// `invoke_action` can't act on stale items.
+#[tauri::command]
+pub async fn set_query_immediate(
+    query: String,
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let resources_dir = app::resolve_resources_dir(&handle);
+    if let QueryResult::PushedSpell = state.set_query_immediate(query) {
+        let _ = emit_state_snapshot(&handle, state.snapshot());
+        state.load_current_frame(&resources_dir, &handle);
+        return Ok(());
+    }
+
+    let is_dynamic = state
+        .get_current_spell()
+        .and_then(|s| s.is_dynamic)
+        .unwrap_or(false);
+    if is_dynamic {
+        let generation = state.next_filter_generation();
+        state.reload_dynamic_items(generation, &resources_dir).await;
+    }
+    emit_with_preview(&handle, &state);
+    Ok(())
+}
+
+// Dynamic spells still need their provider re-run for the empty query.
+#[tauri::command]
+pub fn clear_query(handle: AppHandle, state: State<'_, AppState>) {
+    let is_dynamic = state
+        .get_current_spell()
+        .and_then(|s| s.is_dynamic)
+        .unwrap_or(false);
+    if is_dynamic {
+        return set_query(String::new(), handle, state);
+    }
+    if state.clear_query() {
+        emit_with_preview(&handle, &state);
+    }
+}
+
 #[tauri::command]
 pub fn set_selection_delta(delta: isize, handle: AppHandle, state: State<'_, AppState>) {
     let changed = state.set_selection_delta(delta);
     if changed {
+        emit_with_preview(&handle, &state);
+    }
+}
+
+#[tauri::command]
+pub fn set_selection_page(
+    direction: i8,
+    page_size: usize,
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) {
+    if state.set_selection_page(direction, page_size) {
+        emit_with_preview(&handle, &state);
+    }
+}
+
+#[tauri::command]
+pub fn set_selection_index(index: usize, handle: AppHandle, state: State<'_, AppState>) {
+    if state.set_selection_index(index) {
+        emit_with_preview(&handle, &state);
+    }
+}
+
+#[tauri::command]
+pub fn set_scope(
+    index: usize,
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    if state.set_scope(index)? {
+        emit_with_preview(&handle, &state);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn toggle_mark(index: usize, handle: AppHandle, state: State<'_, AppState>) {
+    if state.toggle_mark(index) {
         let _ = emit_state_snapshot(&handle, state.snapshot());
     }
 }
 
+// For compound interactions (type then move, mark then move): one snapshot
+// for the whole batch instead of one per step.
+#[tauri::command]
+pub fn dispatch(
+    actions: Vec<UiAction>,
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Option<ActionOutcome>, String> {
+    let resources_dir = app::resolve_resources_dir(&handle);
+    let result = state.dispatch(
+        actions,
+        |label| state.invoke_action(label, &resources_dir, &handle),
+        |snapshot| {
+            let _ = emit_state_snapshot(&handle, snapshot);
+            state.spawn_preview(&resources_dir, &handle);
+        },
+    )?;
+
+    let is_dynamic = state
+        .get_current_spell()
+        .and_then(|s| s.is_dynamic)
+        .unwrap_or(false);
+    if result.pushed_spell {
+        state.load_current_frame(&resources_dir, &handle);
+    } else if result.query_changed && is_dynamic {
+        let generation = state.next_filter_generation();
+        let state = state.inner().clone();
+        tauri::async_runtime::spawn(async move {
+            if state.reload_dynamic_items(generation, &resources_dir).await {
+                emit_with_preview(&handle, &state);
+            }
+        });
+    }
+    Ok(result.outcome)
+}
+
 #[tauri::command]
 pub fn invoke_action(
     label: String,
     handle: AppHandle,
     state: State<'_, AppState>,
-) -> Result<(), String> {
+) -> Result<ActionOutcome, String> {
     let resources_dir = app::resolve_resources_dir(&handle);
     state.invoke_action(&label, &resources_dir, &handle)
 }
 
+#[tauri::command]
+pub fn confirm_action(
+    label: String,
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<ActionOutcome, String> {
+    let resources_dir = app::resolve_resources_dir(&handle);
+    state.confirm_action(&label, &resources_dir, &handle)
+}
+
+#[tauri::command]
+pub async fn invoke_action_blocking(
+    label: String,
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<ActionOutcome, String> {
+    let resources_dir = app::resolve_resources_dir(&handle);
+    state
+        .invoke_action_blocking(&label, &resources_dir, &handle)
+        .await
+}
+
+#[tauri::command]
+pub fn preview_action(label: String, state: State<'_, AppState>) -> Result<String, String> {
+    state.preview_action(&label)
+}
+
+// For a details pane that fetches previews only while it is open.
+#[tauri::command]
+pub async fn get_preview(
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    let resources_dir = app::resolve_resources_dir(&handle);
+    state.get_preview(&resources_dir).await
+}
+
+#[tauri::command]
+pub fn push_spell(
+    spell_id: String,
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let resources_dir = app::resolve_resources_dir(&handle);
+    state.push_spell_frame(&spell_id, &resources_dir, &handle)
+}
+
 #[tauri::command]
 pub fn handle_escape(handle: AppHandle, state: State<'_, AppState>) {
     match state.handle_escape() {
         EscapeResult::ClearedQuery | EscapeResult::PoppedFrame => {
-            let _ = emit_state_snapshot(&handle, state.snapshot());
+            emit_with_preview(&handle, &state);
         }
         EscapeResult::Noop => {}
     }
 }
+
+#[tauri::command]
+pub fn pop_frame(handle: AppHandle, state: State<'_, AppState>) -> bool {
+    let popped = state.pop_frame();
+    if popped {
+        emit_with_preview(&handle, &state);
+    }
+    popped
+}
+
+#[tauri::command]
+pub fn reset_to_root(handle: AppHandle, state: State<'_, AppState>) -> Result<(), String> {
+    state.collapse_to_root()?;
+    emit_state_snapshot(&handle, state.snapshot())
+        .map_err(|err| format!("failed to emit snapshot: {err}"))?;
+    state.spawn_preview(&app::resolve_resources_dir(&handle), &handle);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_settings(state: State<'_, AppState>) -> Settings {
+    state.settings()
+}
+
+// Takes effect right away for settings read on use (shell, wrap_selection, …);
+// window geometry applies on the next show.
+#[tauri::command]
+pub fn update_settings(
+    patch: serde_json::Value,
+    handle: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Settings, String> {
+    let settings = app::save_settings(&handle, &patch)?;
+    state.set_settings(settings.clone());
+    Ok(settings)
+}
+
+#[tauri::command]
+pub fn set_pinned(pinned: bool, handle: AppHandle) {
+    crate::set_pinned(&handle, pinned);
+}
+
+#[tauri::command]
+pub fn resize_to_rows(rows: usize, handle: AppHandle) {
+    crate::resize_to_rows(&handle, rows);
+}
+
+// For changes that may move the selection onto an item without a cached preview.
+fn emit_with_preview(handle: &AppHandle, state: &AppState) {
+    let _ = emit_state_snapshot(handle, state.snapshot());
+    state.spawn_preview(&app::resolve_resources_dir(handle), handle);
+}
@@ -1,9 +1,19 @@
 use tauri::{AppHandle, Emitter};
 
-use crate::api::types::StateSnapshot;
+use crate::api::types::{Item, ItemsAppended, StateSnapshot};
 
 pub const STATE_SNAPSHOT_EVENT: &str = "state-snapshot";
+pub const ITEMS_APPENDED_EVENT: &str = "items-appended";
 
 pub fn emit_state_snapshot(app: &AppHandle, snapshot: StateSnapshot) -> Result<(), tauri::Error> {
+    crate::update_tray_status(app, snapshot.status, snapshot.error_message.as_deref());
     app.emit(STATE_SNAPSHOT_EVENT, snapshot)
 }
+
+pub fn emit_items_appended(
+    app: &AppHandle,
+    frame_id: u64,
+    items: Vec<Item>,
+) -> Result<(), tauri::Error> {
+    app.emit(ITEMS_APPENDED_EVENT, ItemsAppended { frame_id, items })
+}
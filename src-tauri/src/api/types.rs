@@ -1,15 +1,27 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
 use serde::{Deserialize, Serialize};
 
+use crate::core::settings::Settings;
+use crate::core::usage::UsageStore;
+
 pub const STARTING_SPELL_ID: &str = "quickspell";
+pub const SPELL_ENTRY_TYPE: &str = "QUICKSPELL_SPELL";
+/// Section separators in a list: shown, but never selected or matched.
+pub const HEADER_ITEM_TYPE: &str = "HEADER";
 
 // AppState
 
 #[derive(Clone)]
 pub struct AppState {
     pub inner: Arc<RwLock<AppInner>>,
+    /// Bumped per query; a reload watches it to stop once superseded.
+    pub filter_generation: Arc<tokio::sync::watch::Sender<u64>>,
+    /// Bumped per selection; a running `preview_cmd` stops once superseded.
+    pub preview_generation: Arc<tokio::sync::watch::Sender<u64>>,
 }
 
 // AppInner (internal state)
@@ -20,6 +32,40 @@ pub struct AppInner {
     pub spells: HashMap<String, Spell>,
     pub stack: Vec<Frame>,
     pub next_frame_id: u64,
+    pub last_error: Option<String>,
+    pub recent_filters: VecDeque<FilterMetrics>,
+    pub usage: UsageStore,
+    pub usage_path: Option<PathBuf>,
+    /// Last non-empty query per spell id, for spells with `remember_query`.
+    pub remembered_queries: HashMap<String, String>,
+    pub settings: Settings,
+    /// Spell files skipped by the last load, with the reason.
+    pub spell_errors: Vec<(PathBuf, String)>,
+    /// `preview_cmd` output keyed by spell id and item data.
+    pub preview_cache: HashMap<String, String>,
+    /// Parsed provider output per spell and rendered run, for `cache_ttl_ms`.
+    pub provider_cache: HashMap<String, CachedItems>,
+    /// Shown instead of a list when the spells dir had nothing to load.
+    pub no_spells_message: Option<String>,
+    /// Action whose `confirm` prompt is showing, until it is accepted.
+    pub pending_confirm: Option<PendingConfirm>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedItems {
+    pub items: Vec<Item>,
+    pub loaded_at: Instant,
+}
+
+/// The selection an action's `confirm` prompt was rendered against;
+/// `confirm_action` refuses to run once it no longer matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingConfirm {
+    pub label: String,
+    pub frame_uid: u64,
+    pub query: String,
+    pub selected: Option<Item>,
+    pub marked: Vec<usize>,
 }
 
 // StateSnapshot
@@ -31,6 +77,8 @@ pub struct StateSnapshot {
     #[serde(rename = "noOfSpells")]
     pub no_of_spells: usize,
     pub spell_names: Vec<String>,
+    /// One entry per stack frame, root first.
+    pub frames: Vec<FrameSummary>,
     pub top_items: Vec<Item>,
     pub query: String,
     #[serde(rename = "isFiltering")]
@@ -39,6 +87,48 @@ pub struct StateSnapshot {
     pub selected_item: Option<SelectedItem>,
     #[serde(rename = "totalItems")]
     pub total_items: usize,
+    pub error_message: Option<String>,
+    /// Latest `filter_items` run; only sent with the `debug_metrics` setting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_filter_micros: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_filter_item_count: Option<usize>,
+    /// `[path, message]` per spell file that failed to load.
+    pub spell_errors: Vec<(PathBuf, String)>,
+    /// `preview_cmd` output for the selected item, once it has run.
+    pub preview: Option<String>,
+    /// Streamed items are still arriving; `total_items` is the count so far.
+    pub is_loading_more: bool,
+    /// Marked indices into `top_items`, ascending.
+    pub marked_indices: Vec<usize>,
+    /// The spell's `empty_message` while it is ready with no items to show.
+    pub empty_message: Option<String>,
+    /// Fuzzy score of the selected item; `None` with an empty query.
+    pub selected_score: Option<f32>,
+    /// Names of the spell's `scopes`, in order.
+    pub scopes: Vec<String>,
+    /// Index into `scopes`; `None` when the spell has none.
+    pub active_scope: Option<usize>,
+    /// Items per `Type` over every match, not only those in `top_items`.
+    pub type_counts: HashMap<String, usize>,
+    /// Why the query matches nothing, e.g. an invalid pattern in `regex` mode.
+    pub query_error: Option<String>,
+    /// Why the dynamic spell's provider failed on the last query change.
+    pub reload_error: Option<String>,
+    /// Rows the window should fit; only sent with the `auto_height` setting.
+    pub visible_rows: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameSummary {
+    /// Frame uid, the one `items-appended` batches carry.
+    pub id: u64,
+    pub spell_id: String,
+    pub spell_name: String,
+    pub query: String,
+    /// Length of the frame's filtered list.
+    pub item_count: usize,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -54,6 +144,7 @@ pub struct SelectedItem {
 pub enum ActionType {
     Cmd,
     Spell,
+    OpenUrl,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -64,6 +155,92 @@ pub struct AvailableAction {
     pub action_type: ActionType,
 }
 
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActionInfo {
+    pub label: String,
+    pub kind: ActionType,
+}
+
+/// Returned by `list_spells`. `provider` is only filled in on request since
+/// provider commands may embed tokens.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpellInfo {
+    pub id: String,
+    pub name: String,
+    pub alias: Option<String>,
+    pub tags: Vec<String>,
+    pub enabled: bool,
+    pub is_streaming: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+}
+
+/// Result of `invoke_action`; `keep_open` is false when the window was hidden.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActionOutcome {
+    pub keep_open: bool,
+    /// The action has a `confirm` prompt and did not run yet; accepting it
+    /// means calling `confirm_action` with the same label.
+    pub needs_confirm: bool,
+    /// Rendered `confirm` prompt when `needs_confirm` is set.
+    pub prompt: Option<String>,
+}
+
+/// One step of a `dispatch` batch, applied in order before a single snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type")]
+pub enum UiAction {
+    SetQuery { query: String },
+    SelectDelta { delta: isize },
+    SelectIndex { index: usize },
+    ToggleMark { index: usize },
+    Invoke { label: String },
+}
+
+/// Result of `get_items_page`: a slice of the filtered list and its full length.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ItemsPage {
+    pub items: Vec<Item>,
+    pub total: usize,
+}
+
+/// Payload of `items-appended`: one streamed batch for frame `frame_id`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ItemsAppended {
+    pub frame_id: u64,
+    pub items: Vec<Item>,
+}
+
+// FilterMetrics
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterMetrics {
+    pub query: String,
+    pub items: usize,
+    pub results: usize,
+    pub applied: bool,
+    pub elapsed_micros: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSummary {
+    pub samples: usize,
+    pub avg_elapsed_micros: u64,
+    pub p50_elapsed_micros: u64,
+    pub p95_elapsed_micros: u64,
+    pub max_elapsed_micros: u64,
+    pub avg_items: usize,
+    pub avg_results: usize,
+    pub slowest: Option<FilterMetrics>,
+}
+
 // AppStatus
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -86,7 +263,22 @@ pub struct Frame {
     pub all_items: Vec<Item>,
     pub filtered_items: Vec<Item>,
     pub is_filtering: bool,
+    /// A streaming provider is still adding items to this frame.
+    pub is_loading_more: bool,
     pub selected_idx: usize,
+    /// Marked indices into `filtered_items`; cleared whenever those change.
+    pub selected_set: HashSet<usize>,
+    /// Active entry of the spell's `scopes`; ignored when it has none.
+    pub scope_idx: usize,
+    /// Set by `cancel_loading`: the running provider is killed and nothing
+    /// more is added until the frame is refreshed. Loaders wait on it
+    /// alongside the provider's output.
+    pub load_cancelled: tokio::sync::watch::Sender<bool>,
+    /// Items per type over every match, including those past the list limit.
+    pub type_counts: HashMap<String, usize>,
+    /// Why the last dynamic provider run failed; cleared by the next one
+    /// that succeeds.
+    pub reload_error: Option<String>,
 }
 
 // Action
@@ -101,6 +293,15 @@ pub enum Action {
         #[serde(rename = "if", default)]
         condition: Option<String>,
         cmd: String,
+        /// Stay open (and keep the stack) after the command succeeds.
+        #[serde(default)]
+        keep_open: Option<bool>,
+        /// Run once per marked item instead of only for the selection.
+        #[serde(default)]
+        multi: Option<bool>,
+        /// Templated question the user must accept before the action runs.
+        #[serde(default)]
+        confirm: Option<String>,
     },
     Spell {
         #[serde(default)]
@@ -108,9 +309,36 @@ pub enum Action {
         #[serde(rename = "if", default)]
         condition: Option<String>,
         spell: String,
+        #[serde(default)]
+        confirm: Option<String>,
+    },
+    OpenUrl {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(rename = "if", default)]
+        condition: Option<String>,
+        url: String,
+        #[serde(default)]
+        keep_open: Option<bool>,
+        #[serde(default)]
+        multi: Option<bool>,
+        #[serde(default)]
+        confirm: Option<String>,
     },
 }
 
+// Scope
+
+/// A named quick filter on a spell, switched with `set_scope`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scope {
+    pub name: String,
+    /// Condition (like an action's `if`) rendered per item, with the item as
+    /// the spell's selection. Without one the scope keeps every item.
+    #[serde(default)]
+    pub filter: Option<String>,
+}
+
 // SearchConfig
 
 #[derive(Debug, Clone, Copy, Deserialize, Default)]
@@ -127,32 +355,78 @@ pub enum SearchMode {
     #[default]
     Fuzzy,
     Exact,
+    /// Field must start with the query.
+    Prefix,
+    /// Query must appear as one contiguous run.
+    Substring,
+    /// Whole field must equal the query; only the first such item is kept.
+    Equals,
+    /// Query is a regular expression; an invalid one matches nothing.
+    Regex,
+}
+
+/// A single 1-indexed column (`field: 2`) or several (`field: [2, 3]`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SearchField {
+    Single(usize),
+    Multiple(Vec<usize>),
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct SearchConfig {
     #[serde(default = "default_field")]
-    pub field: usize, // 1-indexed
+    pub field: SearchField,
     #[serde(default)]
     pub scheme: SearchScheme,
     #[serde(default)]
     pub mode: SearchMode,
+    /// Match letter case exactly. Applies to every mode; with `exact` the query
+    /// must appear as a case-identical substring.
+    #[serde(default)]
+    pub case_sensitive: bool,
+    /// Fold accented letters to their base form in query and fields, so
+    /// `cafe` matches `café`. Not applied in `regex` mode.
+    #[serde(default)]
+    pub normalize_diacritics: bool,
 }
 
-fn default_field() -> usize {
-    1
+fn default_field() -> SearchField {
+    SearchField::Single(1)
 }
 
 impl Default for SearchConfig {
     fn default() -> Self {
         Self {
-            field: 1,
+            field: default_field(),
             scheme: SearchScheme::Plain,
             mode: SearchMode::Fuzzy,
+            case_sensitive: false,
+            normalize_diacritics: false,
         }
     }
 }
 
+// ItemFormat
+
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemFormat {
+    #[default]
+    Tsv,
+    Json,
+}
+
+// SpellSource
+
+/// Built-in item lists a spell can show instead of running its provider.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpellSource {
+    /// Recently opened spells, most recent first; MAIN opens the spell.
+    RecentSpells,
+}
+
 // Spell
 
 #[derive(Debug, Clone, Deserialize)]
@@ -161,17 +435,122 @@ pub struct Spell {
     pub name: String,
     pub id: String,
     pub enabled: bool,
-    pub provider: String,
+    /// May be left out when `items` or a `source` supply the list.
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// Item lines listed in the spell itself, used instead of running a
+    /// process when `provider` is unset or empty. `provider` wins when both
+    /// are set.
+    #[serde(default)]
+    pub items: Option<Vec<String>>,
+    /// File the spell was loaded from, rewritten by `set_spell_enabled`.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
     #[serde(default)]
     pub alias: Option<String>,
+    /// Categories like `git`, lowercased into a hidden 4th field of the root's
+    /// spell entries; a root searching `field: [2, 4]` matches them.
+    #[serde(default)]
+    pub tags: Vec<String>,
     #[serde(default)]
     pub is_streaming: Option<bool>,
+    /// Re-run the provider (with `QUICKSPELL_QUERY`) on every query change
+    /// instead of filtering the loaded items locally.
+    #[serde(default)]
+    pub is_dynamic: Option<bool>,
+    #[serde(default)]
+    pub format: ItemFormat,
+    /// Field separator for `tsv` output, defaults to a tab. `"\0"` splits on NUL.
+    #[serde(default)]
+    pub delimiter: Option<String>,
+    /// Decode `\n`, `\t` and `\\` in TSV Name and Data fields. Off by default so
+    /// literal backslashes survive.
+    #[serde(default)]
+    pub unescape_items: Option<bool>,
+    /// Keep TSV lines with fewer than three fields, filling the missing Name
+    /// and Data with empty strings. Off by default so format bugs show up as
+    /// skipped lines.
+    #[serde(default)]
+    pub lenient_parsing: Option<bool>,
+    /// Encoding of the provider output, e.g. `latin1`. Defaults to UTF-8 with
+    /// invalid bytes replaced.
+    #[serde(default)]
+    pub encoding: Option<String>,
+    /// Preview text template-resolved against the stack, returned by
+    /// `get_preview` instead of running `preview_cmd`.
     #[serde(default)]
     pub preview: Option<String>,
+    /// Command whose stdout previews the selected item, template-resolved like
+    /// actions. Runs in the background and is cached per item.
+    #[serde(default)]
+    pub preview_cmd: Option<String>,
+    /// Shown instead of a blank list when nothing matches, template-resolved
+    /// against the stack (e.g. `"No results for {{query}}"`).
+    #[serde(default)]
+    pub empty_message: Option<String>,
     #[serde(default)]
     pub search: Option<SearchConfig>,
+    /// Quick filters applied after the query, the first one active on open.
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
     #[serde(default)]
     pub actions: Vec<Action>,
+    /// Synthesize the items instead of running `provider`.
+    #[serde(default)]
+    pub source: Option<SpellSource>,
+    /// Root spell only: append an entry per enabled spell to the provider output.
+    /// Entries are typed `SPELL_ENTRY_TYPE` and open their spell on MAIN.
+    #[serde(default)]
+    pub include_spells: Option<bool>,
+    /// Boost frequently and recently launched items; with an empty query,
+    /// items are ordered by usage instead of provider order.
+    #[serde(default)]
+    pub rank_by_usage: Option<bool>,
+    /// Reopen the spell with its last non-empty query. Escape forgets it.
+    #[serde(default)]
+    pub remember_query: Option<bool>,
+    /// Escape goes back a frame even with a query typed; the query is only
+    /// cleared on the root frame.
+    #[serde(default)]
+    pub escape_pops_first: Option<bool>,
+    /// Runs for MAIN instead of "no matching action" when the filtered list is
+    /// empty or the matched action renders blank, e.g. a web search for the query.
+    #[serde(default)]
+    pub fallback_action: Option<Action>,
+    /// Rows (same format as provider TSV output) listed instead of the items
+    /// while the query is empty, e.g. `"HINT\tType to search…\t"`.
+    #[serde(default)]
+    pub placeholder_items: Option<Vec<String>>,
+    /// Provider working directory, template-resolved against the stack.
+    /// Relative paths are taken from the resources dir; `~` is the home dir.
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Command the provider's stdout is piped into; its stdout becomes the
+    /// item lines. Runs with the provider's shell, cwd and environment.
+    #[serde(default)]
+    pub transform: Option<String>,
+    /// Overrides the `shell` setting for this spell's provider and actions.
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Expand `${VAR}` in the provider and `CMD` actions before running them,
+    /// instead of relying on the shell.
+    #[serde(default)]
+    pub expand_env: Option<bool>,
+    /// Legacy fzf flags, translated onto `search` at load time.
+    /// See `search::apply_fzf_options` for the supported subset.
+    #[serde(default)]
+    pub fzf_options: Option<Vec<String>>,
+    /// Reuse the provider output for this long when the spell is reopened.
+    /// Not applied to streaming or dynamic spells.
+    #[serde(default)]
+    pub cache_ttl_ms: Option<u64>,
+    /// Re-run a failing provider up to this many times before showing the
+    /// error. Streaming providers only retry when they produced no items.
+    #[serde(default)]
+    pub retries: Option<u32>,
+    /// Pause between retries; defaults to 500ms.
+    #[serde(default)]
+    pub retry_delay_ms: Option<u64>,
 }
 
 // Item
@@ -184,44 +563,168 @@ pub struct Item {
     pub name: String,
     #[serde(rename = "Data")]
     pub data: String,
+    /// Columns after Data, in provider order.
+    #[serde(rename = "Extra", default)]
+    pub extra: Vec<String>,
+    #[serde(rename = "matchRanges", default)]
+    pub match_ranges: Vec<(usize, usize)>,
+    /// Fuzzy score from the last filter; `None` when shown unfiltered.
+    #[serde(skip)]
+    pub score: Option<u32>,
+    /// From the spell's `placeholder_items`: shown, but never selected.
+    #[serde(skip)]
+    pub placeholder: bool,
 }
 
 impl Item {
     pub fn from_line(line: &str) -> Option<Self> {
-        let mut fields = line.split('\t');
+        Self::from_delimited_line(line, "\t")
+    }
+
+    pub fn from_delimited_line(line: &str, delimiter: &str) -> Option<Self> {
+        let mut fields = line.split(delimiter);
         let item_type = fields.next()?;
         let name = fields.next()?;
         let data = fields.next()?;
+        Some(Self::from_fields(item_type, name, data, fields))
+    }
 
-        Some(Self {
+    /// Like `from_delimited_line`, with missing Name and Data left empty.
+    /// `None` only for an empty line.
+    pub fn from_partial_line(line: &str, delimiter: &str) -> Option<Self> {
+        if line.is_empty() {
+            return None;
+        }
+        let mut fields = line.split(delimiter);
+        let item_type = fields.next().unwrap_or_default();
+        let name = fields.next().unwrap_or_default();
+        let data = fields.next().unwrap_or_default();
+        Some(Self::from_fields(item_type, name, data, fields))
+    }
+
+    fn from_fields<'a>(
+        item_type: &str,
+        name: &str,
+        data: &str,
+        extra: impl Iterator<Item = &'a str>,
+    ) -> Self {
+        Self {
             item_type: item_type.to_string(),
             name: name.to_string(),
             data: data.to_string(),
+            extra: extra.map(str::to_string).collect(),
+            match_ranges: Vec::new(),
+            score: None,
+            placeholder: false,
+        }
+    }
+
+    /// Parses `{"type": ..., "name": ..., "data": ..., "extra": [...]}`; `extra` is
+    /// optional and other keys are ignored.
+    pub fn from_json_line(line: &str) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct JsonItem {
+            #[serde(rename = "type")]
+            item_type: String,
+            name: String,
+            data: String,
+            #[serde(default)]
+            extra: Vec<String>,
+        }
+
+        let parsed: JsonItem = serde_json::from_str(line).ok()?;
+        Some(Self {
+            item_type: parsed.item_type,
+            name: parsed.name,
+            data: parsed.data,
+            extra: parsed.extra,
+            match_ranges: Vec::new(),
+            score: None,
+            placeholder: false,
         })
     }
 
+    /// Decodes `\n`, `\t` and `\\` in Name and Data; other backslashes stay.
+    pub fn unescaped(mut self) -> Self {
+        self.name = unescape(&self.name);
+        self.data = unescape(&self.data);
+        self
+    }
+
+    /// Whether 0-indexed column `idx` resolves to Name, the displayed text:
+    /// Name itself or an extra column this item doesn't have.
+    pub fn field_is_name(&self, idx: usize) -> bool {
+        idx == 1 || (idx > 2 && self.extra.len() <= idx - 3)
+    }
+
+    pub fn is_header(&self) -> bool {
+        self.item_type == HEADER_ITEM_TYPE
+    }
+
+    /// Headers and placeholders are shown but skipped by the selection.
+    pub fn is_selectable(&self) -> bool {
+        !self.is_header() && !self.placeholder
+    }
+
     pub fn field(&self, idx: usize) -> &str {
         match idx {
             0 => &self.item_type,
             1 => &self.name,
             2 => &self.data,
-            _ => &self.name,
+            _ => self
+                .extra
+                .get(idx - 3)
+                .map(String::as_str)
+                .unwrap_or(&self.name),
         }
     }
 
     pub fn raw(&self) -> String {
-        format!("{}\t{}\t{}", self.item_type, self.name, self.data)
+        let mut raw = format!("{}\t{}\t{}", self.item_type, self.name, self.data);
+        for value in &self.extra {
+            raw.push('\t');
+            raw.push_str(value);
+        }
+        raw
     }
 }
 
+fn unescape(value: &str) -> String {
+    if !value.contains('\\') {
+        return value.to_string();
+    }
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
 // SpellLoadError
 
 #[derive(Debug)]
 pub enum SpellLoadError {
     ResourceNotFound(std::path::PathBuf),
     Io(std::io::Error),
-    Parse {
-        path: std::path::PathBuf,
-        error: serde_yaml::Error,
-    },
+}
+
+/// Spells that loaded, plus a message per file that was skipped.
+#[derive(Debug, Default)]
+pub struct LoadedSpells {
+    pub spells: HashMap<String, Spell>,
+    pub errors: Vec<(PathBuf, String)>,
 }
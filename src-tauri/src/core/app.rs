@@ -4,24 +4,37 @@ use std::path::{Path, PathBuf};
 
 use tauri::{async_runtime, path::BaseDirectory, AppHandle, Manager, State};
 
-use crate::api::types::{AppState, Spell, SpellLoadError};
+use crate::api::types::{Action, AppState, LoadedSpells, Spell, SpellLoadError};
+use crate::core::search;
+use crate::core::settings::{Settings, SETTINGS_FILE_NAME};
+use crate::core::template;
+use crate::core::usage;
+use crate::core::window::WINDOW_STATE_FILE_NAME;
 
 pub fn initialize(app: &AppHandle) -> Result<(), String> {
     let (spells_dir, resources_dir) = resolve_resource_dirs(app);
 
-    let spells =
+    let loaded =
         load_spells_from_dir(&spells_dir).map_err(|err| format!("failed to load spells: {err}"))?;
 
     let state: State<AppState> = app.state();
-    if state.begin_loading_with_spells(spells).is_err() {
+    if state
+        .begin_loading_with_spells(loaded.spells, &spells_dir)
+        .is_err()
+    {
         return Ok(()); // already started
     }
+    state.set_spell_errors(loaded.errors)?;
+    match resolve_usage_path(app) {
+        Ok(path) => state.load_usage(path),
+        Err(err) => eprintln!("usage ranking disabled: {err}"),
+    }
     state
         .emit_snapshot(app)
         .map_err(|err| format!("failed to emit loading snapshot: {err}"))?;
 
     let app_handle = app.clone();
-    async_runtime::spawn_blocking(move || {
+    async_runtime::spawn(async move {
         let state: State<AppState> = app_handle.state();
 
         let is_streaming = state
@@ -30,19 +43,22 @@ pub fn initialize(app: &AppHandle) -> Result<(), String> {
             .unwrap_or(false);
 
         let result = if is_streaming {
-            state.stream_items_for_current_frame(&resources_dir, &app_handle)
+            state
+                .stream_items_for_current_frame(&resources_dir, &app_handle, None)
+                .await
         } else {
-            state.finish_loading_with_items(&resources_dir)
+            state.finish_loading_with_items(&resources_dir).await
         };
 
         match result {
             Ok(()) => {
                 let _ = state.emit_snapshot(&app_handle);
+                state.spawn_preview(&resources_dir, &app_handle);
             }
             Err(err) => {
-                state.set_error();
-                let _ = state.emit_snapshot(&app_handle);
                 eprintln!("failed to load items: {err}");
+                state.set_error(err);
+                let _ = state.emit_snapshot(&app_handle);
             }
         }
     });
@@ -50,6 +66,64 @@ pub fn initialize(app: &AppHandle) -> Result<(), String> {
     Ok(())
 }
 
+/// Re-reads the user spells dir and swaps in the result, keeping the stack.
+/// On failure the previously loaded spells stay active.
+pub fn reload_spells(app: &AppHandle) -> Result<(), String> {
+    let (spells_dir, _) = resolve_resource_dirs(app);
+    let loaded = load_spells_from_dir(&spells_dir).map_err(|err| err.to_string())?;
+
+    let state: State<AppState> = app.state();
+    state.replace_spells(loaded.spells)?;
+    state.set_spell_errors(loaded.errors)?;
+    state.filter_items();
+    state
+        .emit_snapshot(app)
+        .map_err(|err| format!("failed to emit snapshot: {err}"))
+}
+
+/// Enables or disables `spell_id` and writes it to the spell's file. Memory
+/// is only updated once the file is written.
+pub fn set_spell_enabled(state: &AppState, spell_id: &str, enabled: bool) -> Result<(), String> {
+    let path = state.spell_path(spell_id)?;
+    write_spell_enabled(&path, enabled)?;
+    state.set_spell_enabled(spell_id, enabled)
+}
+
+// Edits the top-level `enabled:` line in place so comments and layout
+// survive; files without one (flow style, …) are re-serialized instead.
+fn write_spell_enabled(path: &Path, enabled: bool) -> Result<(), String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+    let updated = match replace_enabled_line(&content, enabled) {
+        Some(updated) => updated,
+        None => {
+            let mut spell: serde_yaml::Mapping = serde_yaml::from_str(&content)
+                .map_err(|err| format!("failed to parse {}: {err}", path.display()))?;
+            spell.insert("enabled".into(), enabled.into());
+            serde_yaml::to_string(&spell)
+                .map_err(|err| format!("failed to serialize {}: {err}", path.display()))?
+        }
+    };
+    fs::write(path, updated).map_err(|err| format!("failed to write {}: {err}", path.display()))
+}
+
+fn replace_enabled_line(content: &str, enabled: bool) -> Option<String> {
+    let mut found = false;
+    let lines: Vec<String> = content
+        .split_inclusive('\n')
+        .map(|line| {
+            if found || !line.starts_with("enabled:") {
+                return line.to_string();
+            }
+            found = true;
+            let comment = line.find(" #").map_or("", |idx| line[idx..].trim_end());
+            let ending = &line[line.trim_end_matches(['\r', '\n']).len()..];
+            format!("enabled: {enabled}{comment}{ending}")
+        })
+        .collect();
+    found.then(|| lines.concat())
+}
+
 pub fn resolve_resource_dirs(app: &AppHandle) -> (PathBuf, PathBuf) {
     let factory_resources_dir = resolve_factory_resources_dir(app);
     let user_resources_dir = match resolve_user_resources_dir(app) {
@@ -74,6 +148,24 @@ pub fn resolve_resources_dir(app: &AppHandle) -> PathBuf {
     resources_dir
 }
 
+/// The folder the tray's "Open Spells Folder" item opens.
+pub fn resolve_spells_folder(app: &AppHandle) -> PathBuf {
+    spells_folder(
+        resolve_user_resources_dir(app),
+        &resolve_factory_resources_dir(app),
+    )
+}
+
+fn spells_folder(user_resources_dir: Result<PathBuf, String>, factory_dir: &Path) -> PathBuf {
+    match user_resources_dir {
+        Ok(dir) => dir.join("spells"),
+        Err(err) => {
+            eprintln!("failed to resolve user resources dir, opening factory resources: {err}");
+            factory_dir.to_path_buf()
+        }
+    }
+}
+
 fn resolve_factory_resources_dir(app: &AppHandle) -> PathBuf {
     let dev_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources");
     if dev_dir.exists() {
@@ -110,6 +202,36 @@ fn resolve_user_resources_dir(app: &AppHandle) -> Result<PathBuf, String> {
     Ok(dir)
 }
 
+pub fn load_settings(app: &AppHandle) -> Settings {
+    match resolve_user_resources_dir(app) {
+        Ok(dir) => Settings::load(&dir.join(SETTINGS_FILE_NAME)),
+        Err(err) => {
+            eprintln!("using default settings: {err}");
+            Settings::default()
+        }
+    }
+}
+
+/// Applies `patch` to `settings.yml`; see `Settings::save_patch`.
+pub fn save_settings(app: &AppHandle, patch: &serde_json::Value) -> Result<Settings, String> {
+    let dir = resolve_user_resources_dir(app)?;
+    Settings::save_patch(&dir.join(SETTINGS_FILE_NAME), patch)
+}
+
+pub fn resolve_window_state_path(app: &AppHandle) -> Result<PathBuf, String> {
+    Ok(resolve_user_resources_dir(app)?.join(WINDOW_STATE_FILE_NAME))
+}
+
+fn resolve_usage_path(app: &AppHandle) -> Result<PathBuf, String> {
+    let dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("failed to resolve app data dir: {err}"))?;
+    fs::create_dir_all(&dir)
+        .map_err(|err| format!("failed to create app data dir {}: {err}", dir.display()))?;
+    Ok(dir.join(usage::USAGE_FILE_NAME))
+}
+
 fn sync_default_resources(factory_dir: &Path, user_dir: &Path) -> std::io::Result<()> {
     for subdir in ["spells", "providers"] {
         let src = factory_dir.join(subdir);
@@ -137,36 +259,169 @@ fn sync_default_resources(factory_dir: &Path, user_dir: &Path) -> std::io::Resul
     Ok(())
 }
 
-fn load_spells_from_dir(dir: &Path) -> Result<HashMap<String, Spell>, SpellLoadError> {
+// `provider` may only be left out when `items` or a `source` supply the list.
+fn parse_spell(content: &str) -> Result<Spell, String> {
+    let spell: Spell = serde_yaml::from_str(content).map_err(|err| err.to_string())?;
+    if spell.provider.is_none() && spell.items.is_none() && spell.source.is_none() {
+        return Err("missing field `provider`, `items` or `source`".to_string());
+    }
+    Ok(spell)
+}
+
+// The id of an already loaded spell with the same alias; aliases match
+// case-insensitively, so the first spell in load order keeps it.
+fn alias_owner(spells: &HashMap<String, Spell>, spell: &Spell) -> Option<String> {
+    let alias = spell.alias.as_deref().filter(|alias| !alias.is_empty())?;
+    spells
+        .values()
+        .find(|other| {
+            other
+                .alias
+                .as_deref()
+                .is_some_and(|other| other.eq_ignore_ascii_case(alias))
+        })
+        .map(|other| other.id.clone())
+}
+
+/// Invalid spell files are skipped and reported in `errors`, so one typo
+/// doesn't hide the other spells. Files load in name order and the first
+/// spell with a given id wins.
+fn load_spells_from_dir(dir: &Path) -> Result<LoadedSpells, SpellLoadError> {
     if !dir.exists() {
         return Err(SpellLoadError::ResourceNotFound(dir.to_path_buf()));
     }
 
-    let mut spells = HashMap::new();
+    let mut loaded = LoadedSpells::default();
 
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
 
+    for path in paths {
         if !path.is_file() {
             continue;
         }
 
         match path.extension().and_then(|ext| ext.to_str()) {
             Some("yml") | Some("yaml") => {
-                let content = fs::read_to_string(&path)?;
-                let spell: Spell =
-                    serde_yaml::from_str(&content).map_err(|error| SpellLoadError::Parse {
-                        path: path.clone(),
-                        error,
-                    })?;
-                spells.insert(spell.id.clone(), spell);
+                let parsed = fs::read_to_string(&path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|content| parse_spell(&content));
+                let mut spell = match parsed {
+                    Ok(spell) => spell,
+                    Err(err) => {
+                        eprintln!("skipping spell {}: {err}", path.display());
+                        loaded.errors.push((path, err));
+                        continue;
+                    }
+                };
+                if let Some(options) = spell.fzf_options.take() {
+                    let mut config = spell.search.take().unwrap_or_default();
+                    for warning in search::apply_fzf_options(&mut config, &options) {
+                        eprintln!("{}: {warning}", path.display());
+                    }
+                    spell.search = Some(config);
+                }
+                if spell.provider.as_deref().is_some_and(|p| !p.is_empty()) && spell.items.is_some()
+                {
+                    eprintln!(
+                        "{}: both provider and items are set, using provider",
+                        path.display()
+                    );
+                }
+                spell.path = Some(path.clone());
+                if loaded.spells.contains_key(&spell.id) {
+                    let message = format!(
+                        "duplicate spell id {} in {}, ignored",
+                        spell.id,
+                        path.display()
+                    );
+                    eprintln!("{message}");
+                    loaded.errors.push((path, message));
+                    continue;
+                }
+                if let Some(owner) = alias_owner(&loaded.spells, &spell) {
+                    let alias = spell.alias.take().unwrap_or_default();
+                    let message = format!(
+                        "alias {alias} of {} is already used by {owner}, ignored",
+                        spell.id
+                    );
+                    eprintln!("{}: {message}", path.display());
+                    loaded.errors.push((path.clone(), message));
+                }
+                for message in template_errors(&spell) {
+                    eprintln!("{}: {message}", path.display());
+                    loaded.errors.push((path.clone(), message));
+                }
+                loaded.spells.insert(spell.id.clone(), spell);
             }
             _ => continue,
         }
     }
 
-    Ok(spells)
+    Ok(loaded)
+}
+
+// Malformed templates otherwise only fail when the action runs. The spell
+// still loads; its other templates may be fine.
+fn template_errors(spell: &Spell) -> Vec<String> {
+    let mut templates: Vec<(String, &str)> = Vec::new();
+    for (idx, action) in spell.actions.iter().enumerate() {
+        let (body, condition, confirm) = match action {
+            Action::Cmd {
+                cmd,
+                condition,
+                confirm,
+                ..
+            } => (cmd, condition, confirm),
+            Action::Spell {
+                spell,
+                condition,
+                confirm,
+                ..
+            } => (spell, condition, confirm),
+            Action::OpenUrl {
+                url,
+                condition,
+                confirm,
+                ..
+            } => (url, condition, confirm),
+        };
+        templates.push((format!("action {idx}"), body));
+        if let Some(condition) = condition {
+            templates.push((format!("action {idx} condition"), condition));
+        }
+        if let Some(confirm) = confirm {
+            templates.push((format!("action {idx} confirm"), confirm));
+        }
+    }
+    for (name, field) in [
+        ("preview", &spell.preview),
+        ("preview_cmd", &spell.preview_cmd),
+        ("empty_message", &spell.empty_message),
+        ("cwd", &spell.cwd),
+    ] {
+        if let Some(value) = field {
+            templates.push((name.to_string(), value));
+        }
+    }
+
+    for scope in &spell.scopes {
+        if let Some(filter) = &scope.filter {
+            templates.push((format!("scope {}", scope.name), filter));
+        }
+    }
+
+    templates
+        .into_iter()
+        .filter_map(|(place, value)| {
+            template::check_template(value).err().map(|err| {
+                let template::TemplateError::Render(err) = err;
+                format!("spell {} has invalid template in {place}: {err}", spell.id)
+            })
+        })
+        .collect()
 }
 
 impl std::fmt::Display for SpellLoadError {
@@ -176,9 +431,6 @@ impl std::fmt::Display for SpellLoadError {
                 write!(f, "spells directory not found at {}", path.display())
             }
             SpellLoadError::Io(err) => write!(f, "io error while loading spells: {err}"),
-            SpellLoadError::Parse { path, error } => {
-                write!(f, "failed to parse {}: {error}", path.display())
-            }
         }
     }
 }
@@ -192,12 +444,248 @@ impl From<std::io::Error> for SpellLoadError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::types::AppStatus;
 
     #[test]
     fn load_dev_spells() {
         let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         dir.push("resources/spells");
-        let spells = load_spells_from_dir(&dir).expect("failed to load spells from dev resources");
-        assert!(!spells.is_empty(), "expected at least one spell");
+        let loaded = load_spells_from_dir(&dir).expect("failed to load spells from dev resources");
+        assert!(!loaded.spells.is_empty(), "expected at least one spell");
+        assert!(loaded.errors.is_empty(), "{:?}", loaded.errors);
+    }
+
+    #[test]
+    fn spells_folder_falls_back_to_factory_resources() {
+        let factory = PathBuf::from("/factory/resources");
+
+        assert_eq!(
+            spells_folder(Ok(PathBuf::from("/config/quickspell")), &factory),
+            PathBuf::from("/config/quickspell/spells")
+        );
+        assert_eq!(
+            spells_folder(Err("no config dir".into()), &factory),
+            factory
+        );
+    }
+
+    #[test]
+    fn malformed_templates_are_reported_at_load() {
+        let dir = temp_spells_dir(
+            "templates",
+            &[(
+                "files.yml",
+                "{name: Files, id: files, enabled: true, provider: 'echo',
+                  actions: [
+                    {type: CMD, cmd: 'open {{shellquote context.files.selection.data}}'},
+                    {type: CMD, name: BAD, cmd: 'open {{context.files.selection.data'}]}",
+            )],
+        );
+
+        let loaded = load_spells_from_dir(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(loaded.spells.contains_key("files"));
+        assert_eq!(loaded.errors.len(), 1);
+        assert!(
+            loaded.errors[0]
+                .1
+                .starts_with("spell files has invalid template in action 1:"),
+            "{:?}",
+            loaded.errors
+        );
+    }
+
+    fn temp_spells_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("quickspell-spells-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn spell_enabled_toggle_is_written_to_its_file() {
+        let dir = temp_spells_dir(
+            "toggle",
+            &[
+                (
+                    "files.yml",
+                    "# Finder search\nname: Files\nid: files\nenabled: true # on by default\nprovider: 'echo'\n",
+                ),
+                ("web.yml", "{name: Web, id: web, enabled: false, provider: 'echo'}"),
+            ],
+        );
+        let state = AppState::new();
+        state
+            .replace_spells(load_spells_from_dir(&dir).unwrap().spells)
+            .unwrap();
+
+        set_spell_enabled(&state, "files", false).unwrap();
+        set_spell_enabled(&state, "web", true).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("files.yml")).unwrap(),
+            "# Finder search\nname: Files\nid: files\nenabled: false # on by default\nprovider: 'echo'\n"
+        );
+        let reloaded = load_spells_from_dir(&dir).unwrap().spells;
+        assert!(!reloaded["files"].enabled);
+        assert!(reloaded["web"].enabled);
+        assert_eq!(reloaded["web"].name, "Web");
+        assert_eq!(state.snapshot().no_of_spells, 1);
+
+        fs::remove_file(dir.join("web.yml")).unwrap();
+        assert!(set_spell_enabled(&state, "web", false).is_err());
+        assert!(state.inner.read().unwrap().spells["web"].enabled);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn invalid_spell_files_are_reported_and_skipped() {
+        let dir = temp_spells_dir(
+            "invalid",
+            &[
+                (
+                    "good.yml",
+                    "{name: Good, id: good, enabled: true, provider: 'echo'}",
+                ),
+                ("broken.yml", "name: [unterminated"),
+                ("no_provider.yml", "{name: Bad, id: bad, enabled: true}"),
+            ],
+        );
+
+        let loaded = load_spells_from_dir(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(loaded.spells.keys().collect::<Vec<_>>(), vec!["good"]);
+        assert_eq!(loaded.errors.len(), 2);
+        assert!(loaded.errors[0].0.ends_with("broken.yml"));
+        assert!(loaded.errors[1].0.ends_with("no_provider.yml"));
+        assert!(
+            loaded.errors[1].1.contains("provider"),
+            "{}",
+            loaded.errors[1].1
+        );
+    }
+
+    #[test]
+    fn spell_with_inline_items_loads_without_a_provider() {
+        let dir = temp_spells_dir(
+            "inline",
+            &[(
+                "power.yml",
+                "name: Power\nid: quickspell\nenabled: true\nsearch: {field: 2}\nitems:\n  - \"CMD\\tSleep\\tpmset sleepnow\"\n  - \"CMD\\tLock\\tpmset displaysleepnow\"\n",
+            )],
+        );
+        let loaded = load_spells_from_dir(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(loaded.errors.is_empty(), "{:?}", loaded.errors);
+
+        let state = AppState::new();
+        state
+            .begin_loading_with_spells(loaded.spells, &dir)
+            .unwrap();
+        async_runtime::block_on(state.finish_loading_with_items(&std::env::temp_dir())).unwrap();
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.status, AppStatus::Ready);
+        let names: Vec<_> = snapshot.top_items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["Sleep", "Lock"]);
+        assert_eq!(snapshot.top_items[0].data, "pmset sleepnow");
+    }
+
+    #[test]
+    fn spell_with_a_source_loads_without_a_provider() {
+        let dir = temp_spells_dir(
+            "source",
+            &[(
+                "quickspell.yml",
+                "{name: QuickSpell, id: quickspell, enabled: true, source: recent_spells}",
+            )],
+        );
+        let loaded = load_spells_from_dir(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(loaded.errors.is_empty(), "{:?}", loaded.errors);
+        assert_eq!(loaded.spells["quickspell"].provider, None);
+    }
+
+    #[test]
+    fn empty_spells_dir_is_ready_with_a_message() {
+        let dir = temp_spells_dir("empty", &[]);
+        let loaded = load_spells_from_dir(&dir).unwrap();
+        let state = AppState::new();
+        state
+            .begin_loading_with_spells(loaded.spells, &dir)
+            .unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.status, AppStatus::Ready);
+        assert_eq!(snapshot.no_of_spells, 0);
+        assert_eq!(
+            snapshot.empty_message,
+            Some(format!("No spells found in {}", dir.display()))
+        );
+    }
+
+    #[test]
+    fn duplicate_spell_ids_keep_the_first_file() {
+        let dir = temp_spells_dir(
+            "duplicate",
+            &[
+                (
+                    "a.yml",
+                    "{name: First, id: same, enabled: true, provider: 'echo'}",
+                ),
+                (
+                    "b.yml",
+                    "{name: Second, id: same, enabled: true, provider: 'echo'}",
+                ),
+            ],
+        );
+
+        let loaded = load_spells_from_dir(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(loaded.spells.len(), 1);
+        assert_eq!(loaded.spells["same"].name, "First");
+        assert_eq!(loaded.errors.len(), 1);
+        assert!(loaded.errors[0].0.ends_with("b.yml"));
+        assert!(loaded.errors[0]
+            .1
+            .starts_with("duplicate spell id same in "));
+    }
+
+    #[test]
+    fn duplicate_aliases_keep_the_first_file() {
+        let dir = temp_spells_dir(
+            "alias",
+            &[
+                (
+                    "a.yml",
+                    "{name: Files, id: files, enabled: true, alias: f, provider: 'echo'}",
+                ),
+                (
+                    "b.yml",
+                    "{name: Folders, id: folders, enabled: true, alias: F, provider: 'echo'}",
+                ),
+            ],
+        );
+
+        let loaded = load_spells_from_dir(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(loaded.spells["files"].alias.as_deref(), Some("f"));
+        assert_eq!(loaded.spells["folders"].alias, None);
+        assert_eq!(loaded.errors.len(), 1);
+        assert!(loaded.errors[0].0.ends_with("b.yml"));
+        assert_eq!(
+            loaded.errors[0].1,
+            "alias F of folders is already used by files, ignored"
+        );
     }
 }
@@ -4,7 +4,9 @@ use std::cell::RefCell;
 use nucleo_matcher::pattern::{AtomKind, CaseMatching, Normalization, Pattern};
 use nucleo_matcher::{Config, Matcher, Utf32Str};
 use rayon::prelude::*;
+use unicode_normalization::char::is_combining_mark;
 use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
 use crate::api::types::Item;
 
@@ -20,25 +22,44 @@ pub enum Scheme {
 pub enum Mode {
     Fuzzy,
     Exact,
+    Prefix,
+    Substring,
+    Equals,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct Options {
-    pub field: usize, // 1-indexed
+    pub fields: Vec<usize>, // 1-indexed, best-scoring field wins
     pub scheme: Scheme,
     pub mode: Mode,
+    pub case_sensitive: bool,
+    /// Drop accents from query and fields before matching, so `cafe` finds `café`.
+    pub normalize_diacritics: bool,
 }
 
 impl Default for Options {
     fn default() -> Self {
         Self {
-            field: 1,
+            fields: vec![1],
             scheme: Scheme::Default,
             mode: Mode::Fuzzy,
+            case_sensitive: false,
+            normalize_diacritics: false,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match<'a> {
+    pub item: &'a Item,
+    /// Matcher score plus any bonus; 0 for an empty query.
+    pub score: u32,
+    /// Byte ranges `[start, end)` of the matched characters in the searched field.
+    pub ranges: Vec<(usize, usize)>,
+    /// 1-indexed field the ranges belong to; 0 for an empty query.
+    pub field: usize,
+}
+
 thread_local! {
     static MATCHER_PLAIN: RefCell<MatcherCtx> = RefCell::new(MatcherCtx::new(Config::DEFAULT));
     static MATCHER_PATH: RefCell<MatcherCtx> = RefCell::new(MatcherCtx::new(Config::DEFAULT.match_paths()));
@@ -127,11 +148,16 @@ fn ascii_lower(b: u8) -> u8 {
     }
 }
 
-fn normalize_nfd<'a>(text: &'a str) -> Cow<'a, str> {
+// NFD, minus the combining marks when folding diacritics (`é` -> `e`).
+fn normalize_nfd(text: &str, fold_diacritics: bool) -> Cow<'_, str> {
     if text.is_ascii() {
         Cow::Borrowed(text)
     } else {
-        Cow::Owned(text.nfd().collect::<String>())
+        Cow::Owned(
+            text.nfd()
+                .filter(|ch| !fold_diacritics || !is_combining_mark(*ch))
+                .collect::<String>(),
+        )
     }
 }
 
@@ -163,37 +189,125 @@ fn basename_substring_start(
         .map(|pos| start + pos)
 }
 
-pub fn filter_items<'a>(items: &'a [Item], query: &str, options: &Options) -> Vec<&'a Item> {
+// Maps nucleo match indices (units of `Utf32Str` over the normalized haystack)
+// back to merged byte ranges of the original haystack.
+fn match_ranges(
+    original: &str,
+    normalized: &str,
+    fold_diacritics: bool,
+    ascii_units: bool,
+    mut indices: Vec<u32>,
+) -> Vec<(usize, usize)> {
+    indices.sort_unstable();
+    indices.dedup();
+
+    let unit_spans: Vec<(usize, usize)> = if ascii_units {
+        indices
+            .iter()
+            .map(|&idx| (idx as usize, idx as usize + 1))
+            .collect()
+    } else {
+        let graphemes: Vec<(usize, usize)> = normalized
+            .grapheme_indices(true)
+            .map(|(start, g)| (start, start + g.len()))
+            .collect();
+        indices
+            .iter()
+            .filter_map(|&idx| graphemes.get(idx as usize).copied())
+            .collect()
+    };
+
+    // NFD never shrinks text, so equal lengths mean no characters were decomposed.
+    // Folding does, so there the lengths prove nothing.
+    let spans: Vec<(usize, usize)> = if normalized.len() == original.len() && !fold_diacritics {
+        unit_spans
+    } else {
+        // (normalized end, original start, original end) per original char.
+        let mut segments = Vec::new();
+        let mut norm_offset = 0;
+        for (start, ch) in original.char_indices() {
+            norm_offset += std::iter::once(ch)
+                .nfd()
+                .filter(|ch| !fold_diacritics || !is_combining_mark(*ch))
+                .map(char::len_utf8)
+                .sum::<usize>();
+            segments.push((norm_offset, start, start + ch.len_utf8()));
+        }
+        let locate = |norm_byte: usize| {
+            let idx = segments.partition_point(|(end, _, _)| *end <= norm_byte);
+            segments
+                .get(idx.min(segments.len().saturating_sub(1)))
+                .copied()
+        };
+        unit_spans
+            .into_iter()
+            .filter_map(|(start, end)| {
+                let (_, orig_start, _) = locate(start)?;
+                let (_, _, orig_end) = locate(end.saturating_sub(1))?;
+                Some((orig_start, orig_end))
+            })
+            .collect()
+    };
+
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(spans.len());
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Items matching `query`, best first, with `bonus` added to each matching
+/// item's score.
+pub fn filter_items_with_bonus<'a, F>(
+    items: &'a [Item],
+    query: &str,
+    options: &Options,
+    bonus: F,
+) -> Vec<Match<'a>>
+where
+    F: Fn(&Item) -> u32 + Sync,
+{
     if query.is_empty() {
-        return items.iter().collect();
+        return items
+            .iter()
+            .map(|item| Match {
+                item,
+                score: 0,
+                ranges: Vec::new(),
+                field: 0,
+            })
+            .collect();
     }
 
     let atom_kind = match options.mode {
-        Mode::Exact => AtomKind::Substring,
+        Mode::Exact | Mode::Substring => AtomKind::Substring,
+        Mode::Prefix => AtomKind::Prefix,
         Mode::Fuzzy => AtomKind::Fuzzy,
+        Mode::Equals => AtomKind::Exact,
     };
 
     // Normalize query to NFD so it matches macOS filesystem form.
-    let query = query.nfd().collect::<String>();
+    let fold = options.normalize_diacritics;
+    let query = normalize_nfd(query, fold).into_owned();
     let query_lower = query.to_lowercase();
     let query_bytes = query_lower.as_bytes();
     let query_is_ascii = query.is_ascii();
 
-    let pattern = Pattern::new(
-        &query,
-        CaseMatching::Ignore,
-        Normalization::Smart,
-        atom_kind,
-    );
-    let field_idx = options.field.saturating_sub(1);
+    let case_matching = if options.case_sensitive {
+        CaseMatching::Respect
+    } else {
+        CaseMatching::Ignore
+    };
+    let pattern = Pattern::new(&query, case_matching, Normalization::Smart, atom_kind);
     let use_path = matches!(options.scheme, Scheme::Path);
 
     let mut ranked: Vec<_> = items
         .par_iter()
         .enumerate()
         .filter_map(|(idx, item)| {
-            let haystack = item.field(field_idx);
-            let haystack_norm = normalize_nfd(haystack);
             let matcher_tls = if use_path {
                 &MATCHER_PATH
             } else {
@@ -202,50 +316,144 @@ pub fn filter_items<'a>(items: &'a [Item], query: &str, options: &Options) -> Ve
             matcher_tls.with(|cell| {
                 let mut ctx = cell.borrow_mut();
                 let MatcherCtx { matcher, buf } = &mut *ctx;
-
-                let haystack_str = Utf32Str::new(haystack_norm.as_ref(), buf);
-                let score = if use_path && matches!(options.mode, Mode::Exact) {
-                    // Manual check for exact path mode to handle unicode normalization and ensure a match.
-                    if !haystack_norm.to_lowercase().contains(&query_lower) {
-                        return None;
+                let mut best: Option<(Rank, Match)> = None;
+                let item_bonus = bonus(item);
+
+                for field in &options.fields {
+                    let haystack = item.field(field.saturating_sub(1));
+                    let haystack_norm = normalize_nfd(haystack, fold);
+                    let haystack_str = Utf32Str::new(haystack_norm.as_ref(), buf);
+                    let ascii_units = matches!(haystack_str, Utf32Str::Ascii(_));
+                    let mut indices = Vec::new();
+                    let is_contiguous = matches!(options.mode, Mode::Exact | Mode::Substring);
+                    let score = if matches!(options.mode, Mode::Equals) {
+                        // Compared directly: the pattern would split the query on whitespace.
+                        let equal = if options.case_sensitive {
+                            haystack_norm.as_ref() == query.as_str()
+                        } else {
+                            haystack_norm.to_lowercase() == query_lower
+                        };
+                        if !equal {
+                            continue;
+                        }
+                        indices.extend(0..haystack_str.len() as u32);
+                        0
+                    } else if use_path && is_contiguous {
+                        // Manual check for exact path mode to handle unicode normalization and ensure a match.
+                        let found = if options.case_sensitive {
+                            haystack_norm.contains(query.as_str())
+                        } else {
+                            haystack_norm.to_lowercase().contains(&query_lower)
+                        };
+                        if !found {
+                            continue;
+                        }
+                        let _ = pattern.indices(haystack_str, matcher, &mut indices);
+                        0
+                    } else {
+                        match pattern.indices(haystack_str, matcher, &mut indices) {
+                            Some(score) => score,
+                            None => continue,
+                        }
                     }
-                    0
-                } else {
-                    pattern.score(haystack_str, matcher)?
-                };
-
-                let (length, pathname) = if use_path {
-                    let (len, last_delim) = path_metrics(haystack_norm.as_ref());
-                    let begin = basename_substring_start(
-                        haystack_norm.as_ref(),
-                        query_bytes,
-                        query_is_ascii,
-                        last_delim,
-                    )
-                    .unwrap_or(0);
-                    (len, pathname_distance(last_delim, begin))
-                } else {
-                    (haystack_norm.len(), 0)
-                };
-
-                let rank = if use_path {
-                    Rank::new_path(score, pathname, length, idx)
-                } else {
-                    Rank::new(score, pathname, length, idx)
-                };
-                Some((rank, item))
+                    .saturating_add(item_bonus);
+
+                    let (length, pathname) = if use_path {
+                        let (len, last_delim) = path_metrics(haystack_norm.as_ref());
+                        let begin = basename_substring_start(
+                            haystack_norm.as_ref(),
+                            query_bytes,
+                            query_is_ascii,
+                            last_delim,
+                        )
+                        .unwrap_or(0);
+                        (len, pathname_distance(last_delim, begin))
+                    } else {
+                        (haystack_norm.len(), 0)
+                    };
+
+                    let rank = if use_path {
+                        Rank::new_path(score, pathname, length, idx)
+                    } else {
+                        Rank::new(score, pathname, length, idx)
+                    };
+
+                    let is_better = match &best {
+                        Some((current, _)) => cmp_rank(&rank, current).is_lt(),
+                        None => true,
+                    };
+                    if is_better {
+                        let ranges =
+                            match_ranges(haystack, &haystack_norm, fold, ascii_units, indices);
+                        best = Some((
+                            rank,
+                            Match {
+                                item,
+                                score,
+                                ranges,
+                                field: *field,
+                            },
+                        ));
+                    }
+                }
+
+                best
             })
         })
         .collect();
 
+    // Ties fall back to the original index, so equal matches keep provider order.
     ranked.sort_by(|a, b| cmp_rank(&a.0, &b.0));
-    ranked.into_iter().map(|(_, item)| item).collect()
+    if matches!(options.mode, Mode::Equals) {
+        ranked.truncate(1);
+    }
+    ranked.into_iter().map(|(_, m)| m).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn equal_scores_keep_provider_order_across_calls() {
+        let items: Vec<Item> = (0..200)
+            .map(|i| Item::from_line(&format!("APP\tnote {i:03}\t{i}")).unwrap())
+            .collect();
+        let options = Options {
+            fields: vec![2],
+            ..Options::default()
+        };
+        let order = || -> Vec<String> {
+            filter_items_with_bonus(&items, "note", &options, |_| 0)
+                .into_iter()
+                .map(|m| m.item.data.clone())
+                .collect()
+        };
+
+        let first = order();
+        let expected: Vec<String> = (0..200).map(|i| i.to_string()).collect();
+        assert_eq!(first, expected);
+        for _ in 0..5 {
+            assert_eq!(order(), first);
+        }
+    }
+
+    #[test]
+    fn results_are_sorted_by_descending_score() {
+        let items = vec![
+            Item::from_line("APP\tn-o-t-e\ta").unwrap(),
+            Item::from_line("APP\tnote\tb").unwrap(),
+        ];
+        let options = Options {
+            fields: vec![2],
+            ..Options::default()
+        };
+
+        let results = filter_items_with_bonus(&items, "note", &options, |_| 0);
+        assert_eq!(results[0].item.data, "b");
+        assert!(results[0].score > results[1].score);
+    }
+
     #[test]
     fn pathname_distance_prefers_basename() {
         let path = "/a/b/file.rs";
@@ -278,14 +486,15 @@ mod tests {
         ];
 
         let options = Options {
-            field: 3,
+            fields: vec![3],
             scheme: Scheme::Path,
             mode: Mode::Fuzzy,
+            ..Options::default()
         };
 
-        let results = filter_items(&items, "repos", &options);
+        let results = filter_items_with_bonus(&items, "repos", &options, |_| 0);
         assert_eq!(
-            results.first().map(|item| item.raw()),
+            results.first().map(|m| m.item.raw()),
             Some("DIR\trepos\t/tmp/demo/repos/".to_string())
         );
     }
@@ -295,12 +504,13 @@ mod tests {
         let items =
             vec![Item::from_line("FILE\tksięgowość\t/home/user/księgowość/report.txt").unwrap()];
         let options = Options {
-            field: 3,
+            fields: vec![3],
             scheme: Scheme::Path,
             mode: Mode::Exact,
+            ..Options::default()
         };
 
-        let results = filter_items(&items, "księ", &options);
+        let results = filter_items_with_bonus(&items, "księ", &options, |_| 0);
         assert_eq!(results.len(), 1);
     }
 
@@ -311,12 +521,13 @@ mod tests {
             Item::from_line(&format!("FILE\t{}\t/home/user/{}/report.txt", nfd, nfd)).unwrap(),
         ];
         let options = Options {
-            field: 3,
+            fields: vec![3],
             scheme: Scheme::Path,
             mode: Mode::Exact,
+            ..Options::default()
         };
 
-        let results = filter_items(&items, "księ", &options);
+        let results = filter_items_with_bonus(&items, "księ", &options, |_| 0);
         assert_eq!(results.len(), 1);
     }
 
@@ -331,13 +542,14 @@ mod tests {
             Item::from_line(&format!("FILE\tnotes\t{file2}")).unwrap(),
         ];
         let options = Options {
-            field: 3,
+            fields: vec![3],
             scheme: Scheme::Path,
             mode: Mode::Exact,
+            ..Options::default()
         };
 
-        let results = filter_items(&items, "księ", &options);
-        let first = results.first().map(|item| item.data.as_str()).unwrap_or("");
+        let results = filter_items_with_bonus(&items, "księ", &options, |_| 0);
+        let first = results.first().map(|m| m.item.data.as_str()).unwrap_or("");
         assert!(first.contains(dir));
     }
 
@@ -352,16 +564,196 @@ mod tests {
             Item::from_line(&format!("FILE\tq1\t{file2}")).unwrap(),
         ];
         let options = Options {
-            field: 3,
+            fields: vec![3],
             scheme: Scheme::Path,
             mode: Mode::Exact,
+            ..Options::default()
         };
 
-        let results = filter_items(&items, "księ", &options);
-        let ordered: Vec<_> = results.into_iter().cloned().collect();
+        let results = filter_items_with_bonus(&items, "księ", &options, |_| 0);
+        let ordered: Vec<_> = results.into_iter().map(|m| m.item.clone()).collect();
         assert_eq!(
             ordered.first().map(|item| item.raw()),
             Some(format!("DIR\tksięgowość\t{dir}"))
         );
     }
+
+    #[test]
+    fn reports_match_ranges_for_searched_field() {
+        let items = vec![Item::from_line("APP\tFirefox\t/Applications/Firefox.app").unwrap()];
+        let options = Options {
+            fields: vec![2],
+            scheme: Scheme::Default,
+            mode: Mode::Exact,
+            ..Options::default()
+        };
+
+        let results = filter_items_with_bonus(&items, "fox", &options, |_| 0);
+        assert_eq!(results[0].ranges, vec![(4, 7)]);
+
+        let options = Options {
+            fields: vec![3],
+            ..options.clone()
+        };
+        let results = filter_items_with_bonus(&items, "fox", &options, |_| 0);
+        assert_eq!(results[0].ranges, vec![(18, 21)]);
+    }
+
+    #[test]
+    fn match_ranges_are_byte_offsets_into_unicode_field() {
+        let items = vec![Item::from_line("FILE\tzażółć księgi\t/tmp/x").unwrap()];
+        let options = Options {
+            fields: vec![2],
+            scheme: Scheme::Default,
+            mode: Mode::Exact,
+            ..Options::default()
+        };
+
+        let results = filter_items_with_bonus(&items, "księ", &options, |_| 0);
+        let name = &results[0].item.name;
+        let (start, end) = results[0].ranges[0];
+        assert_eq!(&name[start..end], "księ");
+    }
+
+    #[test]
+    fn empty_query_has_no_match_ranges() {
+        let items = vec![Item::from_line("APP\tFirefox\t/Applications/Firefox.app").unwrap()];
+        let results = filter_items_with_bonus(&items, "", &Options::default(), |_| 0);
+        assert_eq!(results.len(), 1);
+        assert!(results[0].ranges.is_empty());
+    }
+
+    #[test]
+    fn case_sensitivity_is_opt_in() {
+        let items = vec![
+            Item::from_line("SYM\tFoo\tsrc/foo.rs").unwrap(),
+            Item::from_line("SYM\tfoo\tsrc/bar.rs").unwrap(),
+        ];
+        let names = |results: Vec<Match>| -> Vec<String> {
+            let mut names: Vec<_> = results.into_iter().map(|m| m.item.name.clone()).collect();
+            names.sort();
+            names
+        };
+
+        for mode in [Mode::Fuzzy, Mode::Exact] {
+            let insensitive = Options {
+                fields: vec![2],
+                mode,
+                ..Options::default()
+            };
+            assert_eq!(
+                names(filter_items_with_bonus(&items, "Foo", &insensitive, |_| 0)),
+                vec!["Foo", "foo"]
+            );
+            assert_eq!(
+                names(filter_items_with_bonus(&items, "foo", &insensitive, |_| 0)),
+                vec!["Foo", "foo"]
+            );
+
+            let sensitive = Options {
+                case_sensitive: true,
+                ..insensitive.clone()
+            };
+            assert_eq!(
+                names(filter_items_with_bonus(&items, "Foo", &sensitive, |_| 0)),
+                vec!["Foo"]
+            );
+            assert_eq!(
+                names(filter_items_with_bonus(&items, "foo", &sensitive, |_| 0)),
+                vec!["foo"]
+            );
+        }
+    }
+
+    #[test]
+    fn diacritics_are_folded_only_when_enabled() {
+        let items = vec![
+            Item::from_line("APP	Café	/Applications/Café.app").unwrap(),
+            Item::from_line("DOC	naïve.txt	/tmp/naïve.txt").unwrap(),
+        ];
+        let names = |results: Vec<Match>| -> Vec<String> {
+            results.into_iter().map(|m| m.item.name.clone()).collect()
+        };
+
+        for mode in [Mode::Fuzzy, Mode::Exact, Mode::Equals] {
+            let folded = Options {
+                fields: vec![2],
+                mode,
+                normalize_diacritics: true,
+                ..Options::default()
+            };
+            assert_eq!(
+                names(filter_items_with_bonus(&items, "cafe", &folded, |_| 0)),
+                vec!["Café"]
+            );
+            assert_eq!(
+                names(filter_items_with_bonus(&items, "Café", &folded, |_| 0)),
+                vec!["Café"]
+            );
+        }
+
+        let equals = Options {
+            fields: vec![2],
+            mode: Mode::Equals,
+            ..Options::default()
+        };
+        assert!(filter_items_with_bonus(&items, "cafe", &equals, |_| 0).is_empty());
+
+        let folded = Options {
+            fields: vec![2],
+            mode: Mode::Exact,
+            normalize_diacritics: true,
+            ..Options::default()
+        };
+        let results = filter_items_with_bonus(&items, "naive", &folded, |_| 0);
+        assert_eq!(names(results.clone()), vec!["naïve.txt"]);
+        let name = &results[0].item.name;
+        let (start, end) = results[0].ranges[0];
+        assert_eq!(&name[start..end], "naïve");
+    }
+
+    #[test]
+    fn case_sensitive_exact_path_mode() {
+        let items = vec![
+            Item::from_line("FILE\tReadme\t/tmp/README.md").unwrap(),
+            Item::from_line("FILE\treadme\t/tmp/docs/readme.md").unwrap(),
+        ];
+        let options = Options {
+            fields: vec![3],
+            scheme: Scheme::Path,
+            mode: Mode::Exact,
+            case_sensitive: true,
+            ..Options::default()
+        };
+
+        let results = filter_items_with_bonus(&items, "README", &options, |_| 0);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].item.data, "/tmp/README.md");
+    }
+
+    #[test]
+    fn matches_best_of_multiple_fields() {
+        let items = vec![
+            Item::from_line("APP\tNotes\t/Applications/Notes.app").unwrap(),
+            Item::from_line("APP\tTerminal\t/System/Applications/Utilities/Terminal.app").unwrap(),
+        ];
+        let name_only = Options {
+            fields: vec![2],
+            mode: Mode::Exact,
+            ..Options::default()
+        };
+        assert!(filter_items_with_bonus(&items, "utilities", &name_only, |_| 0).is_empty());
+
+        let name_and_data = Options {
+            fields: vec![2, 3],
+            ..name_only
+        };
+        let results = filter_items_with_bonus(&items, "utilities", &name_and_data, |_| 0);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].item.name, "Terminal");
+        assert_eq!(results[0].ranges, vec![(21, 30)]);
+
+        let results = filter_items_with_bonus(&items, "notes", &name_and_data, |_| 0);
+        assert_eq!(results[0].ranges, vec![(0, 5)]);
+    }
 }
@@ -0,0 +1,125 @@
+use std::env;
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+const BUNDLE_IDENTIFIER: &str = "com.adrian.quickspell";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `[event] key=value ...`, with `elapsed_micros` shown as `time=1.2ms`.
+    #[default]
+    Text,
+    /// One JSON object per line with an `event` key.
+    Json,
+}
+
+/// Appends one entry to the quickspell log.
+pub fn write_entry(
+    format: LogFormat,
+    event: &str,
+    fields: &[(&str, Value)],
+) -> std::io::Result<()> {
+    let log_path = resolve_log_path()?;
+    if let Some(parent) = log_path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+
+    writeln!(file, "{}", format_entry(format, event, fields))
+}
+
+pub fn format_entry(format: LogFormat, event: &str, fields: &[(&str, Value)]) -> String {
+    match format {
+        LogFormat::Text => {
+            let mut line = format!("[{event}]");
+            for (key, value) in fields {
+                match (*key, value) {
+                    ("elapsed_micros", Value::Number(micros)) => {
+                        let elapsed = Duration::from_micros(micros.as_u64().unwrap_or(0));
+                        line.push_str(&format!(" time={elapsed:?}"));
+                    }
+                    (_, Value::String(text)) => line.push_str(&format!(" {key}={text:?}")),
+                    (_, other) => line.push_str(&format!(" {key}={other}")),
+                }
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut entry = Map::new();
+            entry.insert("event".to_string(), Value::from(event));
+            for (key, value) in fields {
+                entry.insert(key.to_string(), value.clone());
+            }
+            Value::Object(entry).to_string()
+        }
+    }
+}
+
+fn resolve_log_path() -> std::io::Result<PathBuf> {
+    let base = if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(PathBuf::from).map(|p| {
+            p.join("Library")
+                .join("Application Support")
+                .join(BUNDLE_IDENTIFIER)
+        })
+    } else if cfg!(target_os = "windows") {
+        env::var_os("APPDATA")
+            .map(PathBuf::from)
+            .map(|p| p.join(BUNDLE_IDENTIFIER))
+    } else {
+        env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|p| PathBuf::from(p).join(".local/share")))
+            .map(|p| p.join(BUNDLE_IDENTIFIER))
+    };
+
+    base.map(|p| p.join("quickspell.log"))
+        .ok_or_else(|| std::io::Error::other("could not resolve log directory for quickspell"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_fields() -> Vec<(&'static str, Value)> {
+        vec![
+            ("query", Value::from("no\"te")),
+            ("items", Value::from(120)),
+            ("results", Value::from(4)),
+            ("applied", Value::from(true)),
+            ("elapsed_micros", Value::from(950)),
+        ]
+    }
+
+    #[test]
+    fn json_entries_parse_back() {
+        let line = format_entry(LogFormat::Json, "filter", &filter_fields());
+        assert!(!line.contains('\n'));
+
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["event"], "filter");
+        assert_eq!(parsed["query"], "no\"te");
+        assert_eq!(parsed["items"], 120);
+        assert_eq!(parsed["results"], 4);
+        assert_eq!(parsed["applied"], true);
+        assert_eq!(parsed["elapsed_micros"], 950);
+    }
+
+    #[test]
+    fn text_entries_quote_strings() {
+        let line = format_entry(LogFormat::Text, "filter", &filter_fields());
+        assert_eq!(
+            line,
+            r#"[filter] query="no\"te" items=120 results=4 applied=true time=950µs"#
+        );
+    }
+}
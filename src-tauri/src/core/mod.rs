@@ -1,5 +1,11 @@
 pub mod app;
 pub mod fuzzy;
+pub mod logging;
 pub mod search;
+pub mod settings;
+pub mod shell;
 pub mod state;
 pub mod template;
+pub mod tray;
+pub mod usage;
+pub mod window;
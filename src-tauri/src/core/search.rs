@@ -1,18 +1,324 @@
-use crate::api::types::{Item, SearchConfig, SearchMode, SearchScheme};
+use std::sync::Mutex;
+
+use rayon::prelude::*;
+use regex::{Regex, RegexBuilder};
+
+use crate::api::types::{Item, SearchConfig, SearchField, SearchMode, SearchScheme};
 use crate::core::fuzzy;
 
-pub fn filter_items<'a>(items: &'a [Item], query: &str, config: &SearchConfig) -> Vec<&'a Item> {
+// Last compiled `regex` query, reused between filtering and snapshots.
+static REGEX_CACHE: Mutex<Option<CachedRegex>> = Mutex::new(None);
+
+struct CachedRegex {
+    query: String,
+    case_sensitive: bool,
+    regex: Result<Regex, String>,
+}
+
+pub fn filter_items<'a>(
+    items: &'a [Item],
+    query: &str,
+    config: &SearchConfig,
+) -> Vec<fuzzy::Match<'a>> {
+    filter_items_with_bonus(items, query, config, |_| 0)
+}
+
+pub fn filter_items_with_bonus<'a, F>(
+    items: &'a [Item],
+    query: &str,
+    config: &SearchConfig,
+    bonus: F,
+) -> Vec<fuzzy::Match<'a>>
+where
+    F: Fn(&Item) -> u32 + Sync,
+{
+    let fields = match &config.field {
+        SearchField::Single(field) => vec![*field],
+        SearchField::Multiple(fields) if !fields.is_empty() => fields.clone(),
+        SearchField::Multiple(_) => vec![1],
+    };
+    let mode = match config.mode {
+        SearchMode::Fuzzy => fuzzy::Mode::Fuzzy,
+        SearchMode::Exact => fuzzy::Mode::Exact,
+        SearchMode::Prefix => fuzzy::Mode::Prefix,
+        SearchMode::Substring => fuzzy::Mode::Substring,
+        SearchMode::Equals => fuzzy::Mode::Equals,
+        SearchMode::Regex => return filter_regex(items, query, &fields, config, bonus),
+    };
     let options = fuzzy::Options {
-        field: config.field,
+        fields,
         scheme: match config.scheme {
             SearchScheme::Plain => fuzzy::Scheme::Default,
             SearchScheme::Path => fuzzy::Scheme::Path,
         },
-        mode: match config.mode {
-            SearchMode::Fuzzy => fuzzy::Mode::Fuzzy,
-            SearchMode::Exact => fuzzy::Mode::Exact,
-        },
+        mode,
+        case_sensitive: config.case_sensitive,
+        normalize_diacritics: config.normalize_diacritics,
     };
 
-    fuzzy::filter_items(items, query, &options)
+    fuzzy::filter_items_with_bonus(items, query, &options, bonus)
+}
+
+/// Why `query` can't be used with `config`: the parse error of an invalid
+/// `regex` query. `None` for every other mode.
+pub fn query_error(query: &str, config: &SearchConfig) -> Option<String> {
+    if !matches!(config.mode, SearchMode::Regex) || query.is_empty() {
+        return None;
+    }
+    compiled_regex(query, config.case_sensitive).err()
+}
+
+fn compiled_regex(query: &str, case_sensitive: bool) -> Result<Regex, String> {
+    let Ok(mut cache) = REGEX_CACHE.lock() else {
+        return build_regex(query, case_sensitive);
+    };
+    match &*cache {
+        Some(cached) if cached.query == query && cached.case_sensitive == case_sensitive => {
+            cached.regex.clone()
+        }
+        _ => {
+            let regex = build_regex(query, case_sensitive);
+            *cache = Some(CachedRegex {
+                query: query.to_string(),
+                case_sensitive,
+                regex: regex.clone(),
+            });
+            regex
+        }
+    }
+}
+
+fn build_regex(query: &str, case_sensitive: bool) -> Result<Regex, String> {
+    RegexBuilder::new(query)
+        .case_insensitive(!case_sensitive)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+// Items with a field the query matches, in provider order unless a bonus
+// reorders them. An invalid pattern matches nothing.
+fn filter_regex<'a, F>(
+    items: &'a [Item],
+    query: &str,
+    fields: &[usize],
+    config: &SearchConfig,
+    bonus: F,
+) -> Vec<fuzzy::Match<'a>>
+where
+    F: Fn(&Item) -> u32 + Sync,
+{
+    if query.is_empty() {
+        return fuzzy::filter_items_with_bonus(items, query, &fuzzy::Options::default(), bonus);
+    }
+    let Ok(regex) = compiled_regex(query, config.case_sensitive) else {
+        return Vec::new();
+    };
+
+    let mut matches: Vec<fuzzy::Match> = items
+        .par_iter()
+        .filter_map(|item| {
+            fields.iter().find_map(|field| {
+                let haystack = item.field(field.saturating_sub(1));
+                if !regex.is_match(haystack) {
+                    return None;
+                }
+                let ranges = regex
+                    .find_iter(haystack)
+                    .filter(|m| !m.is_empty())
+                    .map(|m| (m.start(), m.end()))
+                    .collect();
+                Some(fuzzy::Match {
+                    item,
+                    score: bonus(item),
+                    ranges,
+                    field: *field,
+                })
+            })
+        })
+        .collect();
+    matches.sort_by_key(|m| std::cmp::Reverse(m.score));
+    matches
+}
+
+/// Maps the fzf flags quickspell understands onto `config` and returns a
+/// warning per flag it had to ignore. Supported: `-e`/`--exact`, `-i`/`+i`,
+/// `--nth=N[,M...]`, `--scheme=default|path` and `--tiebreak=index`
+/// (results already tie on provider order).
+pub fn apply_fzf_options(config: &mut SearchConfig, options: &[String]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for option in options {
+        let (flag, value) = match option.split_once('=') {
+            Some((flag, value)) => (flag, Some(value)),
+            None => (option.as_str(), None),
+        };
+        match (flag, value) {
+            ("-e" | "--exact", None) => config.mode = SearchMode::Exact,
+            ("-i", None) => config.case_sensitive = false,
+            ("+i", None) => config.case_sensitive = true,
+            ("--scheme", Some("default")) => config.scheme = SearchScheme::Plain,
+            ("--scheme", Some("path")) => config.scheme = SearchScheme::Path,
+            ("--tiebreak", Some("index")) => {}
+            ("-n" | "--nth", Some(value)) => match parse_nth(value) {
+                Some(fields) => config.field = SearchField::Multiple(fields),
+                None => warnings.push(format!("unsupported fzf option {option}")),
+            },
+            _ => warnings.push(format!("unsupported fzf option {option}")),
+        }
+    }
+    warnings
+}
+
+// Only plain positive field indexes; fzf ranges like `2..` are not supported.
+fn parse_nth(value: &str) -> Option<Vec<usize>> {
+    value
+        .split(',')
+        .map(|field| field.trim().parse::<usize>().ok().filter(|n| *n > 0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_accepts_single_index_or_list() {
+        let single: SearchConfig = serde_yaml::from_str("field: 3").unwrap();
+        assert!(matches!(single.field, SearchField::Single(3)));
+
+        let multiple: SearchConfig = serde_yaml::from_str("field: [2, 3]").unwrap();
+        assert!(matches!(multiple.field, SearchField::Multiple(ref f) if f == &vec![2, 3]));
+    }
+
+    #[test]
+    fn query_matching_only_data_is_found_when_listed() {
+        let items = vec![
+            Item::from_line("FILE\tnotes\t/Users/me/projects/notes.txt").unwrap(),
+            Item::from_line("FILE\ttodo\t/Users/me/todo.txt").unwrap(),
+        ];
+        let config: SearchConfig = serde_yaml::from_str("{field: [2, 3], mode: exact}").unwrap();
+
+        let results = filter_items(&items, "projects", &config);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].item.name, "notes");
+    }
+
+    #[test]
+    fn prefix_mode_requires_a_leading_match() {
+        let items = vec![
+            Item::from_line("UNIT\tbarfoo\tb").unwrap(),
+            Item::from_line("UNIT\tfoo\tf").unwrap(),
+        ];
+        let names = |mode: &str| -> Vec<String> {
+            let config: SearchConfig =
+                serde_yaml::from_str(&format!("{{field: 2, mode: {mode}}}")).unwrap();
+            filter_items(&items, "fo", &config)
+                .into_iter()
+                .map(|m| m.item.name.clone())
+                .collect()
+        };
+
+        assert_eq!(names("fuzzy").len(), 2);
+        assert_eq!(names("prefix"), vec!["foo"]);
+    }
+
+    #[test]
+    fn substring_mode_requires_a_contiguous_run() {
+        let items = vec![
+            Item::from_line("EMOJI\tfxo\ta").unwrap(),
+            Item::from_line("EMOJI\tinfo\tb").unwrap(),
+        ];
+        let fuzzy: SearchConfig = serde_yaml::from_str("{field: 2, mode: fuzzy}").unwrap();
+        let substring: SearchConfig = serde_yaml::from_str("{field: 2, mode: substring}").unwrap();
+
+        assert_eq!(filter_items(&items, "fo", &fuzzy).len(), 2);
+        let results = filter_items(&items, "fo", &substring);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].item.name, "info");
+    }
+
+    #[test]
+    fn equals_mode_matches_the_whole_field_only() {
+        let items = vec![
+            Item::from_line("COUNTRY	us	United States").unwrap(),
+            Item::from_line("COUNTRY	usa	United States of America").unwrap(),
+        ];
+        let names = |mode: &str, query: &str| -> Vec<String> {
+            let config: SearchConfig =
+                serde_yaml::from_str(&format!("{{field: 2, mode: {mode}}}")).unwrap();
+            filter_items(&items, query, &config)
+                .into_iter()
+                .map(|m| m.item.name.clone())
+                .collect()
+        };
+
+        assert_eq!(names("exact", "us"), vec!["us", "usa"]);
+        assert_eq!(names("equals", "us"), vec!["us"]);
+        assert_eq!(names("equals", "US"), vec!["us"]);
+        assert!(names("equals", "u").is_empty());
+        assert_eq!(names("equals", "").len(), 2);
+    }
+
+    #[test]
+    fn regex_mode_keeps_items_matching_the_pattern() {
+        let items = vec![
+            Item::from_line("LOG\tERROR disk full\t1").unwrap(),
+            Item::from_line("LOG\tinfo started\t2").unwrap(),
+            Item::from_line("LOG\terror: timeout after 30s\t3").unwrap(),
+        ];
+        let config: SearchConfig = serde_yaml::from_str("{field: 2, mode: regex}").unwrap();
+
+        let results = filter_items(&items, r"^error\b.*\d+s$", &config);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].item.data, "3");
+        assert_eq!(results[0].ranges, vec![(0, 24)]);
+        assert_eq!(filter_items(&items, "error", &config).len(), 2);
+        assert_eq!(query_error("error", &config), None);
+    }
+
+    #[test]
+    fn invalid_regex_matches_nothing_and_reports_why() {
+        let items = vec![Item::from_line("LOG\t(unclosed\t1").unwrap()];
+        let config: SearchConfig = serde_yaml::from_str("{field: 2, mode: regex}").unwrap();
+
+        assert!(filter_items(&items, "(unclosed", &config).is_empty());
+        assert!(query_error("(unclosed", &config).is_some());
+        let fuzzy: SearchConfig = serde_yaml::from_str("{field: 2}").unwrap();
+        assert_eq!(query_error("(unclosed", &fuzzy), None);
+    }
+
+    fn fzf(options: &[&str]) -> (SearchConfig, Vec<String>) {
+        let mut config = SearchConfig::default();
+        let options: Vec<String> = options.iter().map(|o| o.to_string()).collect();
+        let warnings = apply_fzf_options(&mut config, &options);
+        (config, warnings)
+    }
+
+    #[test]
+    fn fzf_exact_maps_to_exact_mode() {
+        let (config, warnings) = fzf(&["--exact"]);
+        assert!(matches!(config.mode, SearchMode::Exact));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn fzf_options_map_case_fields_and_scheme() {
+        let (config, warnings) = fzf(&["+i", "--nth=2,3", "--scheme=path", "--tiebreak=index"]);
+        assert!(config.case_sensitive);
+        assert!(matches!(config.field, SearchField::Multiple(ref f) if f == &vec![2, 3]));
+        assert!(matches!(config.scheme, SearchScheme::Path));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn unsupported_fzf_options_are_reported() {
+        let (config, warnings) = fzf(&["--tiebreak=length", "--nth=2..", "-e"]);
+        assert!(matches!(config.mode, SearchMode::Exact));
+        assert_eq!(
+            warnings,
+            vec![
+                "unsupported fzf option --tiebreak=length",
+                "unsupported fzf option --nth=2..",
+            ]
+        );
+    }
 }
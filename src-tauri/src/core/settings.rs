@@ -0,0 +1,154 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::logging::LogFormat;
+
+pub const SETTINGS_FILE_NAME: &str = "settings.yml";
+
+/// User preferences from `settings.yml` in the app config dir. Every key is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub window_width: Option<u32>,
+    #[serde(default)]
+    pub window_height: Option<u32>,
+    /// Center the window on the primary monitor, defaults to true.
+    #[serde(default)]
+    pub window_center: Option<bool>,
+    /// Reopen the window where it was last hidden instead of re-centering.
+    #[serde(default)]
+    pub restore_window_geometry: Option<bool>,
+    /// Shell for providers and `CMD` actions, e.g. `zsh`. Spells can override it.
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Include the last filter's timing in state snapshots.
+    #[serde(default)]
+    pub debug_metrics: Option<bool>,
+    /// `text` (default) or `json` lines in quickspell.log.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Also log provider runs and action invocations, not just filters.
+    #[serde(default)]
+    pub verbose_log: Option<bool>,
+    /// Arrow keys wrap from the last item to the first and back.
+    #[serde(default)]
+    pub wrap_selection: Option<bool>,
+    /// Spell id to open on launch instead of `quickspell`.
+    #[serde(default)]
+    pub start_spell: Option<String>,
+    /// Return to the root spell with an empty query whenever the window hides.
+    #[serde(default)]
+    pub clear_on_hide: Option<bool>,
+    /// Most frames `SPELL` actions may stack up, root included; defaults to 32.
+    #[serde(default)]
+    pub max_stack_depth: Option<usize>,
+    /// Shrink the window to fit the listed items, up to `window_height`.
+    #[serde(default)]
+    pub auto_height: Option<bool>,
+    /// Height of one result row for `auto_height`, in logical pixels; defaults to 44.
+    #[serde(default)]
+    pub row_height: Option<u32>,
+}
+
+impl Settings {
+    /// Missing file means defaults; a malformed one is reported and ignored.
+    pub fn load(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(content) if content.trim().is_empty() => Self::default(),
+            Ok(content) => serde_yaml::from_str(&content).unwrap_or_else(|err| {
+                eprintln!("ignoring malformed settings file {}: {err}", path.display());
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Merges `patch` (a JSON object) into the file at `path` and returns the
+    /// resulting settings. `null` removes a key; keys this version doesn't know
+    /// are kept. Nothing is written when the result wouldn't parse.
+    pub fn save_patch(path: &Path, patch: &serde_json::Value) -> Result<Self, String> {
+        let serde_json::Value::Object(patch) = patch else {
+            return Err("settings patch must be an object".to_string());
+        };
+
+        let mut file = match fs::read_to_string(path) {
+            Ok(content) => match serde_yaml::from_str(&content)
+                .map_err(|err| format!("settings file is malformed: {err}"))?
+            {
+                serde_yaml::Value::Mapping(mapping) => mapping,
+                serde_yaml::Value::Null => serde_yaml::Mapping::new(),
+                _ => return Err("settings file is not a mapping".to_string()),
+            },
+            Err(_) => serde_yaml::Mapping::new(),
+        };
+        for (key, value) in patch {
+            let key = serde_yaml::Value::String(key.clone());
+            if value.is_null() {
+                file.remove(&key);
+            } else {
+                let value = serde_yaml::to_value(value).map_err(|err| err.to_string())?;
+                file.insert(key, value);
+            }
+        }
+
+        let file = serde_yaml::Value::Mapping(file);
+        let settings: Self = serde_yaml::from_value(file.clone())
+            .map_err(|err| format!("invalid settings: {err}"))?;
+        let content = serde_yaml::to_string(&file).map_err(|err| err.to_string())?;
+        fs::write(path, content)
+            .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_partial_settings() {
+        let settings: Settings = serde_yaml::from_str("window_width: 800").unwrap();
+        assert_eq!(settings.window_width, Some(800));
+        assert_eq!(settings.window_height, None);
+        assert_eq!(settings.window_center, None);
+    }
+
+    fn temp_settings_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "quickspell-settings-{name}-{}.yml",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn missing_file_loads_defaults() {
+        let path = temp_settings_path("missing");
+        let _ = fs::remove_file(&path);
+        assert_eq!(Settings::load(&path), Settings::default());
+    }
+
+    #[test]
+    fn save_patch_round_trips_and_keeps_unknown_keys() {
+        let path = temp_settings_path("patch");
+        fs::write(&path, "window_width: 800\nfuture_option: [a, b]\n").unwrap();
+
+        let saved = Settings::save_patch(
+            &path,
+            &serde_json::json!({"window_width": null, "wrap_selection": true}),
+        )
+        .unwrap();
+        assert_eq!(saved.window_width, None);
+        assert_eq!(saved.wrap_selection, Some(true));
+        assert_eq!(Settings::load(&path), saved);
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("future_option"), "{content}");
+
+        let err = Settings::save_patch(&path, &serde_json::json!({"window_width": "wide"}));
+        assert!(err.unwrap_err().starts_with("invalid settings"));
+        assert_eq!(Settings::load(&path), saved);
+        let _ = fs::remove_file(&path);
+    }
+}
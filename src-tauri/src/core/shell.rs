@@ -0,0 +1,148 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+#[cfg(windows)]
+const DEFAULT_SHELL: &str = "cmd";
+#[cfg(not(windows))]
+const DEFAULT_SHELL: &str = "sh";
+
+/// Shell used to run provider scripts (and `CMD` actions when configured).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shell {
+    program: String,
+    flag: &'static str,
+}
+
+impl Shell {
+    /// `configured` comes from the spell's or the settings' `shell`; `None` or
+    /// blank means the platform default. Fails when the program can't be found.
+    pub fn resolve(configured: Option<&str>) -> Result<Self, String> {
+        let program = configured
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .unwrap_or(DEFAULT_SHELL);
+
+        if !program_exists(program) {
+            return Err(format!(
+                "shell `{program}` not found; check the `shell` setting"
+            ));
+        }
+
+        Ok(Self {
+            program: program.to_string(),
+            flag: shell_flag(program),
+        })
+    }
+
+    pub fn command(&self, script: &str) -> Command {
+        let mut command = Command::new(&self.program);
+        command.arg(self.flag).arg(script);
+        command
+    }
+}
+
+impl std::fmt::Display for Shell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.program)
+    }
+}
+
+fn shell_flag(program: &str) -> &'static str {
+    let is_cmd = Path::new(program)
+        .file_stem()
+        .is_some_and(|stem| stem.eq_ignore_ascii_case("cmd"));
+    if is_cmd {
+        "/C"
+    } else {
+        "-c"
+    }
+}
+
+/// Replaces `${VAR}` with the variable's value, so spells with `expand_env`
+/// behave the same whichever shell runs them. Unset variables become empty
+/// with a warning; a `${` without a closing brace is left as is.
+pub fn expand_env_vars(text: &str) -> String {
+    expand_vars(text, |name| env::var(name).ok())
+}
+
+fn expand_vars(text: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        expanded.push_str(&rest[..start]);
+        match lookup(name) {
+            Some(value) => expanded.push_str(&value),
+            None => eprintln!("environment variable {name} is not set, expanding to empty"),
+        }
+        rest = &rest[start + 3 + len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn program_exists(program: &str) -> bool {
+    if program.contains(['/', '\\']) {
+        return Path::new(program).is_file();
+    }
+
+    let Some(paths) = env::var_os("PATH") else {
+        return false;
+    };
+    env::split_paths(&paths).any(|dir| {
+        dir.join(program).is_file()
+            || (cfg!(windows) && dir.join(format!("{program}.exe")).is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_to_platform_shell() {
+        let shell = Shell::resolve(None).unwrap();
+        assert_eq!(shell, Shell::resolve(Some("  ")).unwrap());
+        assert_eq!(shell.program, DEFAULT_SHELL);
+    }
+
+    #[test]
+    fn uses_slash_c_for_cmd() {
+        assert_eq!(shell_flag("cmd"), "/C");
+        assert_eq!(shell_flag("CMD.EXE"), "/C");
+        assert_eq!(shell_flag("/bin/zsh"), "-c");
+    }
+
+    #[test]
+    fn rejects_missing_shell() {
+        let err = Shell::resolve(Some("no-such-shell-quickspell")).unwrap_err();
+        assert!(err.contains("no-such-shell-quickspell"));
+
+        let err = Shell::resolve(Some("/nonexistent/zsh")).unwrap_err();
+        assert!(err.contains("/nonexistent/zsh"));
+    }
+
+    #[test]
+    fn expands_known_and_unknown_variables() {
+        let lookup = |name: &str| (name == "HOME").then(|| "/Users/me".to_string());
+        assert_eq!(
+            expand_vars("ls ${HOME}/src ${NOPE}x", lookup),
+            "ls /Users/me/src x"
+        );
+        assert_eq!(
+            expand_vars("echo $HOME ${HOME", lookup),
+            "echo $HOME ${HOME"
+        );
+    }
+
+    #[test]
+    fn expands_from_the_process_environment() {
+        let path = env::var("PATH").unwrap();
+        assert_eq!(expand_env_vars("${PATH}"), path);
+        assert_eq!(expand_env_vars("[${QUICKSPELL_UNSET_TEST_VAR}]"), "[]");
+    }
+}
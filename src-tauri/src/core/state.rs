@@ -1,23 +1,41 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::fs::{create_dir_all, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::{Arc, PoisonError, RwLockWriteGuard};
 use std::time::{Duration, Instant};
 
-use tauri::{async_runtime, AppHandle, Manager};
+use tauri::{async_runtime, AppHandle, Manager, Url};
+use tauri_plugin_opener::OpenerExt;
+use tokio::io::AsyncBufReadExt;
 
 use crate::api::events;
 use crate::api::types::{
-    Action, ActionType, AppInner, AppState, AppStatus, AvailableAction, Frame, Item, SelectedItem,
-    Spell, StateSnapshot, STARTING_SPELL_ID,
+    Action, ActionInfo, ActionOutcome, ActionType, AppInner, AppState, AppStatus, AvailableAction,
+    CachedItems, FilterMetrics, Frame, FrameSummary, Item, ItemFormat, ItemsPage, MetricsSummary,
+    PendingConfirm, SelectedItem, Spell, SpellInfo, SpellSource, StateSnapshot, UiAction,
+    SPELL_ENTRY_TYPE, STARTING_SPELL_ID,
 };
+use crate::core::logging::{self, LogFormat};
+use crate::core::settings::Settings;
+use crate::core::shell::{self, Shell};
 use crate::core::template;
+use crate::core::usage::{self, UsageStore};
 
 const TOP_ITEMS_LIMIT: usize = 100;
+const FILTER_METRICS_LIMIT: usize = 200;
+const QUERY_DEBOUNCE: Duration = Duration::from_millis(80);
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
 const MAIN_WINDOW_LABEL: &str = "main";
-const BUNDLE_IDENTIFIER: &str = "com.adrian.quickspell";
+const DEFAULT_DELIMITER: &str = "\t";
+const PREVIEW_DEBOUNCE: Duration = Duration::from_millis(60);
+const PREVIEW_OUTPUT_LIMIT: usize = 64 * 1024;
+const PREVIEW_ERROR_LIMIT: usize = 500;
+const PREVIEW_CACHE_LIMIT: usize = 256;
+const DEFAULT_MAX_STACK_DEPTH: usize = 32;
+const POISON_RECOVERY_MESSAGE: &str = "recovered from an internal error; refresh to reload items";
 
 pub enum EscapeResult {
     ClearedQuery,
@@ -25,6 +43,237 @@ pub enum EscapeResult {
     Noop,
 }
 
+pub enum QueryResult {
+    Updated,
+    PushedSpell,
+}
+
+#[derive(Default)]
+pub struct DispatchResult {
+    /// A `SetQuery` matched an alias; the pushed frame still needs loading.
+    pub pushed_spell: bool,
+    pub query_changed: bool,
+    /// Outcome of the last `Invoke` step.
+    pub outcome: Option<ActionOutcome>,
+}
+
+struct LineFormat {
+    format: ItemFormat,
+    delimiter: String,
+    unescape: bool,
+    lenient: bool,
+    encoding: Option<&'static encoding_rs::Encoding>,
+}
+
+struct ProviderJob {
+    shell: Shell,
+    cache_ttl: Option<Duration>,
+    retries: u32,
+    retry_delay: Duration,
+    command: String,
+    transform: Option<String>,
+    cwd: Option<String>,
+    line_format: LineFormat,
+    frame_id: String,
+    frame_uid: u64,
+    /// The frame's `load_cancelled`, flipped by `cancel_loading`.
+    cancelled: tokio::sync::watch::Receiver<bool>,
+    spell_entries: Vec<Item>,
+    /// Items for spells with a `source`; the provider isn't run.
+    source_items: Option<Vec<Item>>,
+    env: Vec<(&'static str, String)>,
+}
+
+// A provider process and, with `transform`, the process reading its stdout.
+struct ProviderRun {
+    provider: tokio::process::Child,
+    transform: Option<tokio::process::Child>,
+}
+
+impl ProviderRun {
+    // Errors name the stage that failed to start.
+    fn spawn(job: &ProviderJob, resources_dir: &Path, pipe_stderr: bool) -> Result<Self, String> {
+        let frame_id = &job.frame_id;
+        let spawn_stage = |stage: &str, mut command: tokio::process::Command| {
+            if pipe_stderr {
+                command.stderr(Stdio::piped());
+            }
+            command
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|err| {
+                    format!(
+                        "failed to launch {stage} for {frame_id} with shell `{}`: {err}",
+                        job.shell
+                    )
+                })
+        };
+
+        let mut provider = spawn_stage("provider", provider_command(job, resources_dir).into())?;
+        let transform = match &job.transform {
+            Some(script) => {
+                let stdout: Stdio = provider
+                    .stdout
+                    .take()
+                    .ok_or("no stdout handle")?
+                    .try_into()
+                    .map_err(|err| format!("failed to pipe provider for {frame_id}: {err}"))?;
+                let mut command =
+                    tokio::process::Command::from(stage_command(job, script, resources_dir));
+                command.stdin(stdout);
+                Some(spawn_stage("transform", command)?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            provider,
+            transform,
+        })
+    }
+
+    // The process whose stdout holds the item lines.
+    fn output_stage(&mut self) -> &mut tokio::process::Child {
+        self.transform.as_mut().unwrap_or(&mut self.provider)
+    }
+
+    fn start_kill(&mut self) {
+        let _ = self.provider.start_kill();
+        if let Some(transform) = &mut self.transform {
+            let _ = transform.start_kill();
+        }
+    }
+
+    // Waits for every stage; the error names the first one that failed. A
+    // provider cut off by a transform that stopped reading early (`head -n 5`)
+    // doesn't count as failed when the transform succeeded.
+    async fn wait(&mut self, frame_id: &str) -> Result<std::process::ExitStatus, String> {
+        let provider = self.provider.wait().await;
+        let Some(transform) = &mut self.transform else {
+            return stage_status("provider", frame_id, provider);
+        };
+        let transform = stage_status("transform", frame_id, transform.wait().await);
+        if transform.is_ok() && provider.as_ref().is_ok_and(is_broken_pipe) {
+            return transform;
+        }
+        stage_status("provider", frame_id, provider).and(transform)
+    }
+
+    async fn wait_with_output(self, frame_id: &str) -> Result<std::process::Output, String> {
+        let Some(transform) = self.transform else {
+            let output = self.provider.wait_with_output().await;
+            return stage_output("provider", frame_id, output);
+        };
+        let (provider, output) = tokio::join!(
+            self.provider.wait_with_output(),
+            transform.wait_with_output()
+        );
+        let output = stage_output("transform", frame_id, output);
+        let cut_off = provider.as_ref().is_ok_and(|p| is_broken_pipe(&p.status));
+        if !(output.is_ok() && cut_off) {
+            stage_output("provider", frame_id, provider)?;
+        }
+        output
+    }
+}
+
+// Killed by SIGPIPE, or a shell reporting a child that was.
+#[cfg(unix)]
+fn is_broken_pipe(status: &std::process::ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    const SIGPIPE: i32 = 13;
+    status.signal() == Some(SIGPIPE) || status.code() == Some(128 + SIGPIPE)
+}
+
+#[cfg(not(unix))]
+fn is_broken_pipe(_status: &std::process::ExitStatus) -> bool {
+    false
+}
+
+fn stage_status(
+    stage: &str,
+    frame_id: &str,
+    status: std::io::Result<std::process::ExitStatus>,
+) -> Result<std::process::ExitStatus, String> {
+    match status {
+        Ok(status) if status.success() => Ok(status),
+        Ok(status) => Err(format!(
+            "{stage} for {frame_id} exited with status {status}"
+        )),
+        Err(err) => Err(format!("failed to wait for {stage} of {frame_id}: {err}")),
+    }
+}
+
+fn stage_output(
+    stage: &str,
+    frame_id: &str,
+    output: std::io::Result<std::process::Output>,
+) -> Result<std::process::Output, String> {
+    let output =
+        output.map_err(|err| format!("failed to wait for {stage} of {frame_id}: {err}"))?;
+    stage_status(stage, frame_id, Ok(output.status))?;
+    Ok(output)
+}
+
+struct PreviewJob {
+    key: String,
+    shell: Option<String>,
+    script: Result<String, String>,
+    /// The spell's templated `cwd`, resolved like the provider's.
+    cwd: Result<Option<String>, String>,
+}
+
+enum ResolvedAction {
+    Spell(String),
+    OpenUrl {
+        urls: Vec<String>,
+        outcome: ActionOutcome,
+    },
+    Cmd {
+        cmds: Vec<String>,
+        shell: Option<String>,
+        outcome: ActionOutcome,
+    },
+}
+
+impl ResolvedAction {
+    // Every rendered value is empty, e.g. a template over a missing selection.
+    fn is_blank(&self) -> bool {
+        match self {
+            ResolvedAction::Spell(spell_id) => spell_id.is_empty(),
+            ResolvedAction::OpenUrl { urls: values, .. }
+            | ResolvedAction::Cmd { cmds: values, .. } => {
+                values.iter().all(|value| value.trim().is_empty())
+            }
+        }
+    }
+}
+
+// A rendered action and its rendered `confirm` prompt, if it has one.
+type Rendered = (ResolvedAction, Option<String>);
+
+// What `label` resolved to, with the stack it was rendered against for usage
+// tracking.
+struct Resolution {
+    action: ResolvedAction,
+    confirm: Option<String>,
+    frames: Vec<Frame>,
+}
+
+// What `invoke_action` does with a label: ask first, or run it.
+enum Invocation {
+    Prompt(ActionOutcome),
+    Run(Resolution),
+}
+
+// The stack, the current spell's actions and its configured shell.
+struct ActionContext {
+    frames: Vec<Frame>,
+    actions: Vec<Action>,
+    shell: Option<String>,
+    fallback: Option<Action>,
+}
+
 impl AppState {
     pub fn new() -> Self {
         Self {
@@ -33,55 +282,161 @@ impl AppState {
                 spells: HashMap::new(),
                 stack: Vec::new(),
                 next_frame_id: 0,
+                last_error: None,
+                recent_filters: VecDeque::new(),
+                usage: UsageStore::default(),
+                usage_path: None,
+                remembered_queries: HashMap::new(),
+                settings: Settings::default(),
+                spell_errors: Vec::new(),
+                preview_cache: HashMap::new(),
+                provider_cache: HashMap::new(),
+                no_spells_message: None,
+                pending_confirm: None,
             })),
+            filter_generation: Arc::new(tokio::sync::watch::Sender::new(0)),
+            preview_generation: Arc::new(tokio::sync::watch::Sender::new(0)),
         }
     }
 
-    pub fn begin_loading_with_spells(&self, spells: HashMap<String, Spell>) -> Result<(), String> {
-        let mut inner = self.inner.write().map_err(|_| "state lock poisoned")?;
+    /// Opens the start spell. With no spells at all there is nothing to load:
+    /// the app is ready with an empty stack and points the user at `spells_dir`.
+    pub fn begin_loading_with_spells(
+        &self,
+        spells: HashMap<String, Spell>,
+        spells_dir: &Path,
+    ) -> Result<(), String> {
+        let mut inner = self.write_inner();
 
         if inner.status != AppStatus::NotStarted {
             return Err("already started".to_string());
         }
 
         inner.status = AppStatus::Booting;
+        if spells.is_empty() {
+            inner.no_spells_message = Some(format!("No spells found in {}", spells_dir.display()));
+            inner.status = AppStatus::Ready;
+            return Ok(());
+        }
         inner.spells = spells;
+        warn_missing_start_spell(&inner);
         inner.status = AppStatus::Loading;
-        inner.stack = vec![new_frame(&mut inner, STARTING_SPELL_ID.to_string())];
+        let start_id = start_spell_id(&inner);
+        inner.stack = vec![new_frame(&mut inner, start_id)];
         Ok(())
     }
 
-    pub fn finish_loading_with_items(&self, resources_dir: &Path) -> Result<(), String> {
-        let Some((items, frame_uid)) = self.load_items_for_current_frame(resources_dir)? else {
+    pub fn replace_spells(&self, spells: HashMap<String, Spell>) -> Result<(), String> {
+        let mut inner = self.write_inner();
+        inner.spells = spells;
+        warn_missing_start_spell(&inner);
+        inner.provider_cache.clear();
+        inner.preview_cache.clear();
+        Ok(())
+    }
+
+    /// The file `spell_id` was loaded from.
+    pub fn spell_path(&self, spell_id: &str) -> Result<PathBuf, String> {
+        let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
+        let spell = inner
+            .spells
+            .get(spell_id)
+            .ok_or_else(|| format!("spell {spell_id} not found"))?;
+        spell
+            .path
+            .clone()
+            .ok_or_else(|| format!("spell {spell_id} has no file"))
+    }
+
+    pub fn set_spell_enabled(&self, spell_id: &str, enabled: bool) -> Result<(), String> {
+        let mut inner = self.write_inner();
+        let spell = inner
+            .spells
+            .get_mut(spell_id)
+            .ok_or_else(|| format!("spell {spell_id} not found"))?;
+        spell.enabled = enabled;
+        Ok(())
+    }
+
+    pub fn set_spell_errors(&self, errors: Vec<(PathBuf, String)>) -> Result<(), String> {
+        let mut inner = self.write_inner();
+        inner.spell_errors = errors;
+        Ok(())
+    }
+
+    /// Drops the current spell's cached items and re-runs its provider.
+    pub fn refresh(&self, resources_dir: &Path, app: &AppHandle) -> Result<(), String> {
+        {
+            let mut inner = self.write_inner();
+            let Some(spell_id) = inner.stack.last().map(|frame| frame.spell_id.clone()) else {
+                return Ok(());
+            };
+            let prefix = spell_key_prefix(&spell_id);
+            inner
+                .provider_cache
+                .retain(|key, _| !key.starts_with(&prefix));
+            inner
+                .preview_cache
+                .retain(|key, _| !key.starts_with(&prefix));
+            if let Some(frame) = inner.stack.last_mut() {
+                frame.load_cancelled.send_replace(false);
+            }
+            inner.status = AppStatus::Loading;
+        }
+
+        let _ = self.emit_snapshot(app);
+        self.load_current_frame(resources_dir, app);
+        Ok(())
+    }
+
+    pub async fn finish_loading_with_items(&self, resources_dir: &Path) -> Result<(), String> {
+        let Some((items, frame_uid)) = self.load_items_for_current_frame(resources_dir).await?
+        else {
             return Ok(());
         };
 
-        if let Ok(mut inner) = self.inner.write() {
-            if is_current_frame(&inner, frame_uid) {
-                if let Some(frame) = inner.stack.last_mut() {
-                    frame.all_items = items.clone();
-                    frame.filtered_items = items;
-                }
-                inner.status = AppStatus::Ready;
+        let needs_filter = {
+            let mut inner = self.write_inner();
+            if !is_current_frame(&inner, frame_uid) {
+                return Ok(());
             }
-            Ok(())
-        } else {
-            Err("state lock poisoned".to_string())
+            if let Some(frame) = inner.stack.last_mut() {
+                frame.all_items = items;
+            }
+            let needs_filter = inner.stack.last().is_some_and(|f| !f.query.is_empty());
+            let visible = match current_placeholder_items(&inner) {
+                Some(placeholders) if !needs_filter => placeholders,
+                _ => scoped_items(&inner),
+            };
+            if let Some(frame) = inner.stack.last_mut() {
+                frame.filtered_items = visible;
+                frame.type_counts = type_counts(&frame.filtered_items);
+                frame.selected_set.clear();
+                clamp_selection(frame);
+            }
+            inner.status = AppStatus::Ready;
+            inner.last_error = None;
+            needs_filter
+        };
+
+        if needs_filter {
+            self.filter_items();
         }
+        Ok(())
     }
 
-    pub fn set_error(&self) {
-        if let Ok(mut inner) = self.inner.write() {
-            inner.status = AppStatus::Error;
-            inner.spells.clear();
-            inner.stack.clear();
-        }
+    pub fn set_error(&self, message: String) {
+        let mut inner = self.write_inner();
+        inner.status = AppStatus::Error;
+        inner.last_error = Some(message);
+        inner.spells.clear();
+        inner.stack.clear();
     }
 
     pub fn set_ready(&self) {
-        if let Ok(mut inner) = self.inner.write() {
-            inner.status = AppStatus::Ready;
-        }
+        let mut inner = self.write_inner();
+        inner.status = AppStatus::Ready;
+        inner.last_error = None;
     }
 
     pub fn get_current_spell(&self) -> Option<Spell> {
@@ -90,20 +445,298 @@ impl AppState {
         inner.spells.get(&frame.spell_id).cloned()
     }
 
-    pub fn set_query(&self, query: String) {
-        if let Ok(mut inner) = self.inner.write() {
-            if let Some(frame) = inner.stack.last_mut() {
-                frame.query = query;
-                frame.selected_idx = 0;
-                frame.is_filtering = true;
+    pub fn set_query(&self, query: String) -> QueryResult {
+        let mut inner = self.write_inner();
+        if let Some((spell_id, rest)) = match_alias(&inner, &query) {
+            let items = placeholder_items(&inner, root_spell_id(&inner))
+                .unwrap_or_else(|| scoped_items(&inner));
+            if let Some(root) = inner.stack.last_mut() {
+                root.query.clear();
+                root.type_counts = type_counts(&items);
+                root.filtered_items = items;
+                root.selected_idx = 0;
+                root.selected_set.clear();
+                root.is_filtering = false;
+            }
+            let mut frame = new_frame(&mut inner, spell_id);
+            if !rest.is_empty() {
+                frame.query = rest;
+            }
+            inner.stack.push(frame);
+            inner.status = AppStatus::Loading;
+            return QueryResult::PushedSpell;
+        }
+
+        if !query.is_empty() {
+            if let Some(spell_id) = remembering_spell_id(&inner) {
+                inner.remembered_queries.insert(spell_id, query.clone());
             }
         }
+        if let Some(frame) = inner.stack.last_mut() {
+            frame.query = query;
+            frame.selected_idx = 0;
+            frame.is_filtering = true;
+        }
+        QueryResult::Updated
+    }
+
+    /// `set_query` without the debounce: the list matches `query` on return,
+    /// and filters still pending for older queries are dropped. Dynamic spells
+    /// are left to `reload_dynamic_items`.
+    pub fn set_query_immediate(&self, query: String) -> QueryResult {
+        let result = self.set_query(query);
+        let is_dynamic = self
+            .get_current_spell()
+            .and_then(|s| s.is_dynamic)
+            .unwrap_or(false);
+        if matches!(result, QueryResult::Updated) && !is_dynamic {
+            self.next_filter_generation();
+            self.filter_items();
+        }
+        result
+    }
+
+    pub fn next_filter_generation(&self) -> u64 {
+        let mut next = 0;
+        self.filter_generation.send_modify(|generation| {
+            *generation += 1;
+            next = *generation;
+        });
+        next
+    }
+
+    fn is_filter_generation(&self, generation: u64) -> bool {
+        *self.filter_generation.borrow() == generation
+    }
+
+    /// Waits out the debounce window and filters only if no newer query arrived.
+    pub fn filter_items_debounced(&self, generation: u64) -> bool {
+        std::thread::sleep(QUERY_DEBOUNCE);
+        if !self.is_filter_generation(generation) {
+            return false;
+        }
+        self.filter_items_for_generation(Some(generation))
     }
 
     pub fn filter_items(&self) -> bool {
+        self.filter_items_for_generation(None)
+    }
+
+    fn next_preview_generation(&self) -> u64 {
+        let mut next = 0;
+        self.preview_generation.send_modify(|generation| {
+            *generation += 1;
+            next = *generation;
+        });
+        next
+    }
+
+    fn is_preview_generation(&self, generation: u64) -> bool {
+        *self.preview_generation.borrow() == generation
+    }
+
+    /// Runs the selected item's `preview_cmd` in the background unless it is
+    /// cached, cancelling a preview still running for an earlier selection.
+    pub fn spawn_preview(&self, resources_dir: &Path, app: &AppHandle) {
+        let generation = self.next_preview_generation();
+        let has_preview = self
+            .get_current_spell()
+            .is_some_and(|spell| spell.preview_cmd.is_some());
+        if !has_preview {
+            return;
+        }
+
+        let state = self.clone();
+        let resources_dir = resources_dir.to_path_buf();
+        let app_handle = app.clone();
+        async_runtime::spawn(async move {
+            if state.load_preview(generation, &resources_dir).await {
+                let _ = state.emit_snapshot(&app_handle);
+            }
+        });
+    }
+
+    /// Debounced; true when a preview for the still-selected item was stored.
+    pub async fn load_preview(&self, generation: u64, resources_dir: &Path) -> bool {
+        tokio::time::sleep(PREVIEW_DEBOUNCE).await;
+        if !self.is_preview_generation(generation) {
+            return false;
+        }
+        let Some(job) = self.preview_job() else {
+            return false;
+        };
+
+        let cancelled = superseded(&self.preview_generation, generation);
+        let preview = match run_preview(&job, resources_dir, cancelled).await {
+            Ok(Some(stdout)) => stdout,
+            Ok(None) => return false,
+            Err(err) => preview_error(&err),
+        };
+
+        let mut inner = self.write_inner();
+        cache_preview(&mut inner, job.key, preview);
+        self.is_preview_generation(generation)
+    }
+
+    /// The selected item's preview, fetched on demand without a snapshot: the
+    /// spell's `preview` template resolved against the stack, or else its
+    /// `preview_cmd` output (cached like background previews). `None` when
+    /// the spell has neither.
+    pub async fn get_preview(&self, resources_dir: &Path) -> Result<Option<String>, String> {
+        {
+            let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
+            let Some(spell) = inner
+                .stack
+                .last()
+                .and_then(|frame| inner.spells.get(&frame.spell_id))
+            else {
+                return Ok(None);
+            };
+            if let Some(preview) = &spell.preview {
+                return template::resolve_template(preview, &inner.stack)
+                    .map(Some)
+                    .map_err(|e| match e {
+                        template::TemplateError::Render(err) => format!("invalid preview: {err}"),
+                    });
+            }
+        }
+
+        // No job without a `preview_cmd` or selection, or when already cached.
+        let Some(job) = self.preview_job() else {
+            let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
+            return Ok(current_preview(&inner));
+        };
+        let preview = run_preview(&job, resources_dir, std::future::pending())
+            .await?
+            .unwrap_or_default();
+        let mut inner = self.write_inner();
+        cache_preview(&mut inner, job.key, preview.clone());
+        Ok(Some(preview))
+    }
+
+    // `None` when the spell has no `preview_cmd`, nothing is selected or the
+    // preview is already cached.
+    fn preview_job(&self) -> Option<PreviewJob> {
+        let inner = self.inner.read().ok()?;
+        let frame = inner.stack.last()?;
+        let spell = inner.spells.get(&frame.spell_id)?;
+        let preview_cmd = spell.preview_cmd.as_deref()?;
+        let item = template::selected_item(frame)?;
+
+        let key = preview_key(&frame.spell_id, &item.data);
+        if inner.preview_cache.contains_key(&key) {
+            return None;
+        }
+
+        Some(PreviewJob {
+            key,
+            shell: configured_shell(&inner, spell).map(str::to_string),
+            script: template::resolve_template(preview_cmd, &inner.stack).map_err(|e| match e {
+                template::TemplateError::Render(err) => format!("invalid preview_cmd: {err}"),
+            }),
+            cwd: spell
+                .cwd
+                .as_deref()
+                .map(|cwd| {
+                    template::resolve_template(cwd, &inner.stack).map_err(|e| match e {
+                        template::TemplateError::Render(err) => format!("invalid cwd: {err}"),
+                    })
+                })
+                .transpose(),
+        })
+    }
+
+    /// Debounced provider re-run for dynamic spells. A newer query bumps the
+    /// generation, which kills the in-flight provider and drops its output.
+    pub async fn reload_dynamic_items(&self, generation: u64, resources_dir: &Path) -> bool {
+        tokio::time::sleep(QUERY_DEBOUNCE).await;
+        if !self.is_filter_generation(generation) {
+            return false;
+        }
+
+        let job = match self.provider_job() {
+            Ok(Some(job)) => job,
+            Ok(None) => return false,
+            Err(err) => {
+                let mut inner = self.write_inner();
+                if !self.is_filter_generation(generation) {
+                    return false;
+                }
+                return match inner.stack.last_mut() {
+                    Some(frame) => {
+                        frame.reload_error = Some(err);
+                        frame.is_filtering = false;
+                        true
+                    }
+                    None => false,
+                };
+            }
+        };
+        let query = job.env_value("QUICKSPELL_QUERY").to_string();
+
+        if query.is_empty() {
+            let mut inner = self.write_inner();
+            if let Some(placeholders) = current_placeholder_items(&inner) {
+                if let Some(frame) = inner.stack.last_mut().filter(|f| f.query.is_empty()) {
+                    frame.type_counts = type_counts(&placeholders);
+                    frame.filtered_items = placeholders;
+                    frame.reload_error = None;
+                    clamp_selection(frame);
+                    frame.is_filtering = false;
+                    return true;
+                }
+            }
+        }
+
+        // Dropping the output future on a newer query kills the provider.
+        let output = match ProviderRun::spawn(&job, resources_dir, false) {
+            Ok(run) => tokio::select! {
+                output = run.wait_with_output(&job.frame_id) => output,
+                _ = superseded(&self.filter_generation, generation) => return false,
+            },
+            Err(err) => Err(err),
+        };
+        let (items, reload_error): (Vec<Item>, _) = match output {
+            Ok(output) => (
+                decode_output(&output.stdout, &job.line_format)
+                    .lines()
+                    .filter_map(|line| parse_item_line(line, &job.line_format, &job.frame_id))
+                    .collect(),
+                None,
+            ),
+            Err(err) => (Vec::new(), Some(err)),
+        };
+
+        let mut inner = self.write_inner();
+        if !self.is_filter_generation(generation) || !is_current_frame(&inner, job.frame_uid) {
+            return false;
+        }
+        let mut scope = scope_filter(&inner);
+        match inner.stack.last_mut() {
+            Some(frame) if frame.query == query => {
+                frame.filtered_items = items
+                    .iter()
+                    .filter(|item| in_scope(&mut scope, item))
+                    .cloned()
+                    .collect();
+                frame.type_counts = type_counts(&frame.filtered_items);
+                frame.all_items = items;
+                frame.reload_error = reload_error;
+                clamp_selection(frame);
+                frame.is_filtering = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn filter_items_for_generation(&self, generation: Option<u64>) -> bool {
         let start = Instant::now();
 
-        let (all_items, query, config) = {
+        // Filters in place under the read lock so only kept items are cloned,
+        // not the whole list on every keystroke. Types are counted over every
+        // match, not just the ones kept.
+        let (filtered, counts, query, item_count) = {
             let inner = match self.inner.read() {
                 Ok(i) => i,
                 Err(_) => return false,
@@ -112,47 +745,92 @@ impl AppState {
                 Some(f) => f,
                 None => return false,
             };
-            let cfg = inner
-                .spells
-                .get(&frame.spell_id)
-                .and_then(|s| s.search.clone());
-            (frame.all_items.clone(), frame.query.clone(), cfg)
-        };
+            let spell = inner.spells.get(&frame.spell_id);
+            let bonuses = usage_bonuses(&inner);
+            let bonus = |item: &Item| {
+                bonuses
+                    .as_ref()
+                    .and_then(|b| b.get(&item.data).copied())
+                    .unwrap_or(0)
+            };
 
-        let item_count = all_items.len();
-        let mut filtered: Vec<Item> = if query.is_empty() {
-            all_items
-        } else if let Some(cfg) = config {
-            crate::core::search::filter_items(&all_items, &query, &cfg)
-                .into_iter()
-                .cloned()
-                .collect()
-        } else {
-            all_items
+            let all_items = &frame.all_items;
+            let query = &frame.query;
+            let placeholders = current_placeholder_items(&inner);
+            let mut scope = scope_filter(&inner);
+            let (filtered, counts): (Vec<Item>, _) =
+                if let Some(placeholders) = placeholders.filter(|_| query.is_empty()) {
+                    let counts = type_counts(&placeholders);
+                    (
+                        placeholders.into_iter().take(TOP_ITEMS_LIMIT).collect(),
+                        counts,
+                    )
+                } else if query.is_empty() {
+                    let items = unfiltered_items(&inner);
+                    let counts = type_counts(items.iter().copied());
+                    (
+                        items.into_iter().take(TOP_ITEMS_LIMIT).cloned().collect(),
+                        counts,
+                    )
+                } else if let Some(cfg) = spell.and_then(|s| s.search.as_ref()) {
+                    let matches: Vec<_> =
+                        crate::core::search::filter_items_with_bonus(all_items, query, cfg, bonus)
+                            .into_iter()
+                            .filter(|m| !m.item.is_header() && in_scope(&mut scope, m.item))
+                            .collect();
+                    let counts = type_counts(matches.iter().map(|m| m.item));
+                    (
+                        matches
+                            .into_iter()
+                            .take(TOP_ITEMS_LIMIT)
+                            .map(matched_item)
+                            .collect(),
+                        counts,
+                    )
+                } else {
+                    let items: Vec<&Item> = all_items
+                        .iter()
+                        .filter(|item| in_scope(&mut scope, item))
+                        .collect();
+                    let counts = type_counts(items.iter().copied());
+                    (
+                        items.into_iter().take(TOP_ITEMS_LIMIT).cloned().collect(),
+                        counts,
+                    )
+                };
+            (filtered, counts, query.clone(), all_items.len())
         };
 
-        if filtered.len() > TOP_ITEMS_LIMIT {
-            filtered.truncate(TOP_ITEMS_LIMIT);
-        }
-
         let result_count = filtered.len();
 
-        let applied = if let Ok(mut inner) = self.inner.write() {
+        let applied = {
+            let mut inner = self.write_inner();
+            let is_stale = generation.is_some_and(|g| !self.is_filter_generation(g));
             match inner.stack.last_mut() {
-                Some(frame) if frame.query == query => {
+                Some(frame) if frame.query == query && !is_stale => {
                     frame.filtered_items = filtered;
+                    frame.type_counts = counts;
+                    frame.selected_set.clear();
                     clamp_selection(frame);
                     frame.is_filtering = false;
                     true
                 }
                 _ => false,
             }
-        } else {
-            false
         };
 
+        let elapsed = start.elapsed();
+        self.record_filter_metrics(FilterMetrics {
+            query: query.clone(),
+            items: item_count,
+            results: result_count,
+            applied,
+            elapsed_micros: elapsed_micros(elapsed),
+        });
+
+        let format = self.settings().log_format;
         if let Err(err) =
-            log_filter_metrics(&query, item_count, result_count, applied, start.elapsed())
+            log_filter_metrics(format, &query, item_count, result_count, applied, elapsed)
         {
             eprintln!("failed to write quickspell log: {err}");
         }
@@ -160,93 +838,285 @@ impl AppState {
         applied
     }
 
-    pub fn snapshot(&self) -> StateSnapshot {
-        let (
-            status,
-            no_of_spells,
-            spell_names,
-            top_items,
-            total_items,
-            query,
-            is_filtering,
-            selected,
-        ) = if let Ok(inner) = self.inner.read() {
-            let (top, total, query, is_filtering, selected_idx, selected_item) = inner
-                .stack
-                .last()
-                .map(|f| {
-                    let (clamped_idx, selected) =
-                        if let Some(max_idx) = max_selectable_index(f.filtered_items.len()) {
-                            let idx = f.selected_idx.min(max_idx);
-                            (idx, f.filtered_items.get(idx).cloned())
-                        } else {
-                            (0, None)
-                        };
+    pub fn settings(&self) -> Settings {
+        self.inner
+            .read()
+            .map(|inner| inner.settings.clone())
+            .unwrap_or_default()
+    }
 
-                    (
-                        f.filtered_items
-                            .iter()
-                            .take(TOP_ITEMS_LIMIT)
-                            .cloned()
-                            .collect(),
-                        f.filtered_items.len(),
-                        f.query.clone(),
-                        f.is_filtering,
-                        clamped_idx,
-                        selected,
-                    )
-                })
-                .unwrap_or((Vec::new(), 0, String::new(), false, 0, None));
+    pub fn set_settings(&self, settings: Settings) {
+        let mut inner = self.write_inner();
+        let start_spell_changed = inner.settings.start_spell != settings.start_spell;
+        inner.settings = settings;
+        if start_spell_changed && !inner.spells.is_empty() {
+            warn_missing_start_spell(&inner);
+        }
+    }
 
-            let selected = selected_item.map(|details| SelectedItem {
-                index: selected_idx,
-                details,
-                actions: matching_actions(&inner),
-            });
+    pub fn load_usage(&self, path: PathBuf) {
+        let store = UsageStore::load(&path);
+        let mut inner = self.write_inner();
+        inner.usage = store;
+        inner.usage_path = Some(path);
+    }
 
-            (
-                inner.status,
-                inner.spells.len(),
-                inner
-                    .stack
-                    .iter()
-                    .map(|frame| {
-                        inner
-                            .spells
-                            .get(&frame.spell_id)
-                            .map(|spell| spell.name.clone())
-                            .unwrap_or_else(|| frame.spell_id.clone())
-                    })
-                    .collect(),
-                top,
-                total,
-                query,
-                is_filtering,
-                selected,
-            )
-        } else {
-            (
-                AppStatus::Error,
-                0,
-                Vec::new(),
-                Vec::new(),
-                0,
-                String::new(),
-                false,
-                None,
-            )
+    // Counts a launch of the selected item for spells that rank by usage.
+    fn record_usage(&self, frames: &[Frame]) {
+        let Some(frame) = frames.last() else {
+            return;
         };
-
-        StateSnapshot {
-            status,
-            no_of_spells,
-            spell_names,
-            top_items,
-            total_items,
-            query,
-            is_filtering,
-            selected_item: selected,
+        let Some(item) = template::selected_item(frame) else {
+            return;
+        };
+        let mut inner = self.write_inner();
+        let ranks_by_usage = inner
+            .spells
+            .get(&frame.spell_id)
+            .and_then(|s| s.rank_by_usage)
+            .unwrap_or(false);
+        if !ranks_by_usage {
+            return;
         }
+
+        inner
+            .usage
+            .record(&frame.spell_id, &item.data, usage::now_secs());
+        drop(inner);
+        self.save_usage();
+    }
+
+    // Writes a copy of the usage store so the file write happens outside
+    // the state lock.
+    fn save_usage(&self) {
+        let Some((path, usage)) = self.inner.read().ok().and_then(|inner| {
+            let path = inner.usage_path.clone()?;
+            Some((path, inner.usage.clone()))
+        }) else {
+            return;
+        };
+        if let Err(err) = usage.save(&path) {
+            eprintln!("{err}");
+        }
+    }
+
+    fn log_verbose(&self, event: &str, fields: &[(&str, serde_json::Value)]) {
+        let settings = self.settings();
+        if !settings.verbose_log.unwrap_or(false) {
+            return;
+        }
+        if let Err(err) = logging::write_entry(settings.log_format, event, fields) {
+            eprintln!("failed to write quickspell log: {err}");
+        }
+    }
+
+    fn log_provider_run(&self, spell_id: &str, items: usize, elapsed: Duration, status: &str) {
+        self.log_verbose(
+            "provider",
+            &[
+                ("spell", spell_id.into()),
+                ("items", items.into()),
+                ("elapsed_micros", elapsed_micros(elapsed).into()),
+                ("status", status.into()),
+            ],
+        );
+    }
+
+    fn log_action(
+        &self,
+        label: &str,
+        kind: ActionType,
+        resolved: &str,
+        result: &Result<(), String>,
+    ) {
+        self.log_verbose(
+            "action",
+            &[
+                ("label", label.into()),
+                ("kind", serde_json::to_value(kind).unwrap_or_default()),
+                ("resolved", resolved.into()),
+                ("success", result.is_ok().into()),
+                (
+                    "error",
+                    result.as_ref().err().map_or("", String::as_str).into(),
+                ),
+            ],
+        );
+    }
+
+    /// Up to `limit` filtered items from `offset`, for scrolling past the
+    /// snapshot's `top_items`. Empty past the end.
+    pub fn items_page(&self, offset: usize, limit: usize) -> ItemsPage {
+        let Ok(inner) = self.inner.read() else {
+            return ItemsPage::default();
+        };
+        let Some(frame) = inner.stack.last() else {
+            return ItemsPage::default();
+        };
+        let total = frame.filtered_items.len();
+        let start = offset.min(total);
+        let end = offset.saturating_add(limit).min(total);
+        ItemsPage {
+            items: frame.filtered_items[start..end].to_vec(),
+            total,
+        }
+    }
+
+    pub fn metrics_summary(&self) -> MetricsSummary {
+        match self.inner.read() {
+            Ok(inner) => summarize_filter_metrics(&inner.recent_filters),
+            Err(_) => MetricsSummary::default(),
+        }
+    }
+
+    fn record_filter_metrics(&self, metrics: FilterMetrics) {
+        let mut inner = self.write_inner();
+        if inner.recent_filters.len() >= FILTER_METRICS_LIMIT {
+            inner.recent_filters.pop_front();
+        }
+        inner.recent_filters.push_back(metrics);
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        if self.inner.is_poisoned() {
+            drop(self.write_inner());
+        }
+        // One guard for the whole snapshot so a writer can't land halfway.
+        let inner = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+        let frame = inner.stack.last();
+
+        let (selected_idx, selected_item) = frame
+            .and_then(|f| {
+                let idx = f
+                    .selected_idx
+                    .min(max_selectable_index(f.filtered_items.len())?);
+                let selected = f.filtered_items.get(idx).filter(|i| i.is_selectable());
+                Some((idx, selected.cloned()))
+            })
+            .unwrap_or((0, None));
+        let selected_item = selected_item.map(|details| SelectedItem {
+            index: selected_idx,
+            details,
+            actions: matching_actions(&inner),
+        });
+        let query = frame.map(|f| f.query.clone()).unwrap_or_default();
+        let selected_score = selected_item
+            .as_ref()
+            .filter(|_| !query.is_empty())
+            .and_then(|selected| selected.details.score)
+            .map(|score| score as f32);
+
+        let frames: Vec<FrameSummary> = inner
+            .stack
+            .iter()
+            .map(|frame| FrameSummary {
+                id: frame.id,
+                spell_id: frame.spell_id.clone(),
+                spell_name: inner
+                    .spells
+                    .get(&frame.spell_id)
+                    .map(|spell| spell.name.clone())
+                    .unwrap_or_else(|| frame.spell_id.clone()),
+                query: frame.query.clone(),
+                item_count: frame.filtered_items.len(),
+            })
+            .collect();
+        let spell_names = frames.iter().map(|f| f.spell_name.clone()).collect();
+        let top_items: Vec<Item> = frame
+            .map(|f| {
+                f.filtered_items
+                    .iter()
+                    .take(TOP_ITEMS_LIMIT)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let visible_rows = inner
+            .settings
+            .auto_height
+            .unwrap_or(false)
+            .then_some(top_items.len());
+        let (last_filter_micros, last_filter_item_count) = last_filter_debug(&inner).unzip();
+        let (scopes, active_scope) = current_scopes(&inner);
+
+        StateSnapshot {
+            status: inner.status,
+            no_of_spells: inner.spells.values().filter(|spell| spell.enabled).count(),
+            spell_names,
+            frames,
+            top_items,
+            total_items: frame.map_or(0, |f| f.filtered_items.len()),
+            query,
+            is_filtering: frame.is_some_and(|f| f.is_filtering),
+            selected_item,
+            error_message: inner.last_error.clone(),
+            last_filter_micros,
+            last_filter_item_count,
+            spell_errors: inner.spell_errors.clone(),
+            preview: current_preview(&inner),
+            is_loading_more: frame.is_some_and(|f| f.is_loading_more),
+            marked_indices: frame.map(marked_indices).unwrap_or_default(),
+            empty_message: current_empty_message(&inner),
+            selected_score,
+            scopes,
+            active_scope,
+            type_counts: frame.map(|f| f.type_counts.clone()).unwrap_or_default(),
+            query_error: current_query_error(&inner),
+            reload_error: frame.and_then(|f| f.reload_error.clone()),
+            visible_rows,
+        }
+    }
+
+    // A panic while holding the write lock poisons it and every later access
+    // would no-op. Keep spells and settings, restart the stack at its root
+    // frame without items and surface an error until the next refresh.
+    fn write_inner(&self) -> RwLockWriteGuard<'_, AppInner> {
+        self.inner.write().unwrap_or_else(|err| {
+            eprintln!("state lock poisoned, resetting state");
+            let mut inner = err.into_inner();
+            if let Some(root_id) = inner.stack.first().map(|frame| frame.spell_id.clone()) {
+                inner.stack.clear();
+                let frame = new_frame(&mut inner, root_id);
+                inner.stack.push(frame);
+            }
+            inner.provider_cache.clear();
+            inner.status = AppStatus::Error;
+            inner.last_error = Some(POISON_RECOVERY_MESSAGE.to_string());
+            self.inner.clear_poison();
+            inner
+        })
+    }
+
+    /// Actions whose condition passes for the current selection, for menus.
+    pub fn list_actions(&self) -> Result<Vec<ActionInfo>, String> {
+        let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
+        Ok(matching_actions(&inner)
+            .into_iter()
+            .map(|action| ActionInfo {
+                label: action.label,
+                kind: action.action_type,
+            })
+            .collect())
+    }
+
+    /// Every loaded spell, disabled ones included, sorted by name.
+    pub fn list_spells(&self, include_provider: bool) -> Result<Vec<SpellInfo>, String> {
+        let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
+        let mut spells: Vec<SpellInfo> = inner
+            .spells
+            .values()
+            .map(|spell| SpellInfo {
+                id: spell.id.clone(),
+                name: spell.name.clone(),
+                alias: spell.alias.clone(),
+                tags: spell.tags.clone(),
+                enabled: spell.enabled,
+                is_streaming: spell.is_streaming.unwrap_or(false),
+                provider: spell.provider.clone().filter(|_| include_provider),
+            })
+            .collect();
+        spells.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        Ok(spells)
     }
 
     pub fn emit_snapshot(&self, app: &AppHandle) -> Result<(), tauri::Error> {
@@ -254,536 +1124,4427 @@ impl AppState {
     }
 
     pub fn set_selection_delta(&self, delta: isize) -> bool {
-        if let Ok(mut inner) = self.inner.write() {
-            if let Some(frame) = inner.stack.last_mut() {
-                let Some(max_idx) = max_selectable_index(frame.filtered_items.len()) else {
-                    frame.selected_idx = 0;
-                    return true;
-                };
+        let wrap = self.settings().wrap_selection.unwrap_or(false);
+        self.move_selection(delta, wrap)
+    }
 
-                let current = frame.selected_idx.min(max_idx);
-                let next = (current as isize + delta).clamp(0, max_idx as isize);
-                frame.selected_idx = next as usize;
+    // With `wrap`, moving past either end continues from the other one.
+    fn move_selection(&self, delta: isize, wrap: bool) -> bool {
+        let mut inner = self.write_inner();
+        if let Some(frame) = inner.stack.last_mut() {
+            let Some(max_idx) = max_selectable_index(frame.filtered_items.len()) else {
+                frame.selected_idx = 0;
                 return true;
-            }
+            };
+
+            let current = frame.selected_idx.min(max_idx) as isize;
+            let next = if wrap {
+                current
+                    .saturating_add(delta)
+                    .rem_euclid(max_idx as isize + 1)
+            } else {
+                current.saturating_add(delta).clamp(0, max_idx as isize)
+            } as usize;
+            frame.selected_idx =
+                skip_headers(&frame.filtered_items, next, max_idx, delta >= 0, wrap)
+                    .unwrap_or(next);
+            return true;
         }
         false
     }
 
-    pub fn handle_escape(&self) -> EscapeResult {
-        if let Ok(mut inner) = self.inner.write() {
-            if let Some(frame) = inner.stack.last_mut() {
-                if !frame.query.is_empty() {
-                    frame.query.clear();
-                    frame.selected_idx = 0;
-                    frame.filtered_items = frame.all_items.clone();
-                    frame.is_filtering = false;
-                    return EscapeResult::ClearedQuery;
-                }
+    /// Moves the selection by `page_size` items up (negative `direction`) or down.
+    pub fn set_selection_page(&self, direction: i8, page_size: usize) -> bool {
+        let step = isize::try_from(page_size).unwrap_or(isize::MAX);
+        self.move_selection(isize::from(direction.signum()).saturating_mul(step), false)
+    }
+
+    /// Selects `index`, clamped to the list. False when nothing changed.
+    pub fn set_selection_index(&self, index: usize) -> bool {
+        let mut inner = self.write_inner();
+        let Some(frame) = inner.stack.last_mut() else {
+            return false;
+        };
+        let Some(max_idx) = max_selectable_index(frame.filtered_items.len()) else {
+            return false;
+        };
+
+        let next = index.min(max_idx);
+        let next = skip_headers(&frame.filtered_items, next, max_idx, true, false).unwrap_or(next);
+        if next == frame.selected_idx {
+            return false;
+        }
+        frame.selected_idx = next;
+        true
+    }
+
+    /// Applies a `dispatch` batch in order and calls `emit` once at the end,
+    /// also when a step fails and the rest is skipped. `Invoke` steps go
+    /// through `invoke`, which needs the app handle.
+    pub fn dispatch(
+        &self,
+        actions: Vec<UiAction>,
+        mut invoke: impl FnMut(&str) -> Result<ActionOutcome, String>,
+        emit: impl FnOnce(StateSnapshot),
+    ) -> Result<DispatchResult, String> {
+        let mut result = DispatchResult::default();
+        let mut applied = Ok(());
+        for action in actions {
+            applied = self.apply_ui_action(action, &mut invoke, &mut result);
+            if applied.is_err() {
+                break;
             }
+        }
+        emit(self.snapshot());
+        applied.map(|()| result)
+    }
 
-            if inner.stack.len() > 1 {
-                inner.stack.pop();
-                if let Some(frame) = inner.stack.last_mut() {
-                    clamp_selection(frame);
+    fn apply_ui_action(
+        &self,
+        action: UiAction,
+        invoke: &mut impl FnMut(&str) -> Result<ActionOutcome, String>,
+        result: &mut DispatchResult,
+    ) -> Result<(), String> {
+        match action {
+            UiAction::SetQuery { query } => {
+                if let QueryResult::PushedSpell = self.set_query_immediate(query) {
+                    result.pushed_spell = true;
                 }
-                inner.status = AppStatus::Ready;
-                return EscapeResult::PoppedFrame;
+                result.query_changed = true;
+            }
+            UiAction::SelectDelta { delta } => {
+                self.set_selection_delta(delta);
             }
+            UiAction::SelectIndex { index } => {
+                self.set_selection_index(index);
+            }
+            UiAction::ToggleMark { index } => {
+                self.toggle_mark(index);
+            }
+            UiAction::Invoke { label } => result.outcome = Some(invoke(&label)?),
+        }
+        Ok(())
+    }
+
+    /// Marks or unmarks the item at `index` for `multi` actions. False when
+    /// `index` is past the end of the list.
+    pub fn toggle_mark(&self, index: usize) -> bool {
+        let mut inner = self.write_inner();
+        let Some(frame) = inner.stack.last_mut() else {
+            return false;
+        };
+        if !frame
+            .filtered_items
+            .get(index)
+            .is_some_and(Item::is_selectable)
+        {
+            return false;
+        }
+        if !frame.selected_set.remove(&index) {
+            frame.selected_set.insert(index);
+        }
+        true
+    }
+
+    pub fn handle_escape(&self) -> EscapeResult {
+        let mut inner = self.write_inner();
+        let pops_first = current_spell(&inner)
+            .and_then(|spell| spell.escape_pops_first)
+            .unwrap_or(false);
+        if pops_first && pop_frame(&mut inner) {
+            return EscapeResult::PoppedFrame;
+        }
+
+        if clear_query(&mut inner) {
+            return EscapeResult::ClearedQuery;
+        }
+
+        if pop_frame(&mut inner) {
+            return EscapeResult::PoppedFrame;
         }
 
         EscapeResult::Noop
     }
 
-    fn load_items_for_current_frame(
+    /// Empties the top frame's query and shows its items unfiltered, without
+    /// running a filter. Returns false when the query was already empty.
+    pub fn clear_query(&self) -> bool {
+        let cleared = clear_query(&mut self.write_inner());
+        if cleared {
+            self.next_filter_generation();
+        }
+        cleared
+    }
+
+    /// Switches the top frame to scope `index` of its spell and refilters.
+    /// Returns false when that scope was already active.
+    pub fn set_scope(&self, index: usize) -> Result<bool, String> {
+        {
+            let mut inner = self.write_inner();
+            let count = current_spell(&inner).map_or(0, |spell| spell.scopes.len());
+            if index >= count {
+                return Err(format!("no scope at index {index}"));
+            }
+            let frame = inner.stack.last_mut().ok_or("no active frame")?;
+            if frame.scope_idx == index {
+                return Ok(false);
+            }
+            frame.scope_idx = index;
+            frame.selected_idx = 0;
+        }
+        self.next_filter_generation();
+        Ok(self.filter_items())
+    }
+
+    /// Drops every frame above the root and clears the root's query,
+    /// keeping its loaded items.
+    pub fn collapse_to_root(&self) -> Result<(), String> {
+        {
+            let mut inner = self.write_inner();
+            inner.stack.truncate(1);
+            if let Some(frame) = inner.stack.last_mut() {
+                frame.query.clear();
+                frame.selected_idx = 0;
+                frame.is_filtering = false;
+            }
+            inner.status = AppStatus::Ready;
+            inner.last_error = None;
+        }
+
+        self.next_filter_generation();
+        self.filter_items();
+        Ok(())
+    }
+
+    /// Pops the top frame regardless of its query. False at the root.
+    pub fn pop_frame(&self) -> bool {
+        pop_frame(&mut self.write_inner())
+    }
+
+    fn provider_job(&self) -> Result<Option<ProviderJob>, String> {
+        let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
+        let Some(frame) = inner.stack.last() else {
+            return Ok(None);
+        };
+        let spell = inner
+            .spells
+            .get(&frame.spell_id)
+            .ok_or_else(|| format!("spell not found for frame {}", frame.spell_id))?;
+
+        let cwd = match &spell.cwd {
+            Some(cwd) => Some(template::resolve_template(cwd, &inner.stack).map_err(
+                |e| match e {
+                    template::TemplateError::Render(err) => format!("invalid cwd: {err}"),
+                },
+            )?),
+            None => None,
+        };
+
+        let expand = |script: &str| {
+            if spell.expand_env.unwrap_or(false) {
+                shell::expand_env_vars(script)
+            } else {
+                script.to_string()
+            }
+        };
+
+        Ok(Some(ProviderJob {
+            shell: Shell::resolve(configured_shell(&inner, spell))?,
+            cache_ttl: spell.cache_ttl_ms.map(Duration::from_millis),
+            retries: spell.retries.unwrap_or(0),
+            retry_delay: spell
+                .retry_delay_ms
+                .map_or(DEFAULT_RETRY_DELAY, Duration::from_millis),
+            command: expand(spell.provider.as_deref().unwrap_or_default()),
+            transform: spell.transform.as_deref().map(expand),
+            cwd,
+            line_format: line_format(spell),
+            frame_id: frame.spell_id.clone(),
+            frame_uid: frame.id,
+            cancelled: frame.load_cancelled.subscribe(),
+            spell_entries: spell_entries_for(&inner, spell),
+            source_items: source_items(&inner, spell),
+            env: provider_env(&inner.stack),
+        }))
+    }
+
+    async fn load_items_for_current_frame(
         &self,
         resources_dir: &Path,
     ) -> Result<Option<(Vec<Item>, u64)>, String> {
-        let (provider_cmd, frame_id, frame_uid) = {
-            let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
-            let Some(frame) = inner.stack.last() else {
+        let Some(job) = self.provider_job()? else {
+            return Ok(None);
+        };
+        if let Some(mut items) = job.source_items {
+            let spell_entries: Vec<Item> = job
+                .spell_entries
+                .into_iter()
+                .filter(|entry| !items.contains(entry))
+                .collect();
+            items.extend(spell_entries);
+            return Ok(Some((items, job.frame_uid)));
+        }
+        let frame_id = &job.frame_id;
+        if let Some(mut items) = self.cached_items(&job) {
+            items.extend(job.spell_entries);
+            return Ok(Some((items, job.frame_uid)));
+        }
+        let mut attempt = 0;
+        let (output, started) = loop {
+            let started = Instant::now();
+            match self.run_provider_output(&job, resources_dir, started).await {
+                Ok(Some(output)) => break (output, started),
+                Ok(None) => return Ok(None),
+                Err(err) if attempt < job.retries => {
+                    attempt += 1;
+                    if !self.pause_before_retry(&job, attempt, &err).await {
+                        return Ok(None);
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        let stdout = decode_output(&output.stdout, &job.line_format);
+        let mut items: Vec<Item> = stdout
+            .lines()
+            .filter_map(|line| parse_item_line(line, &job.line_format, frame_id))
+            .collect();
+        self.log_provider_run(
+            frame_id,
+            items.len(),
+            started.elapsed(),
+            &output.status.to_string(),
+        );
+        self.cache_items(&job, &items);
+        items.extend(job.spell_entries);
+        Ok(Some((items, job.frame_uid)))
+    }
+
+    // One provider run; `None` when `cancel_loading` stopped it. Failures are
+    // logged as they happen.
+    async fn run_provider_output(
+        &self,
+        job: &ProviderJob,
+        resources_dir: &Path,
+        started: Instant,
+    ) -> Result<Option<std::process::Output>, String> {
+        let frame_id = &job.frame_id;
+        let failed = |message: String| {
+            self.log_provider_run(frame_id, 0, started.elapsed(), &message);
+            message
+        };
+        // Dropping the output future on cancel kills the provider.
+        let run = ProviderRun::spawn(job, resources_dir, true).map_err(failed)?;
+        let output = tokio::select! {
+            output = run.wait_with_output(frame_id) => output.map_err(failed)?,
+            _ = job.wait_for_cancel() => {
+                self.log_provider_run(frame_id, 0, started.elapsed(), "cancelled");
                 return Ok(None);
-            };
-            let spell = inner
-                .spells
-                .get(&frame.spell_id)
-                .ok_or_else(|| format!("spell not found for frame {}", frame.spell_id))?;
-            (spell.provider.clone(), frame.spell_id.clone(), frame.id)
-        };
-
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(provider_cmd)
-            .current_dir(resources_dir)
-            .output()
-            .map_err(|err| format!("failed to launch provider for {frame_id}: {err}"))?;
-
-        if !output.status.success() {
-            return Err(format!(
-                "provider for {frame_id} exited with status {}",
-                output.status
-            ));
-        }
-
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        Ok(Some((
-            stdout
-                .lines()
-                .filter_map(|line| parse_item_line(line, &frame_id))
-                .collect(),
-            frame_uid,
-        )))
+            }
+        };
+        Ok(Some(output))
+    }
+
+    // Waits out `retry_delay` after a failed run; false when the load was
+    // cancelled meanwhile.
+    async fn pause_before_retry(&self, job: &ProviderJob, attempt: u32, err: &str) -> bool {
+        eprintln!("{err}; retry {attempt}/{}", job.retries);
+        tokio::select! {
+            biased;
+            _ = job.wait_for_cancel() => false,
+            _ = tokio::time::sleep(job.retry_delay) => true,
+        }
     }
 
-    pub fn stream_items_for_current_frame(
+    pub async fn stream_items_for_current_frame(
         &self,
         resources_dir: &Path,
         app: &AppHandle,
+        first_batch: Option<tokio::sync::oneshot::Sender<()>>,
     ) -> Result<(), String> {
-        let (provider_cmd, frame_id, frame_uid) = {
-            let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
-            let Some(frame) = inner.stack.last() else {
-                return Ok(());
+        let streamed = self
+            .stream_items(resources_dir, first_batch, |frame_uid, visible| {
+                let _ = events::emit_items_appended(app, frame_uid, visible);
+            })
+            .await?;
+        if streamed {
+            let _ = self.emit_snapshot(app);
+        }
+        Ok(())
+    }
+
+    // Loads the current frame, streaming spells batch by batch through
+    // `on_batch`. `first_batch` fires on a streaming spell's first batch;
+    // otherwise it is dropped once loading ends.
+    async fn load_frame(
+        &self,
+        resources_dir: &Path,
+        first_batch: Option<tokio::sync::oneshot::Sender<()>>,
+        on_batch: impl Fn(u64, Vec<Item>),
+    ) -> Result<(), String> {
+        let is_streaming = self
+            .get_current_spell()
+            .and_then(|s| s.is_streaming)
+            .unwrap_or(false);
+        if is_streaming {
+            self.stream_items(resources_dir, first_batch, on_batch)
+                .await
+                .map(|_| ())
+        } else {
+            self.finish_loading_with_items(resources_dir).await
+        }
+    }
+
+    /// Streams the current frame's provider output, handing each throttled
+    /// batch of visible items to `on_batch` and firing `first_batch` after
+    /// the first one. Returns whether the frame was still current when the
+    /// provider finished.
+    async fn stream_items(
+        &self,
+        resources_dir: &Path,
+        mut first_batch: Option<tokio::sync::oneshot::Sender<()>>,
+        on_batch: impl Fn(u64, Vec<Item>),
+    ) -> Result<bool, String> {
+        let Some(job) = self.provider_job()? else {
+            return Ok(false);
+        };
+        let frame_id = &job.frame_id;
+        let frame_uid = job.frame_uid;
+        self.set_loading_more(frame_uid, true);
+
+        let mut batch: Vec<Item> = Vec::new();
+        let throttle = Duration::from_millis(500);
+        let mut attempt = 0;
+        let (item_count, started, status) = loop {
+            let started = Instant::now();
+            let mut run = match ProviderRun::spawn(&job, resources_dir, false) {
+                Ok(run) => run,
+                Err(message) => {
+                    self.log_provider_run(frame_id, 0, started.elapsed(), &message);
+                    if attempt < job.retries {
+                        attempt += 1;
+                        if self.pause_before_retry(&job, attempt, &message).await {
+                            continue;
+                        }
+                    }
+                    self.set_loading_more(frame_uid, false);
+                    return Err(message);
+                }
             };
-            let spell = inner
-                .spells
-                .get(&frame.spell_id)
-                .ok_or_else(|| format!("spell not found for frame {}", frame.spell_id))?;
-            (spell.provider.clone(), frame.spell_id.clone(), frame.id)
+
+            let Some(stdout) = run.output_stage().stdout.take() else {
+                self.set_loading_more(frame_uid, false);
+                return Err("no stdout handle".to_string());
+            };
+            let mut lines = tokio::io::BufReader::new(stdout).split(b'\n');
+            let mut item_count = 0;
+            let mut last_emit = Instant::now();
+
+            loop {
+                let segment = tokio::select! {
+                    segment = lines.next_segment() => segment,
+                    _ = job.wait_for_cancel() => {
+                        run.start_kill();
+                        break;
+                    }
+                };
+                let Ok(Some(bytes)) = segment else {
+                    break;
+                };
+                let line = decode_output(&bytes, &job.line_format);
+                let line = line.strip_suffix('\r').unwrap_or(&line);
+                if let Some(item) = parse_item_line(line, &job.line_format, frame_id) {
+                    batch.push(item);
+                    item_count += 1;
+                }
+                if last_emit.elapsed() >= throttle {
+                    if self.is_current_frame(frame_uid) {
+                        let visible =
+                            self.append_items_for_frame(frame_uid, std::mem::take(&mut batch));
+                        on_batch(frame_uid, visible);
+                        if let Some(sender) = first_batch.take() {
+                            let _ = sender.send(());
+                        }
+                    } else {
+                        batch.clear();
+                    }
+                    last_emit = Instant::now();
+                }
+            }
+
+            let exit = run.wait(frame_id).await;
+            let status = exit
+                .as_ref()
+                .map_or_else(Clone::clone, |status| status.to_string());
+            if let Err(err) = exit.as_ref() {
+                if item_count == 0 && attempt < job.retries {
+                    self.log_provider_run(frame_id, 0, started.elapsed(), &status);
+                    attempt += 1;
+                    if self.pause_before_retry(&job, attempt, err).await {
+                        continue;
+                    }
+                }
+            }
+            break (item_count, started, status);
+        };
+
+        batch.extend(job.spell_entries);
+        if !batch.is_empty() && self.is_current_frame(frame_uid) {
+            self.append_items_for_frame(frame_uid, batch);
+        }
+        self.set_loading_more(frame_uid, false);
+
+        let is_current = self.is_current_frame(frame_uid);
+        if is_current {
+            self.set_ready();
+            let has_query = self
+                .inner
+                .read()
+                .map(|inner| inner.stack.last().is_some_and(|f| !f.query.is_empty()))
+                .unwrap_or(false);
+            if has_query {
+                self.filter_items();
+            }
+        }
+        self.log_provider_run(frame_id, item_count, started.elapsed(), &status);
+        Ok(is_current)
+    }
+
+    /// Stops the top frame's provider while it is loading, keeping the items
+    /// it already produced. False when nothing was loading.
+    pub fn cancel_loading(&self) -> bool {
+        let mut inner = self.write_inner();
+        let is_loading = inner.status == AppStatus::Loading;
+        let Some(frame) = inner.stack.last_mut() else {
+            return false;
+        };
+        if !is_loading && !frame.is_loading_more {
+            return false;
+        }
+        frame.load_cancelled.send_replace(true);
+        frame.is_loading_more = false;
+        inner.status = AppStatus::Ready;
+        true
+    }
+
+    fn set_loading_more(&self, frame_uid: u64, loading: bool) {
+        let mut inner = self.write_inner();
+        if let Some(frame) = inner.stack.iter_mut().find(|f| f.id == frame_uid) {
+            frame.is_loading_more = loading;
+        }
+    }
+
+    /// Runs the action named `label`. Actions with a `confirm` prompt only
+    /// return it; `confirm_action` runs them once the user agrees.
+    pub fn invoke_action(
+        &self,
+        label: &str,
+        resources_dir: &Path,
+        app: &AppHandle,
+    ) -> Result<ActionOutcome, String> {
+        match self.invocation(label)? {
+            Invocation::Prompt(outcome) => Ok(outcome),
+            Invocation::Run(resolution) => self.run_resolved(label, resolution, resources_dir, app),
+        }
+    }
+
+    /// Runs `label` without asking, after its `confirm` prompt was accepted.
+    /// Refuses when the selection changed since `invoke_action` showed it.
+    pub fn confirm_action(
+        &self,
+        label: &str,
+        resources_dir: &Path,
+        app: &AppHandle,
+    ) -> Result<ActionOutcome, String> {
+        let resolution = self.confirmed_resolution(label)?;
+        self.run_resolved(label, resolution, resources_dir, app)
+    }
+
+    // Resolves `label`; actions with a `confirm` only get their prompt, and
+    // what it was rendered against is kept for `confirm_action`.
+    fn invocation(&self, label: &str) -> Result<Invocation, String> {
+        let resolution = self.resolve_action(label)?;
+        let Some(outcome) = confirmation_outcome(&resolution) else {
+            return Ok(Invocation::Run(resolution));
         };
+        let mut inner = self.write_inner();
+        inner.pending_confirm = resolution.frames.last().map(|f| pending_confirm(label, f));
+        Ok(Invocation::Prompt(outcome))
+    }
+
+    fn confirmed_resolution(&self, label: &str) -> Result<Resolution, String> {
+        let pending = self
+            .write_inner()
+            .pending_confirm
+            .take()
+            .filter(|pending| pending.label == label)
+            .ok_or_else(|| format!("no confirmation pending for {label}"))?;
+        let resolution = self.resolve_action(label)?;
+        if resolution.frames.last().map(|f| pending_confirm(label, f)) != Some(pending) {
+            return Err("selection changed since the confirmation was asked".to_string());
+        }
+        Ok(resolution)
+    }
+
+    fn run_resolved(
+        &self,
+        label: &str,
+        resolution: Resolution,
+        resources_dir: &Path,
+        app: &AppHandle,
+    ) -> Result<ActionOutcome, String> {
+        let Resolution { action, frames, .. } = resolution;
+        match action {
+            ResolvedAction::Spell(spell_id) => {
+                let result = self.push_spell_frame(&spell_id, resources_dir, app);
+                self.log_action(label, ActionType::Spell, &spell_id, &result);
+                result?;
+                self.record_usage(&frames);
+                Ok(ActionOutcome {
+                    keep_open: true,
+                    ..ActionOutcome::default()
+                })
+            }
+            ResolvedAction::OpenUrl { urls, outcome } => {
+                for rendered_url in urls {
+                    let result = parse_action_url(&rendered_url).and_then(|url| {
+                        app.opener()
+                            .open_url(url.as_str(), None::<&str>)
+                            .map_err(|err| format!("failed to open url: {err}"))
+                    });
+                    self.log_action(label, ActionType::OpenUrl, &rendered_url, &result);
+                    result?;
+                }
+
+                if !outcome.keep_open {
+                    hide_main_window(app);
+                    self.reset_to_root(resources_dir, app)?;
+                }
+                Ok(outcome)
+            }
+            ResolvedAction::Cmd {
+                cmds,
+                shell,
+                outcome,
+            } => {
+                for rendered_cmd in cmds {
+                    let result = run_action_command(shell.as_deref(), &rendered_cmd, resources_dir);
+                    self.log_action(label, ActionType::Cmd, &rendered_cmd, &result);
+                    result?;
+                }
+
+                self.record_usage(&frames);
+                if !outcome.keep_open {
+                    hide_main_window(app);
+                    self.reset_to_root(resources_dir, app)?;
+                }
+                Ok(outcome)
+            }
+        }
+    }
+
+    /// Resolves `label` like `invoke_action` without running it: the spell id,
+    /// URL or command that would be used, one line per marked item for `multi`.
+    pub fn preview_action(&self, label: &str) -> Result<String, String> {
+        Ok(match self.resolve_action(label)?.action {
+            ResolvedAction::Spell(spell_id) => spell_id,
+            ResolvedAction::OpenUrl { urls, .. } => urls
+                .iter()
+                .map(|rendered| parse_action_url(rendered).map(String::from))
+                .collect::<Result<Vec<_>, _>>()?
+                .join("\n"),
+            ResolvedAction::Cmd { cmds, shell, .. } => {
+                for rendered_cmd in &cmds {
+                    if rendered_cmd.trim().is_empty() {
+                        return Err("resolved command is empty".to_string());
+                    }
+                    if shell.is_none() {
+                        action_argv(rendered_cmd)?;
+                    }
+                }
+                cmds.join("\n")
+            }
+        })
+    }
+
+    /// Like `invoke_action`, but a `SPELL` action returns only once the new
+    /// frame has its items, or its first batch for streaming spells, so chained
+    /// invocations never see an empty frame.
+    pub async fn invoke_action_blocking(
+        &self,
+        label: &str,
+        resources_dir: &Path,
+        app: &AppHandle,
+    ) -> Result<ActionOutcome, String> {
+        self.invoke_blocking_with(
+            label,
+            |resolution| self.run_resolved(label, resolution, resources_dir, app),
+            |first_batch| {
+                let _ = self.emit_snapshot(app);
+                self.spawn_load(resources_dir, app, Some(first_batch));
+            },
+        )
+        .await
+    }
+
+    // `invoke_action_blocking` minus the app: other actions go to `run`, and
+    // `load` starts loading a pushed spell and fires the signal it is given.
+    async fn invoke_blocking_with(
+        &self,
+        label: &str,
+        run: impl FnOnce(Resolution) -> Result<ActionOutcome, String>,
+        load: impl FnOnce(tokio::sync::oneshot::Sender<()>),
+    ) -> Result<ActionOutcome, String> {
+        let resolution = match self.invocation(label)? {
+            Invocation::Prompt(outcome) => return Ok(outcome),
+            Invocation::Run(resolution) => resolution,
+        };
+        let Resolution {
+            action: ResolvedAction::Spell(spell_id),
+            frames,
+            ..
+        } = resolution
+        else {
+            return run(resolution);
+        };
+
+        let result = self.push_frame(&spell_id);
+        self.log_action(label, ActionType::Spell, &spell_id, &result);
+        result?;
+        self.record_usage(&frames);
+
+        let (first_batch, loaded) = tokio::sync::oneshot::channel();
+        load(first_batch);
+        let _ = loaded.await;
+        Ok(ActionOutcome {
+            keep_open: true,
+            ..ActionOutcome::default()
+        })
+    }
+
+    // Renders the action `label` would run against the current stack, which is
+    // returned alongside for usage tracking. Labels without an action whose
+    // condition passes mean MAIN. MAIN falls back to the spell's
+    // `fallback_action` when the list is empty or the action renders blank.
+    fn resolve_action(&self, label: &str) -> Result<Resolution, String> {
+        let ActionContext {
+            mut frames,
+            actions,
+            shell,
+            fallback,
+        } = self.action_context()?;
+        let label = match label {
+            "MAIN" => label,
+            _ if !has_matching_action(label, &actions, &frames)? => "MAIN",
+            _ => label,
+        };
+        let fallback = fallback.filter(|_| label == "MAIN");
+        let list_is_empty = frames
+            .last()
+            .is_some_and(|f| template::selected_item(f).is_none());
+
+        let resolved = match fallback {
+            Some(fallback) if list_is_empty => render_fallback(&fallback, &mut frames, &shell)?,
+            fallback => {
+                let resolved = match_action(label, &actions, &mut frames, &shell)?;
+                match fallback {
+                    Some(fallback) if resolved.0.is_blank() => {
+                        render_fallback(&fallback, &mut frames, &shell)?
+                    }
+                    _ => resolved,
+                }
+            }
+        };
+
+        let (action, confirm) = resolved;
+        if matches!(&action, ResolvedAction::Spell(spell_id) if spell_id.is_empty()) {
+            return Err("resolved spell id is empty".to_string());
+        }
+        Ok(Resolution {
+            action,
+            confirm,
+            frames,
+        })
+    }
+
+    fn action_context(&self) -> Result<ActionContext, String> {
+        let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
+        let spell = inner
+            .stack
+            .last()
+            .and_then(|frame| inner.spells.get(&frame.spell_id))
+            .ok_or_else(|| "no active spell".to_string())?;
+        let shell = configured_shell(&inner, spell).map(str::to_string);
+        let expand_env = spell.expand_env.unwrap_or(false);
+        let expand = |action: &Action| {
+            let mut action = action.clone();
+            if let (true, Action::Cmd { cmd, .. }) = (expand_env, &mut action) {
+                *cmd = shell::expand_env_vars(cmd);
+            }
+            action
+        };
+        Ok(ActionContext {
+            frames: inner.stack.clone(),
+            actions: spell.actions.iter().map(expand).collect(),
+            shell,
+            fallback: spell.fallback_action.as_ref().map(expand),
+        })
+    }
+
+    /// Opens `spell_id` on top of the stack like a `SPELL` action and starts
+    /// loading its items. Fails for unknown or disabled spells.
+    pub fn push_spell_frame(
+        &self,
+        spell_id: &str,
+        resources_dir: &Path,
+        app: &AppHandle,
+    ) -> Result<(), String> {
+        self.push_frame(spell_id)?;
+        let _ = self.emit_snapshot(app);
+        self.load_current_frame(resources_dir, app);
+        Ok(())
+    }
+
+    fn push_frame(&self, spell_id: &str) -> Result<(), String> {
+        {
+            let mut inner = self.write_inner();
+            ensure_spell_enabled(&inner, spell_id)?;
+            let max_depth = inner
+                .settings
+                .max_stack_depth
+                .unwrap_or(DEFAULT_MAX_STACK_DEPTH);
+            if inner.stack.len() >= max_depth {
+                return Err("maximum spell depth reached".to_string());
+            }
+            let frame = new_frame(&mut inner, spell_id.to_string());
+            inner.stack.push(frame);
+            inner.status = AppStatus::Loading;
+            inner.usage.record_spell(spell_id);
+        }
+        self.save_usage();
+        Ok(())
+    }
+
+    pub fn load_current_frame(&self, resources_dir: &Path, app: &AppHandle) {
+        self.spawn_load(resources_dir, app, None);
+    }
+
+    // `first_batch` fires on a streaming spell's first batch; otherwise it is
+    // dropped once loading ends.
+    fn spawn_load(
+        &self,
+        resources_dir: &Path,
+        app: &AppHandle,
+        first_batch: Option<tokio::sync::oneshot::Sender<()>>,
+    ) {
+        let state = self.clone();
+        let resources_dir = resources_dir.to_path_buf();
+        let app_handle = app.clone();
+        async_runtime::spawn(async move {
+            let result = state
+                .load_frame(&resources_dir, first_batch, |frame_uid, visible| {
+                    let _ = events::emit_items_appended(&app_handle, frame_uid, visible);
+                })
+                .await;
+
+            match result {
+                Ok(()) => {
+                    let _ = state.emit_snapshot(&app_handle);
+                    state.spawn_preview(&resources_dir, &app_handle);
+                }
+                Err(err) => {
+                    eprintln!("failed to load items: {err}");
+                    state.set_error(err);
+                    let _ = state.emit_snapshot(&app_handle);
+                }
+            }
+        });
+    }
+
+    fn cached_items(&self, job: &ProviderJob) -> Option<Vec<Item>> {
+        let ttl = job.cache_ttl?;
+        let inner = self.inner.read().ok()?;
+        inner
+            .provider_cache
+            .get(&job.cache_key())
+            .filter(|cached| cached.loaded_at.elapsed() < ttl)
+            .map(|cached| cached.items.clone())
+    }
+
+    fn cache_items(&self, job: &ProviderJob, items: &[Item]) {
+        if job.cache_ttl.is_none() {
+            return;
+        }
+        let mut inner = self.write_inner();
+        inner.provider_cache.insert(
+            job.cache_key(),
+            CachedItems {
+                items: items.to_vec(),
+                loaded_at: Instant::now(),
+            },
+        );
+    }
+
+    fn is_current_frame(&self, frame_uid: u64) -> bool {
+        if let Ok(inner) = self.inner.read() {
+            is_current_frame(&inner, frame_uid)
+        } else {
+            false
+        }
+    }
+
+    fn append_items_for_frame(&self, frame_uid: u64, new_items: Vec<Item>) -> Vec<Item> {
+        append_items_for_frame(&mut self.write_inner(), frame_uid, new_items)
+    }
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_current_frame(inner: &AppInner, frame_uid: u64) -> bool {
+    inner
+        .stack
+        .last()
+        .map(|frame| frame.id == frame_uid)
+        .unwrap_or(false)
+}
+
+// Streamed batches go through the current query so typed-ahead filters hold.
+// Returns the items that became visible.
+fn append_items_for_frame(inner: &mut AppInner, frame_uid: u64, new_items: Vec<Item>) -> Vec<Item> {
+    let shows_placeholders = current_placeholder_items(inner).is_some()
+        && inner.stack.last().is_some_and(|f| f.query.is_empty());
+    let config = inner
+        .stack
+        .last()
+        .and_then(|frame| inner.spells.get(&frame.spell_id))
+        .and_then(|spell| spell.search.clone());
+    let mut scope = scope_filter(inner);
+    if let Some(frame) = inner.stack.last_mut() {
+        if frame.id == frame_uid {
+            let mut visible: Vec<Item> = match &config {
+                _ if shows_placeholders => Vec::new(),
+                Some(cfg) if !frame.query.is_empty() => {
+                    crate::core::search::filter_items(&new_items, &frame.query, cfg)
+                        .into_iter()
+                        .filter(|m| !m.item.is_header())
+                        .map(matched_item)
+                        .collect()
+                }
+                _ => new_items.clone(),
+            };
+            visible.retain(|item| in_scope(&mut scope, item));
+            frame.all_items.extend(new_items);
+            add_type_counts(&mut frame.type_counts, &visible);
+            frame.filtered_items.extend(visible.clone());
+            clamp_selection(frame);
+            return visible;
+        }
+    }
+    Vec::new()
+}
+
+// Ranges are only kept when they point into the displayed Name; a hidden
+// search key column (`search: {field: 4}`) matches without highlights.
+fn matched_item(m: crate::core::fuzzy::Match) -> Item {
+    let shows_ranges = m.item.field_is_name(m.field.saturating_sub(1));
+    Item {
+        match_ranges: if shows_ranges { m.ranges } else { Vec::new() },
+        score: Some(m.score),
+        ..m.item.clone()
+    }
+}
+
+fn placeholder_items(inner: &AppInner, spell_id: &str) -> Option<Vec<Item>> {
+    let lines = inner.spells.get(spell_id)?.placeholder_items.as_ref()?;
+    Some(
+        lines
+            .iter()
+            .filter_map(|line| Item::from_line(line))
+            .map(|item| Item {
+                placeholder: true,
+                ..item
+            })
+            .collect(),
+    )
+}
+
+fn current_placeholder_items(inner: &AppInner) -> Option<Vec<Item>> {
+    placeholder_items(inner, &inner.stack.last()?.spell_id)
+}
+
+impl AppState {
+    fn reset_to_root(&self, resources_dir: &Path, app: &AppHandle) -> Result<(), String> {
+        {
+            let mut inner = self.write_inner();
+            let start_id = start_spell_id(&inner);
+            if !inner.spells.contains_key(&start_id) {
+                return Err(format!("spell {start_id} not found"));
+            }
+            let frame = new_frame(&mut inner, start_id);
+            inner.stack.clear();
+            inner.stack.push(frame);
+            inner.status = AppStatus::Loading;
+        }
+
+        let _ = self.emit_snapshot(app);
+
+        let state = self.clone();
+        let resources_dir = resources_dir.to_path_buf();
+        let app_handle = app.clone();
+        async_runtime::spawn(async move {
+            let is_streaming = state
+                .get_current_spell()
+                .and_then(|s| s.is_streaming)
+                .unwrap_or(false);
+
+            let result = if is_streaming {
+                state
+                    .stream_items_for_current_frame(&resources_dir, &app_handle, None)
+                    .await
+            } else {
+                state.finish_loading_with_items(&resources_dir).await
+            };
+
+            match result {
+                Ok(()) => {
+                    let _ = state.emit_snapshot(&app_handle);
+                    state.spawn_preview(&resources_dir, &app_handle);
+                }
+                Err(err) => {
+                    eprintln!("failed to load items after reset: {err}");
+                    state.set_error(err);
+                    let _ = state.emit_snapshot(&app_handle);
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+fn hide_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window(MAIN_WINDOW_LABEL) {
+        let _ = window.hide();
+    }
+}
+
+fn matching_actions(inner: &AppInner) -> Vec<AvailableAction> {
+    let frames = &inner.stack;
+    let Some(frame) = frames.last() else {
+        return Vec::new();
+    };
+    let Some(spell) = inner.spells.get(&frame.spell_id) else {
+        return Vec::new();
+    };
+
+    if template::selected_item(frame).is_some_and(|item| item.item_type == SPELL_ENTRY_TYPE) {
+        return vec![AvailableAction {
+            label: "MAIN".to_string(),
+            action_type: ActionType::Spell,
+        }];
+    }
+
+    spell
+        .actions
+        .iter()
+        .filter_map(
+            |action| match condition_passes(action_condition(action), frames) {
+                Ok(true) => Some(AvailableAction {
+                    label: action_name(action).unwrap_or("MAIN").to_string(),
+                    action_type: ActionType::from(action),
+                }),
+                Ok(false) => None,
+                Err(err) => {
+                    eprintln!("failed to evaluate action condition: {err}");
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+fn new_frame(inner: &mut AppInner, spell_id: String) -> Frame {
+    let id = inner.next_frame_id;
+    inner.next_frame_id = inner.next_frame_id.wrapping_add(1);
+    let query = inner
+        .remembered_queries
+        .get(&spell_id)
+        .filter(|_| {
+            inner
+                .spells
+                .get(&spell_id)
+                .and_then(|s| s.remember_query)
+                .unwrap_or(false)
+        })
+        .cloned()
+        .unwrap_or_default();
+    let is_streaming = inner
+        .spells
+        .get(&spell_id)
+        .and_then(|s| s.is_streaming)
+        .unwrap_or(false);
+    Frame {
+        id,
+        spell_id,
+        query,
+        all_items: Vec::new(),
+        filtered_items: Vec::new(),
+        is_filtering: false,
+        is_loading_more: is_streaming,
+        selected_idx: 0,
+        selected_set: HashSet::new(),
+        scope_idx: 0,
+        load_cancelled: tokio::sync::watch::Sender::new(false),
+        reload_error: None,
+        type_counts: HashMap::new(),
+    }
+}
+
+// Spell id of the current frame when that spell remembers its query.
+fn remembering_spell_id(inner: &AppInner) -> Option<String> {
+    let frame = inner.stack.last()?;
+    let spell = inner.spells.get(&frame.spell_id)?;
+    spell
+        .remember_query
+        .unwrap_or(false)
+        .then(|| spell.id.clone())
+}
+
+fn current_spell(inner: &AppInner) -> Option<&Spell> {
+    let frame = inner.stack.last()?;
+    inner.spells.get(&frame.spell_id)
+}
+
+fn clear_query(inner: &mut AppInner) -> bool {
+    if inner.stack.last().is_none_or(|f| f.query.is_empty()) {
+        return false;
+    }
+    if let Some(spell_id) = remembering_spell_id(inner) {
+        inner.remembered_queries.remove(&spell_id);
+    }
+    let items = current_placeholder_items(inner).unwrap_or_else(|| scoped_items(inner));
+    let Some(frame) = inner.stack.last_mut() else {
+        return false;
+    };
+    frame.query.clear();
+    frame.selected_idx = 0;
+    frame.type_counts = type_counts(&items);
+    frame.filtered_items = items;
+    frame.selected_set.clear();
+    frame.is_filtering = false;
+    clamp_selection(frame);
+    true
+}
+
+// The top frame's active scope filter, compiled once per pass. `None` when
+// the spell has no scopes or the active one keeps every item.
+fn scope_filter(inner: &AppInner) -> Option<template::ItemTemplate> {
+    let frame = inner.stack.last()?;
+    let spell = inner.spells.get(&frame.spell_id)?;
+    let filter = spell.scopes.get(frame.scope_idx)?.filter.as_deref()?;
+    match template::ItemTemplate::new(filter, &inner.stack) {
+        Ok(filter) => Some(filter),
+        Err(template::TemplateError::Render(err)) => {
+            eprintln!("invalid scope filter for {}: {err}", spell.id);
+            None
+        }
+    }
+}
+
+fn in_scope(filter: &mut Option<template::ItemTemplate>, item: &Item) -> bool {
+    let Some(filter) = filter else {
+        return true;
+    };
+    filter
+        .render(item)
+        .is_ok_and(|rendered| rendered_condition_passes(&rendered))
+}
+
+// The top frame's items in its active scope, unfiltered by the query.
+fn scoped_items(inner: &AppInner) -> Vec<Item> {
+    unfiltered_items(inner).into_iter().cloned().collect()
+}
+
+// The top frame's items in the active scope as shown without a query: most
+// used first for spells with `rank_by_usage`, ties keeping provider order.
+fn unfiltered_items(inner: &AppInner) -> Vec<&Item> {
+    let Some(frame) = inner.stack.last() else {
+        return Vec::new();
+    };
+    let mut scope = scope_filter(inner);
+    let mut items: Vec<&Item> = frame
+        .all_items
+        .iter()
+        .filter(|item| in_scope(&mut scope, item))
+        .collect();
+    if let Some(bonuses) = usage_bonuses(inner) {
+        items.sort_by_key(|item| std::cmp::Reverse(bonuses.get(&item.data).copied().unwrap_or(0)));
+    }
+    items
+}
+
+// Launch bonuses by item data when the top frame's spell ranks by usage.
+fn usage_bonuses(inner: &AppInner) -> Option<HashMap<String, u32>> {
+    let spell = current_spell(inner).filter(|s| s.rank_by_usage.unwrap_or(false))?;
+    Some(inner.usage.bonuses_for(&spell.id, usage::now_secs()))
+}
+
+// Timing of the latest filter, only with the `debug_metrics` setting.
+fn last_filter_debug(inner: &AppInner) -> Option<(u64, usize)> {
+    if !inner.settings.debug_metrics.unwrap_or(false) {
+        return None;
+    }
+    let last = inner.recent_filters.back()?;
+    Some((last.elapsed_micros, last.items))
+}
+
+// Scope names of the top frame's spell and the active index.
+fn current_scopes(inner: &AppInner) -> (Vec<String>, Option<usize>) {
+    let Some(frame) = inner.stack.last() else {
+        return (Vec::new(), None);
+    };
+    let Some(spell) = current_spell(inner).filter(|spell| !spell.scopes.is_empty()) else {
+        return (Vec::new(), None);
+    };
+    let names = spell
+        .scopes
+        .iter()
+        .map(|scope| scope.name.clone())
+        .collect();
+    (names, Some(frame.scope_idx))
+}
+
+fn pop_frame(inner: &mut AppInner) -> bool {
+    if inner.stack.len() <= 1 {
+        return false;
+    }
+    inner.stack.pop();
+    if let Some(frame) = inner.stack.last_mut() {
+        frame.selected_set.clear();
+        clamp_selection(frame);
+    }
+    inner.status = AppStatus::Ready;
+    inner.last_error = None;
+    true
+}
+
+fn pending_confirm(label: &str, frame: &Frame) -> PendingConfirm {
+    PendingConfirm {
+        label: label.to_string(),
+        frame_uid: frame.id,
+        query: frame.query.clone(),
+        selected: template::selected_item(frame).cloned(),
+        marked: marked_indices(frame),
+    }
+}
+
+fn marked_indices(frame: &Frame) -> Vec<usize> {
+    let mut marked: Vec<usize> = frame.selected_set.iter().copied().collect();
+    marked.sort_unstable();
+    marked
+}
+
+fn current_query_error(inner: &AppInner) -> Option<String> {
+    let frame = inner.stack.last()?;
+    let config = current_spell(inner)?.search.as_ref()?;
+    crate::core::search::query_error(&frame.query, config)
+}
+
+fn add_type_counts<'a>(
+    counts: &mut HashMap<String, usize>,
+    items: impl IntoIterator<Item = &'a Item>,
+) {
+    for item in items {
+        *counts.entry(item.item_type.clone()).or_insert(0) += 1;
+    }
+}
+
+fn type_counts<'a>(items: impl IntoIterator<Item = &'a Item>) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    add_type_counts(&mut counts, items);
+    counts
+}
+
+// Renders `template` once per marked item of the top frame, in list order.
+// Without marks, or for actions without `multi`, only the selection is used.
+fn resolve_per_item(
+    template: &str,
+    frames: &mut [Frame],
+    multi: bool,
+) -> Result<Vec<String>, String> {
+    let render = |frames: &[Frame]| {
+        template::resolve_template(template, frames).map_err(|e| match e {
+            template::TemplateError::Render(err) => err,
+        })
+    };
+    let marked = match frames.last() {
+        Some(frame) if multi => marked_indices(frame),
+        _ => Vec::new(),
+    };
+    if marked.is_empty() {
+        return render(frames).map(|rendered| vec![rendered]);
+    }
+
+    let top = frames.len() - 1;
+    let selected_idx = frames[top].selected_idx;
+    let rendered = marked
+        .into_iter()
+        .map(|idx| {
+            frames[top].selected_idx = idx;
+            render(frames)
+        })
+        .collect();
+    frames[top].selected_idx = selected_idx;
+    rendered
+}
+
+// Disabled spells stay loaded but can't be opened.
+fn ensure_spell_enabled(inner: &AppInner, spell_id: &str) -> Result<(), String> {
+    match inner.spells.get(spell_id) {
+        Some(spell) if spell.enabled => Ok(()),
+        Some(_) => Err(format!("spell {spell_id} is disabled")),
+        None => Err(format!("spell {spell_id} not found")),
+    }
+}
+
+// The configured `start_spell` when it is loaded, otherwise `STARTING_SPELL_ID`.
+fn start_spell_id(inner: &AppInner) -> String {
+    inner
+        .settings
+        .start_spell
+        .clone()
+        .filter(|_| missing_start_spell(inner).is_none())
+        .unwrap_or_else(|| STARTING_SPELL_ID.to_string())
+}
+
+// The configured `start_spell` when no loaded spell has that id.
+fn missing_start_spell(inner: &AppInner) -> Option<&str> {
+    inner
+        .settings
+        .start_spell
+        .as_deref()
+        .filter(|id| !inner.spells.contains_key(*id))
+}
+
+// Once per spell load rather than on every reset to the root.
+fn warn_missing_start_spell(inner: &AppInner) {
+    if let Some(id) = missing_start_spell(inner) {
+        eprintln!("start_spell {id} not found, starting with {STARTING_SPELL_ID}");
+    }
+}
+
+fn root_spell_id(inner: &AppInner) -> &str {
+    inner
+        .stack
+        .first()
+        .map_or(STARTING_SPELL_ID, |frame| frame.spell_id.as_str())
+}
+
+// Matches "<alias> <rest>" typed into the starting spell's frame.
+fn match_alias(inner: &AppInner, query: &str) -> Option<(String, String)> {
+    if inner.stack.len() != 1 {
+        return None;
+    }
+
+    let (prefix, rest) = query.split_once(' ')?;
+    inner
+        .spells
+        .values()
+        .filter(|spell| spell.enabled)
+        .find(|spell| {
+            spell
+                .alias
+                .as_deref()
+                .is_some_and(|alias| !alias.is_empty() && alias.eq_ignore_ascii_case(prefix))
+        })
+        .map(|spell| (spell.id.clone(), rest.to_string()))
+}
+
+fn spell_entries_for(inner: &AppInner, spell: &Spell) -> Vec<Item> {
+    let root_id = root_spell_id(inner);
+    if spell.id != root_id || !spell.include_spells.unwrap_or(false) {
+        return Vec::new();
+    }
+    spell_entries(&inner.spells, root_id)
+}
+
+fn spell_entries(spells: &HashMap<String, Spell>, root_id: &str) -> Vec<Item> {
+    let mut entries: Vec<&Spell> = spells
+        .values()
+        .filter(|spell| spell.enabled && spell.id != root_id)
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    entries.into_iter().map(spell_entry).collect()
+}
+
+fn source_items(inner: &AppInner, spell: &Spell) -> Option<Vec<Item>> {
+    let Some(source) = spell.source else {
+        let lines = spell
+            .items
+            .as_ref()
+            .filter(|_| spell.provider.as_deref().unwrap_or_default().is_empty())?;
+        let format = line_format(spell);
+        return Some(
+            lines
+                .iter()
+                .filter_map(|line| parse_item_line(line, &format, &spell.id))
+                .collect(),
+        );
+    };
+    match source {
+        SpellSource::RecentSpells => Some(
+            inner
+                .usage
+                .recent_spells()
+                .iter()
+                .filter_map(|id| inner.spells.get(id))
+                .filter(|recent| recent.enabled && recent.id != spell.id)
+                .map(spell_entry)
+                .collect(),
+        ),
+    }
+}
+
+fn spell_entry(spell: &Spell) -> Item {
+    Item {
+        item_type: SPELL_ENTRY_TYPE.to_string(),
+        name: spell_entry_name(spell),
+        data: spell.id.clone(),
+        extra: spell_entry_tags(spell).into_iter().collect(),
+        match_ranges: Vec::new(),
+        score: None,
+        placeholder: false,
+    }
+}
+
+// The alias is part of the name so the root's search finds it.
+fn spell_entry_name(spell: &Spell) -> String {
+    match &spell.alias {
+        Some(alias) if !alias.is_empty() => format!("[S] {} ({alias})", spell.name),
+        _ => format!("[S] {}", spell.name),
+    }
+}
+
+// Lowercased tags for the entry's hidden fourth field, which the root can
+// search alongside the name.
+fn spell_entry_tags(spell: &Spell) -> Option<String> {
+    let tags: Vec<String> = spell
+        .tags
+        .iter()
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| tag.to_lowercase())
+        .collect();
+    (!tags.is_empty()).then(|| tags.join(" "))
+}
+
+impl ProviderJob {
+    // Resolves once `cancel_loading` hit the job's frame; never for a frame
+    // that is gone without being cancelled.
+    async fn wait_for_cancel(&self) {
+        let mut cancelled = self.cancelled.clone();
+        if cancelled.wait_for(|cancelled| *cancelled).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    // Cached output only stands in for the exact same run: the rendered
+    // provider and transform, its cwd and its environment.
+    fn cache_key(&self) -> String {
+        let env: Vec<String> = self.env.iter().map(|(k, v)| format!("{k}={v}")).collect();
+        let run = [
+            self.command.as_str(),
+            self.transform.as_deref().unwrap_or(""),
+            self.cwd.as_deref().unwrap_or(""),
+            &env.join("\0"),
+        ]
+        .join("\0");
+        format!("{}{run}", spell_key_prefix(&self.frame_id))
+    }
+
+    fn env_value(&self, key: &str) -> &str {
+        self.env
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or("")
+    }
+}
+
+// Without a configured shell the command is split and run directly.
+fn run_action_command(
+    shell: Option<&str>,
+    rendered_cmd: &str,
+    resources_dir: &Path,
+) -> Result<(), String> {
+    if rendered_cmd.trim().is_empty() {
+        return Err("resolved command is empty".to_string());
+    }
+
+    let mut command = match shell {
+        Some(shell) => Shell::resolve(Some(shell))?.command(rendered_cmd),
+        None => {
+            let argv = action_argv(rendered_cmd)?;
+            let mut command = std::process::Command::new(&argv[0]);
+            command.args(&argv[1..]);
+            command
+        }
+    };
+
+    let status = command
+        .current_dir(resources_dir)
+        .status()
+        .map_err(|err| format!("failed to run action command: {err}"))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("action command exited with status {status}"))
+    }
+}
+
+// Splits a shell-less `CMD` like a shell would; never empty on success.
+fn action_argv(rendered_cmd: &str) -> Result<Vec<String>, String> {
+    let argv = shell_words::split(rendered_cmd)
+        .map_err(|err| format!("failed to parse action command: {err}"))?;
+    if argv.is_empty() {
+        return Err("resolved command is empty".to_string());
+    }
+    Ok(argv)
+}
+
+// The first action named `label` whose condition passes. MAIN on a spell
+// entry opens that spell instead.
+fn match_action(
+    label: &str,
+    actions: &[Action],
+    frames: &mut [Frame],
+    shell: &Option<String>,
+) -> Result<Rendered, String> {
+    if label == "MAIN" {
+        if let Some(item) = frames.last().and_then(template::selected_item) {
+            if item.item_type == SPELL_ENTRY_TYPE {
+                return Ok((ResolvedAction::Spell(item.data.clone()), None));
+            }
+        }
+    }
+
+    let mut condition_failed = false;
+    for action in actions {
+        if action_name(action).unwrap_or("MAIN") != label {
+            continue;
+        }
+        if !condition_passes(action_condition(action), frames)? {
+            condition_failed = true;
+            continue;
+        }
+        return render_action(action, frames, shell);
+    }
+
+    if condition_failed {
+        Err("condition not met".to_string())
+    } else {
+        Err(format!("no matching action for label {label}"))
+    }
+}
+
+fn has_matching_action(label: &str, actions: &[Action], frames: &[Frame]) -> Result<bool, String> {
+    for action in actions {
+        if action_name(action).unwrap_or("MAIN") == label
+            && condition_passes(action_condition(action), frames)?
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn render_fallback(
+    fallback: &Action,
+    frames: &mut [Frame],
+    shell: &Option<String>,
+) -> Result<Rendered, String> {
+    if !condition_passes(action_condition(fallback), frames)? {
+        return Err("condition not met".to_string());
+    }
+    render_action(fallback, frames, shell)
+}
+
+// Renders an action's templates; the results are validated when it runs.
+fn render_action(
+    action: &Action,
+    frames: &mut [Frame],
+    shell: &Option<String>,
+) -> Result<Rendered, String> {
+    let render = |template: &str, frames: &[Frame]| {
+        template::resolve_template(template, frames).map_err(|e| match e {
+            template::TemplateError::Render(err) => err,
+        })
+    };
+    let confirm = match action_confirm(action) {
+        Some(prompt) => Some(render(prompt, frames)?),
+        None => None,
+    };
+    let outcome = action_outcome(action);
+    let resolved = match action {
+        Action::Spell { spell, .. } => {
+            ResolvedAction::Spell(render(spell, frames)?.trim().to_string())
+        }
+        Action::OpenUrl { url, multi, .. } => ResolvedAction::OpenUrl {
+            urls: resolve_per_item(url, frames, multi.unwrap_or(false))?,
+            outcome,
+        },
+        Action::Cmd { cmd, multi, .. } => ResolvedAction::Cmd {
+            cmds: resolve_per_item(cmd, frames, multi.unwrap_or(false))?,
+            shell: shell.clone(),
+            outcome,
+        },
+    };
+    Ok((resolved, confirm))
+}
+
+// The outcome asking the user to confirm, for actions with a `confirm` prompt.
+fn confirmation_outcome(resolution: &Resolution) -> Option<ActionOutcome> {
+    let prompt = resolution.confirm.clone()?;
+    Some(ActionOutcome {
+        keep_open: true,
+        needs_confirm: true,
+        prompt: Some(prompt),
+    })
+}
+
+// Resolves once a newer generation than `generation` has started.
+async fn superseded(generations: &tokio::sync::watch::Sender<u64>, generation: u64) {
+    let _ = generations
+        .subscribe()
+        .wait_for(|latest| *latest != generation)
+        .await;
+}
+
+// `None` when `cancelled` resolved first; dropping the child kills it.
+async fn run_preview(
+    job: &PreviewJob,
+    resources_dir: &Path,
+    cancelled: impl std::future::Future<Output = ()>,
+) -> Result<Option<String>, String> {
+    let script = job.script.clone()?;
+    let cwd = job.cwd.clone()?;
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let mut command =
+        tokio::process::Command::from(Shell::resolve(job.shell.as_deref())?.command(&script));
+    let child = command
+        .current_dir(provider_cwd(cwd.as_deref(), resources_dir, home.as_deref()))
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("failed to launch preview command: {err}"))?;
+
+    let output = tokio::select! {
+        output = child.wait_with_output() => output
+            .map_err(|err| format!("failed to wait for preview command: {err}"))?,
+        _ = cancelled => return Ok(None),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "preview command exited with status {}",
+            output.status
+        ));
+    }
+    let end = output.stdout.len().min(PREVIEW_OUTPUT_LIMIT);
+    Ok(Some(
+        String::from_utf8_lossy(&output.stdout[..end]).into_owned(),
+    ))
+}
+
+fn preview_error(err: &str) -> String {
+    let escaped: String = err.escape_debug().take(PREVIEW_ERROR_LIMIT).collect();
+    format!("preview failed: {escaped}")
+}
+
+fn cache_preview(inner: &mut AppInner, key: String, preview: String) {
+    if inner.preview_cache.len() >= PREVIEW_CACHE_LIMIT {
+        inner.preview_cache.clear();
+    }
+    inner.preview_cache.insert(key, preview);
+}
+
+// Shared by the provider and preview caches so `refresh` can drop a spell's
+// entries from both.
+fn spell_key_prefix(spell_id: &str) -> String {
+    format!("{spell_id}\t")
+}
+
+fn preview_key(spell_id: &str, data: &str) -> String {
+    format!("{}{data}", spell_key_prefix(spell_id))
+}
+
+fn current_preview(inner: &AppInner) -> Option<String> {
+    let frame = inner.stack.last()?;
+    let spell = inner.spells.get(&frame.spell_id)?;
+    spell.preview_cmd.as_ref()?;
+    let item = template::selected_item(frame)?;
+    inner
+        .preview_cache
+        .get(&preview_key(&frame.spell_id, &item.data))
+        .cloned()
+}
+
+// A template error shows the message unresolved rather than hiding it.
+fn current_empty_message(inner: &AppInner) -> Option<String> {
+    if inner.status != AppStatus::Ready {
+        return None;
+    }
+    let Some(frame) = inner.stack.last() else {
+        return inner.no_spells_message.clone();
+    };
+    if !frame.filtered_items.is_empty() {
+        return None;
+    }
+    let message = inner.spells.get(&frame.spell_id)?.empty_message.as_ref()?;
+    Some(template::resolve_template(message, &inner.stack).unwrap_or_else(|_| message.clone()))
+}
+
+// Spell override first, then the global setting; `None` means the platform default.
+fn configured_shell<'a>(inner: &'a AppInner, spell: &'a Spell) -> Option<&'a str> {
+    spell.shell.as_deref().or(inner.settings.shell.as_deref())
+}
+
+fn provider_command(job: &ProviderJob, resources_dir: &Path) -> Command {
+    stage_command(job, &job.command, resources_dir)
+}
+
+// `script` run like the provider: same shell, cwd and environment.
+fn stage_command(job: &ProviderJob, script: &str, resources_dir: &Path) -> Command {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let mut command = job.shell.command(script);
+    command
+        .current_dir(provider_cwd(
+            job.cwd.as_deref(),
+            resources_dir,
+            home.as_deref(),
+        ))
+        .envs(job.env.iter().map(|(key, value)| (key, value)))
+        .env("QUICKSPELL_RESOURCES_DIR", resources_dir)
+        .env("QUICKSPELL_PROVIDERS_DIR", resources_dir.join("providers"));
+    command
+}
+
+fn provider_cwd(cwd: Option<&str>, resources_dir: &Path, home: Option<&Path>) -> PathBuf {
+    let Some(cwd) = cwd.map(str::trim).filter(|c| !c.is_empty()) else {
+        return resources_dir.to_path_buf();
+    };
+
+    let path = match (cwd.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            home.join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(cwd),
+    };
+
+    if path.is_absolute() {
+        path
+    } else {
+        resources_dir.join(path)
+    }
+}
+
+/// Environment passed to providers. Every variable is always set, empty when
+/// there is no parent frame or selection:
+/// - `QUICKSPELL_QUERY`: query of the frame being loaded
+/// - `QUICKSPELL_PARENT_SPELL_ID`: spell id of the frame below it
+/// - `QUICKSPELL_PARENT_SELECTION_DATA`: Data of that frame's selected item
+///
+/// `provider_command` adds `QUICKSPELL_RESOURCES_DIR` and
+/// `QUICKSPELL_PROVIDERS_DIR` so scripts can call siblings by absolute path.
+fn provider_env(stack: &[Frame]) -> Vec<(&'static str, String)> {
+    let query = stack.last().map(|f| f.query.clone()).unwrap_or_default();
+    let parent = stack.len().checked_sub(2).and_then(|idx| stack.get(idx));
+    let parent_spell_id = parent.map(|f| f.spell_id.clone()).unwrap_or_default();
+    let parent_data = parent
+        .and_then(template::selected_item)
+        .map(|item| item.data.clone())
+        .unwrap_or_default();
+
+    vec![
+        ("QUICKSPELL_QUERY", query),
+        ("QUICKSPELL_PARENT_SPELL_ID", parent_spell_id),
+        ("QUICKSPELL_PARENT_SELECTION_DATA", parent_data),
+    ]
+}
+
+fn line_format(spell: &Spell) -> LineFormat {
+    let delimiter = match spell.delimiter.as_deref() {
+        None | Some("") => DEFAULT_DELIMITER,
+        Some("\\0") => "\0",
+        Some(other) => other,
+    };
+    LineFormat {
+        format: spell.format,
+        delimiter: delimiter.to_string(),
+        unescape: spell.unescape_items.unwrap_or(false),
+        lenient: spell.lenient_parsing.unwrap_or(false),
+        encoding: spell.encoding.as_deref().and_then(|label| {
+            let encoding = encoding_rs::Encoding::for_label(label.as_bytes());
+            if encoding.is_none() {
+                eprintln!(
+                    "unknown encoding {label} for spell {}, using utf-8",
+                    spell.id
+                );
+            }
+            encoding
+        }),
+    }
+}
+
+// Provider output as text in the spell's encoding, lossy UTF-8 by default.
+fn decode_output<'a>(bytes: &'a [u8], line_format: &LineFormat) -> Cow<'a, str> {
+    match line_format.encoding {
+        Some(encoding) => encoding.decode_without_bom_handling(bytes).0,
+        None => String::from_utf8_lossy(bytes),
+    }
+}
+
+fn parse_item_line(line: &str, line_format: &LineFormat, frame_id: &str) -> Option<Item> {
+    if line.trim().is_empty() {
+        return None;
+    }
+
+    let parsed = match line_format.format {
+        ItemFormat::Tsv => {
+            let item = if line_format.lenient {
+                Item::from_partial_line(line, &line_format.delimiter)
+            } else {
+                Item::from_delimited_line(line, &line_format.delimiter)
+            };
+            if line_format.unescape {
+                item.map(Item::unescaped)
+            } else {
+                item
+            }
+        }
+        ItemFormat::Json => Item::from_json_line(line),
+    };
+
+    match parsed {
+        Some(item) => Some(item),
+        None => {
+            eprintln!("skipping malformed item for frame {frame_id}: {line}");
+            None
+        }
+    }
+}
+
+fn log_filter_metrics(
+    format: LogFormat,
+    query: &str,
+    items: usize,
+    results: usize,
+    applied: bool,
+    elapsed: Duration,
+) -> std::io::Result<()> {
+    logging::write_entry(
+        format,
+        "filter",
+        &[
+            ("query", query.into()),
+            ("items", items.into()),
+            ("results", results.into()),
+            ("applied", applied.into()),
+            ("elapsed_micros", elapsed_micros(elapsed).into()),
+        ],
+    )
+}
+
+fn elapsed_micros(elapsed: Duration) -> u64 {
+    u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX)
+}
+
+fn summarize_filter_metrics(recent: &VecDeque<FilterMetrics>) -> MetricsSummary {
+    let samples = recent.len();
+    if samples == 0 {
+        return MetricsSummary::default();
+    }
+
+    let mut elapsed: Vec<u64> = recent.iter().map(|m| m.elapsed_micros).collect();
+    elapsed.sort_unstable();
+
+    let total_elapsed: u64 = elapsed.iter().fold(0u64, |acc, v| acc.saturating_add(*v));
+    let total_items: usize = recent.iter().map(|m| m.items).sum();
+    let total_results: usize = recent.iter().map(|m| m.results).sum();
+
+    MetricsSummary {
+        samples,
+        avg_elapsed_micros: total_elapsed / samples as u64,
+        p50_elapsed_micros: percentile(&elapsed, 50),
+        p95_elapsed_micros: percentile(&elapsed, 95),
+        max_elapsed_micros: elapsed.last().copied().unwrap_or(0),
+        avg_items: total_items / samples,
+        avg_results: total_results / samples,
+        slowest: recent.iter().max_by_key(|m| m.elapsed_micros).cloned(),
+    }
+}
+
+// Nearest-rank percentile over an ascending, non-empty slice.
+fn percentile(sorted: &[u64], pct: usize) -> u64 {
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted[rank.min(sorted.len()) - 1]
+}
+
+fn max_selectable_index(len: usize) -> Option<usize> {
+    if len == 0 {
+        None
+    } else {
+        Some(len.saturating_sub(1).min(TOP_ITEMS_LIMIT.saturating_sub(1)))
+    }
+}
+
+fn clamp_selection(frame: &mut Frame) {
+    if frame.filtered_items.is_empty() {
+        frame.selected_idx = 0;
+    } else if let Some(max_idx) = max_selectable_index(frame.filtered_items.len()) {
+        let idx = frame.selected_idx.min(max_idx);
+        frame.selected_idx =
+            skip_headers(&frame.filtered_items, idx, max_idx, true, false).unwrap_or(idx);
+    } else {
+        frame.selected_idx = 0;
+    }
+}
+
+// The first selectable index from `idx` in the direction of travel, up to
+// `max_idx`. Without `wrap` it falls back to the other direction, so the
+// selection stops at the last item instead of a trailing header. `None` when
+// every item is a header or placeholder.
+fn skip_headers(
+    items: &[Item],
+    idx: usize,
+    max_idx: usize,
+    forward: bool,
+    wrap: bool,
+) -> Option<usize> {
+    let is_item = |i: &usize| items.get(*i).is_some_and(Item::is_selectable);
+    if is_item(&idx) {
+        return Some(idx);
+    }
+    let len = max_idx + 1;
+    if wrap {
+        return (1..len)
+            .map(|step| {
+                if forward {
+                    (idx + step) % len
+                } else {
+                    (idx + len - step) % len
+                }
+            })
+            .find(is_item);
+    }
+    let after = (idx + 1..=max_idx).find(is_item);
+    let before = (0..idx).rev().find(is_item);
+    if forward {
+        after.or(before)
+    } else {
+        before.or(after)
+    }
+}
+
+impl From<&Action> for ActionType {
+    fn from(action: &Action) -> Self {
+        match action {
+            Action::Cmd { .. } => ActionType::Cmd,
+            Action::Spell { .. } => ActionType::Spell,
+            Action::OpenUrl { .. } => ActionType::OpenUrl,
+        }
+    }
+}
+
+// Spell pushes always keep the window open; the others only when asked to.
+fn action_outcome(action: &Action) -> ActionOutcome {
+    let keep_open = match action {
+        Action::Spell { .. } => true,
+        Action::Cmd { keep_open, .. } | Action::OpenUrl { keep_open, .. } => {
+            keep_open.unwrap_or(false)
+        }
+    };
+    ActionOutcome {
+        keep_open,
+        ..ActionOutcome::default()
+    }
+}
+
+fn action_name(action: &Action) -> Option<&str> {
+    match action {
+        Action::Cmd { name, .. } | Action::Spell { name, .. } | Action::OpenUrl { name, .. } => {
+            name.as_deref()
+        }
+    }
+}
+
+fn action_condition(action: &Action) -> Option<&str> {
+    match action {
+        Action::Cmd { condition, .. }
+        | Action::Spell { condition, .. }
+        | Action::OpenUrl { condition, .. } => condition.as_deref(),
+    }
+}
+
+fn action_confirm(action: &Action) -> Option<&str> {
+    match action {
+        Action::Cmd { confirm, .. }
+        | Action::Spell { confirm, .. }
+        | Action::OpenUrl { confirm, .. } => confirm.as_deref(),
+    }
+}
+
+fn parse_action_url(rendered: &str) -> Result<Url, String> {
+    let url = rendered.trim();
+    Url::parse(url).map_err(|_| format!("resolved url is invalid: {url}"))
+}
+
+fn condition_passes(condition: Option<&str>, frames: &[Frame]) -> Result<bool, String> {
+    let Some(raw) = condition else {
+        return Ok(true);
+    };
+
+    let rendered = template::resolve_template(raw, frames).map_err(|e| match e {
+        template::TemplateError::Render(err) => err,
+    })?;
+    Ok(rendered_condition_passes(&rendered))
+}
+
+// `&&` binds tighter than `||`; no parentheses.
+fn rendered_condition_passes(rendered: &str) -> bool {
+    rendered.split("||").any(|clause| {
+        clause
+            .split("&&")
+            .all(|operand| single_condition_passes(operand.trim()))
+    })
+}
+
+fn single_condition_passes(text: &str) -> bool {
+    if text.is_empty() {
+        return true;
+    }
+
+    if let Some((lhs, rhs)) = text.split_once("==") {
+        return normalize_condition_value(lhs) == normalize_condition_value(rhs);
+    }
+
+    if let Some((lhs, rhs)) = text.split_once("!=") {
+        return normalize_condition_value(lhs) != normalize_condition_value(rhs);
+    }
+
+    // Two-char operators first so `>=` isn't read as `>` with a `=`-prefixed rhs.
+    for (op, accepts) in [
+        (">=", CmpOrdering::is_ge as fn(CmpOrdering) -> bool),
+        ("<=", CmpOrdering::is_le),
+        (">", CmpOrdering::is_gt),
+        ("<", CmpOrdering::is_lt),
+    ] {
+        if let Some((lhs, rhs)) = text.split_once(op) {
+            return accepts(compare_condition_values(lhs, rhs));
+        }
+    }
+
+    match text.to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "y" => true,
+        "false" | "0" | "no" | "n" => false,
+        _ => !text.is_empty(),
+    }
+}
+
+// Numeric when both sides parse as f64, otherwise string comparison.
+fn compare_condition_values(lhs: &str, rhs: &str) -> CmpOrdering {
+    let lhs = normalize_condition_value(lhs);
+    let rhs = normalize_condition_value(rhs);
+    match (lhs.parse::<f64>(), rhs.parse::<f64>()) {
+        (Ok(l), Ok(r)) => l.partial_cmp(&r).unwrap_or(CmpOrdering::Equal),
+        _ => lhs.cmp(&rhs),
+    }
+}
+
+fn normalize_condition_value(value: &str) -> String {
+    strip_matching_quotes(value.trim()).to_string()
+}
+
+fn strip_matching_quotes(value: &str) -> &str {
+    if value.len() >= 2 {
+        let bytes = value.as_bytes();
+        if (bytes[0] == b'"' && bytes[value.len() - 1] == b'"')
+            || (bytes[0] == b'\'' && bytes[value.len() - 1] == b'\'')
+        {
+            return &value[1..value.len() - 1];
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(query: &str, items: usize, results: usize, elapsed_micros: u64) -> FilterMetrics {
+        FilterMetrics {
+            query: query.to_string(),
+            items,
+            results,
+            applied: true,
+            elapsed_micros,
+        }
+    }
+
+    fn spell(yaml: &str) -> Spell {
+        serde_yaml::from_str(yaml).expect("failed to parse spell")
+    }
+
+    #[test]
+    fn spell_entries_list_enabled_spells_except_root() {
+        let spells: HashMap<String, Spell> = [
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: '', include_spells: true}"),
+            spell("{name: Search Files, id: search_files, enabled: true, alias: sf, provider: ''}"),
+            spell("{name: Bookmarks, id: bookmarks, enabled: true, provider: ''}"),
+            spell("{name: Open With, id: open_with, enabled: false, provider: ''}"),
+        ]
+        .into_iter()
+        .map(|s| (s.id.clone(), s))
+        .collect();
+
+        let entries = spell_entries(&spells, STARTING_SPELL_ID);
+        let names: Vec<_> = entries.iter().map(|item| item.name.as_str()).collect();
+        assert_eq!(names, vec!["[S] Bookmarks", "[S] Search Files (sf)"]);
+        assert!(entries
+            .iter()
+            .all(|item| item.item_type == SPELL_ENTRY_TYPE));
+        assert_eq!(entries[1].data, "search_files");
+    }
+
+    #[test]
+    fn root_search_matches_spell_tags() {
+        let state = state_with_frame(
+            "{name: QuickSpell, id: quickspell, enabled: true, provider: '', include_spells: true,
+              search: {field: [2, 4]}}",
+            "git",
+        );
+        {
+            let mut inner = state.inner.write().unwrap();
+            for spell in [
+                spell("{name: Repositories, id: repos, enabled: true, provider: '', tags: [Git, code]}"),
+                spell("{name: Bookmarks, id: bookmarks, enabled: true, provider: ''}"),
+            ] {
+                inner.spells.insert(spell.id.clone(), spell);
+            }
+            inner.stack[0].all_items = spell_entries(&inner.spells, STARTING_SPELL_ID);
+        }
+
+        assert!(state.filter_items());
+        let inner = state.inner.read().unwrap();
+        let names: Vec<_> = inner.stack[0]
+            .filtered_items
+            .iter()
+            .map(|item| item.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["[S] Repositories"]);
+        assert_eq!(inner.stack[0].filtered_items[0].extra, vec!["git code"]);
+    }
+
+    #[test]
+    fn spell_entries_require_root_opt_in() {
+        let state = AppState::new();
+        let mut inner = state.inner.write().unwrap();
+        let root = spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}");
+        let other = spell("{name: Bookmarks, id: bookmarks, enabled: true, provider: ''}");
+        inner.spells.insert(root.id.clone(), root.clone());
+        inner.spells.insert(other.id.clone(), other.clone());
+
+        assert!(spell_entries_for(&inner, &root).is_empty());
+        assert!(spell_entries_for(&inner, &other).is_empty());
+
+        let mut root = root;
+        root.include_spells = Some(true);
+        assert_eq!(spell_entries_for(&inner, &root).len(), 1);
+    }
+
+    #[test]
+    fn start_spell_setting_picks_the_first_frame() {
+        let spells: HashMap<String, Spell> = [
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: Menu, id: menu, enabled: true, provider: ''}"),
+        ]
+        .into_iter()
+        .map(|s| (s.id.clone(), s))
+        .collect();
+        let start_with = |start_spell: &str| {
+            let state = AppState::new();
+            state.set_settings(Settings {
+                start_spell: Some(start_spell.to_string()),
+                ..Settings::default()
+            });
+            state
+                .begin_loading_with_spells(spells.clone(), Path::new("spells"))
+                .unwrap();
+            let inner = state.inner.read().unwrap();
+            inner.stack[0].spell_id.clone()
+        };
+
+        assert_eq!(start_with("menu"), "menu");
+        assert_eq!(start_with("missing"), STARTING_SPELL_ID);
+    }
+
+    #[test]
+    fn missing_start_spell_is_only_reported_for_unknown_ids() {
+        let state = state_with_spells(vec![spell(
+            "{name: Menu, id: menu, enabled: true, provider: ''}",
+        )]);
+        let missing = |start_spell: Option<&str>| {
+            let mut inner = state.inner.write().unwrap();
+            inner.settings.start_spell = start_spell.map(str::to_string);
+            missing_start_spell(&inner).map(str::to_string)
+        };
+
+        assert_eq!(missing(None), None);
+        assert_eq!(missing(Some("menu")), None);
+        assert_eq!(missing(Some("gone")), Some("gone".to_string()));
+    }
+
+    fn state_with_spells(spells: Vec<Spell>) -> AppState {
+        let state = AppState::new();
+        {
+            let mut inner = state.inner.write().unwrap();
+            inner.spells = spells.into_iter().map(|s| (s.id.clone(), s)).collect();
+            inner.status = AppStatus::Ready;
+            let root = new_frame(&mut inner, STARTING_SPELL_ID.to_string());
+            inner.stack.push(root);
+        }
+        state
+    }
+
+    #[test]
+    fn alias_prefix_pushes_spell_with_residual_query() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: GitHub, id: github, enabled: true, alias: GH, provider: ''}"),
+        ]);
+
+        assert!(matches!(
+            state.set_query("gh rust".to_string()),
+            QueryResult::PushedSpell
+        ));
+
+        let inner = state.inner.read().unwrap();
+        assert_eq!(inner.stack.len(), 2);
+        let frame = inner.stack.last().unwrap();
+        assert_eq!(frame.spell_id, "github");
+        assert_eq!(frame.query, "rust");
+        assert_eq!(inner.stack[0].query, "");
+    }
+
+    #[test]
+    fn alias_only_triggers_from_starting_spell() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: GitHub, id: github, enabled: true, alias: gh, provider: ''}"),
+        ]);
+
+        assert!(matches!(
+            state.set_query("ghost town".to_string()),
+            QueryResult::Updated
+        ));
+        assert!(matches!(
+            state.set_query("gh rust".to_string()),
+            QueryResult::PushedSpell
+        ));
+        assert!(matches!(
+            state.set_query("gh rust".to_string()),
+            QueryResult::Updated
+        ));
+        assert_eq!(state.inner.read().unwrap().stack.len(), 2);
+    }
+
+    #[test]
+    fn disabled_spells_are_not_opened_or_counted() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: GitHub, id: github, enabled: false, alias: gh, provider: ''}"),
+        ]);
+
+        assert!(matches!(
+            state.set_query("gh rust".to_string()),
+            QueryResult::Updated
+        ));
+        assert_eq!(state.snapshot().no_of_spells, 1);
+
+        let inner = state.inner.read().unwrap();
+        assert_eq!(
+            ensure_spell_enabled(&inner, "github"),
+            Err("spell github is disabled".to_string())
+        );
+        assert_eq!(
+            ensure_spell_enabled(&inner, "missing"),
+            Err("spell missing not found".to_string())
+        );
+        assert!(ensure_spell_enabled(&inner, "quickspell").is_ok());
+    }
+
+    #[test]
+    fn escape_clears_the_query_before_popping_by_default() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: GitHub, id: github, enabled: true, alias: gh, provider: ''}"),
+        ]);
+        state.set_query("gh rust".to_string());
+
+        assert!(matches!(state.handle_escape(), EscapeResult::ClearedQuery));
+        assert!(matches!(state.handle_escape(), EscapeResult::PoppedFrame));
+        assert!(matches!(state.handle_escape(), EscapeResult::Noop));
+    }
+
+    #[test]
+    fn escape_pops_first_keeps_going_back_with_a_query() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell(
+                "{name: GitHub, id: github, enabled: true, alias: gh, provider: '', escape_pops_first: true}",
+            ),
+        ]);
+        state.set_query("gh rust".to_string());
+
+        assert!(matches!(state.handle_escape(), EscapeResult::PoppedFrame));
+        assert_eq!(state.inner.read().unwrap().stack.len(), 1);
+        state.set_query("git".to_string());
+        assert!(matches!(state.handle_escape(), EscapeResult::ClearedQuery));
+    }
+
+    #[test]
+    fn pop_frame_ignores_a_non_empty_query() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: GitHub, id: github, enabled: true, alias: gh, provider: ''}"),
+        ]);
+        state.set_query("gh rust".to_string());
+
+        assert!(state.pop_frame());
+        let inner = state.inner.read().unwrap();
+        assert_eq!(inner.stack.len(), 1);
+        assert_eq!(inner.status, AppStatus::Ready);
+        drop(inner);
+        assert!(!state.pop_frame());
+    }
+
+    #[test]
+    fn list_spells_sorts_by_name_and_hides_providers() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: 'apps.sh'}"),
+            spell(
+                "{name: GitHub, id: github, enabled: false, alias: gh, tags: [git], provider: 'gh.sh $TOKEN'}",
+            ),
+            spell("{name: Files, id: files, enabled: true, is_streaming: true, provider: 'fd .'}"),
+        ]);
+
+        let spells = state.list_spells(false).unwrap();
+        let summary: Vec<_> = spells
+            .iter()
+            .map(|s| {
+                (
+                    s.id.as_str(),
+                    s.name.as_str(),
+                    s.alias.as_deref(),
+                    s.enabled,
+                    s.is_streaming,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("files", "Files", None, true, true),
+                ("github", "GitHub", Some("gh"), false, false),
+                ("quickspell", "QuickSpell", None, true, false),
+            ]
+        );
+        assert!(spells.iter().all(|s| s.provider.is_none()));
+
+        let with_providers = state.list_spells(true).unwrap();
+        assert_eq!(with_providers[1].provider.as_deref(), Some("gh.sh $TOKEN"));
+        assert_eq!(with_providers[1].tags, vec!["git"]);
+    }
+
+    #[test]
+    fn push_frame_opens_known_enabled_spells() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: Bookmarks, id: bookmarks, enabled: true, provider: ''}"),
+            spell("{name: Old, id: old, enabled: false, provider: ''}"),
+        ]);
+
+        state.push_frame("bookmarks").unwrap();
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.spell_names, vec!["QuickSpell", "Bookmarks"]);
+        assert_eq!(snapshot.status, AppStatus::Loading);
+
+        assert_eq!(
+            state.push_frame("old").unwrap_err(),
+            "spell old is disabled"
+        );
+        assert_eq!(
+            state.push_frame("nope").unwrap_err(),
+            "spell nope not found"
+        );
+        assert_eq!(state.inner.read().unwrap().stack.len(), 2);
+    }
+
+    #[test]
+    fn recent_spells_source_lists_opened_spells_most_recent_first() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, source: recent_spells, include_spells: true}"),
+            spell("{name: Apps, id: apps, enabled: true, provider: ''}"),
+            spell("{name: Files, id: files, enabled: true, provider: ''}"),
+            spell("{name: Notes, id: notes, enabled: true, provider: ''}"),
+        ]);
+        for id in ["apps", "files", "apps"] {
+            state.push_frame(id).unwrap();
+            state.pop_frame();
+        }
+        assert_eq!(
+            state.inner.read().unwrap().usage.recent_spells(),
+            ["apps", "files"]
+        );
+
+        block_on(state.finish_loading_with_items(&env::temp_dir())).unwrap();
+        let inner = state.inner.read().unwrap();
+        let listed: Vec<_> = inner.stack[0]
+            .all_items
+            .iter()
+            .map(|item| item.data.as_str())
+            .collect();
+        assert_eq!(listed, vec!["apps", "files", "notes"]);
+    }
+
+    #[test]
+    fn opened_spells_are_saved_to_the_usage_file() {
+        let path = env::temp_dir().join(format!("quickspell-usage-{}.json", std::process::id()));
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: Apps, id: apps, enabled: true, provider: ''}"),
+        ]);
+        state.inner.write().unwrap().usage_path = Some(path.clone());
+
+        state.push_frame("apps").unwrap();
+        assert_eq!(UsageStore::load(&path).recent_spells(), ["apps"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn push_frame_stops_at_max_stack_depth() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: Loop, id: loop, enabled: true, provider: ''}"),
+        ]);
+        state.inner.write().unwrap().settings.max_stack_depth = Some(3);
+
+        state.push_frame("loop").unwrap();
+        state.push_frame("loop").unwrap();
+        assert_eq!(
+            state.push_frame("loop").unwrap_err(),
+            "maximum spell depth reached"
+        );
+        assert_eq!(state.inner.read().unwrap().stack.len(), 3);
+    }
+
+    #[test]
+    fn snapshot_summarizes_each_frame() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: GitHub, id: github, enabled: true, alias: gh, provider: ''}"),
+        ]);
+        state.set_query("gh rust".to_string());
+        {
+            let mut inner = state.inner.write().unwrap();
+            inner.stack[0].query = "git".to_string();
+            inner.stack[0].filtered_items = vec![Item::from_line("APP	GitHub	gh").unwrap()];
+        }
+
+        let snapshot = state.snapshot();
+        let summary: Vec<_> = snapshot
+            .frames
+            .iter()
+            .map(|f| {
+                (
+                    f.spell_id.as_str(),
+                    f.spell_name.as_str(),
+                    f.query.as_str(),
+                    f.item_count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("quickspell", "QuickSpell", "git", 1),
+                ("github", "GitHub", "rust", 0),
+            ]
+        );
+        assert_eq!(snapshot.spell_names, vec!["QuickSpell", "GitHub"]);
+        let inner = state.inner.read().unwrap();
+        let ids: Vec<_> = snapshot.frames.iter().map(|f| f.id).collect();
+        assert_eq!(ids, inner.stack.iter().map(|f| f.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn collapse_to_root_leaves_only_the_root_frame() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: Files, id: files, enabled: true, provider: ''}"),
+        ]);
+        {
+            let mut inner = state.inner.write().unwrap();
+            inner.stack[0].query = "fi".to_string();
+            for _ in 0..2 {
+                let frame = new_frame(&mut inner, "files".to_string());
+                inner.stack.push(frame);
+            }
+            assert_eq!(inner.stack.len(), 3);
+        }
+
+        state.collapse_to_root().unwrap();
+        let inner = state.inner.read().unwrap();
+        assert_eq!(inner.stack.len(), 1);
+        assert_eq!(inner.stack[0].spell_id, STARTING_SPELL_ID);
+        assert_eq!(inner.stack[0].query, "");
+        assert_eq!(inner.status, AppStatus::Ready);
+    }
+
+    fn format_of(format: ItemFormat, delimiter: &str) -> LineFormat {
+        LineFormat {
+            format,
+            delimiter: delimiter.to_string(),
+            unescape: false,
+            lenient: false,
+            encoding: None,
+        }
+    }
+
+    #[test]
+    fn parses_json_item_lines() {
+        let item = parse_item_line(
+            r#"{"type":"FILE","name":"notes\ttabbed","data":"/tmp/a\tb","size":12}"#,
+            &format_of(ItemFormat::Json, "\t"),
+            "files",
+        )
+        .expect("json line should parse");
+        assert_eq!(item.item_type, "FILE");
+        assert_eq!(item.name, "notes\ttabbed");
+        assert_eq!(item.data, "/tmp/a\tb");
+    }
+
+    #[test]
+    fn skips_malformed_json_item_lines() {
+        let json = format_of(ItemFormat::Json, "\t");
+        assert!(parse_item_line("{\"type\":\"FILE\"", &json, "files").is_none());
+        assert!(parse_item_line("{\"type\":\"FILE\"}", &json, "files").is_none());
+        assert!(parse_item_line("FILE\tnotes\t/tmp/notes", &json, "files").is_none());
+
+        let tsv = format_of(ItemFormat::Tsv, "\t");
+        assert!(parse_item_line("FILE\tnotes\t/tmp/notes", &tsv, "files").is_some());
+    }
+
+    #[test]
+    fn splits_on_configured_delimiter() {
+        let comma = line_format(&spell(
+            "{name: Csv, id: csv, enabled: true, provider: '', delimiter: ','}",
+        ));
+        let item = parse_item_line("FILE,notes,/tmp/notes", &comma, "csv").unwrap();
+        assert_eq!(
+            (
+                item.item_type.as_str(),
+                item.name.as_str(),
+                item.data.as_str()
+            ),
+            ("FILE", "notes", "/tmp/notes")
+        );
+        assert!(parse_item_line("FILE\tnotes\t/tmp/notes", &comma, "csv").is_none());
+    }
+
+    #[test]
+    fn keeps_columns_after_data_as_extra() {
+        let tsv = format_of(ItemFormat::Tsv, "\t");
+        let item = parse_item_line("FILE\tnotes\t/tmp/notes\t2 KB\tmd", &tsv, "files").unwrap();
+        assert_eq!(item.data, "/tmp/notes");
+        assert_eq!(item.extra, vec!["2 KB", "md"]);
+        assert_eq!(item.field(4), "md");
+
+        let json = format_of(ItemFormat::Json, "\t");
+        let item = parse_item_line(
+            r#"{"type":"FILE","name":"notes","data":"/tmp/notes","extra":["2 KB"]}"#,
+            &json,
+            "files",
+        )
+        .unwrap();
+        assert_eq!(item.extra, vec!["2 KB"]);
+    }
+
+    #[test]
+    fn lenient_parsing_keeps_lines_with_missing_fields() {
+        let strict = format_of(ItemFormat::Tsv, "\t");
+        let lenient = line_format(&spell(
+            "{name: Apps, id: apps, enabled: true, provider: '', lenient_parsing: true}",
+        ));
+        let fields = |item: Item| (item.item_type, item.name, item.data);
+        let owned = |t: &str, n: &str, d: &str| (t.to_string(), n.to_string(), d.to_string());
+
+        assert!(parse_item_line("APP", &strict, "apps").is_none());
+        assert!(parse_item_line("APP\tSafari", &strict, "apps").is_none());
+        assert_eq!(
+            fields(
+                parse_item_line("APP\tSafari\t/Applications/Safari.app", &strict, "apps").unwrap()
+            ),
+            owned("APP", "Safari", "/Applications/Safari.app")
+        );
+
+        assert_eq!(
+            fields(parse_item_line("APP", &lenient, "apps").unwrap()),
+            owned("APP", "", "")
+        );
+        assert_eq!(
+            fields(parse_item_line("APP\tSafari", &lenient, "apps").unwrap()),
+            owned("APP", "Safari", "")
+        );
+        assert_eq!(
+            fields(
+                parse_item_line("APP\tSafari\t/Applications/Safari.app", &lenient, "apps").unwrap()
+            ),
+            owned("APP", "Safari", "/Applications/Safari.app")
+        );
+        assert!(parse_item_line("", &lenient, "apps").is_none());
+        assert!(parse_item_line("  ", &lenient, "apps").is_none());
+    }
+
+    #[test]
+    fn splits_on_nul_delimiter() {
+        for yaml in [
+            r#"{name: Nul, id: nul, enabled: true, provider: '', delimiter: "\0"}"#,
+            r#"{name: Nul, id: nul, enabled: true, provider: '', delimiter: '\0'}"#,
+        ] {
+            let nul = line_format(&spell(yaml));
+            assert_eq!(nul.delimiter, "\0");
+            let item = parse_item_line("FILE\0my, notes\0/tmp/my\tnotes", &nul, "nul").unwrap();
+            assert_eq!(item.name, "my, notes");
+            assert_eq!(item.data, "/tmp/my\tnotes");
+        }
+    }
+
+    #[test]
+    fn unescape_items_decodes_each_sequence() {
+        let unescaping = line_format(&spell(
+            "{name: Notes, id: notes, enabled: true, provider: '', unescape_items: true}",
+        ));
+        let parse = |line: &str| parse_item_line(line, &unescaping, "notes").unwrap();
+
+        assert_eq!(parse("NOTE\tline 1\\nline 2\t/a").name, "line 1\nline 2");
+        assert_eq!(parse("NOTE\tx\tcol\\tcol").data, "col\tcol");
+        assert_eq!(parse("NOTE\tx\tC:\\\\Users").data, "C:\\Users");
+        assert_eq!(
+            parse("NOTE\tx\tkeep \\d and trailing \\").data,
+            "keep \\d and trailing \\"
+        );
+    }
+
+    #[test]
+    fn items_keep_backslashes_by_default() {
+        let tsv = format_of(ItemFormat::Tsv, "\t");
+        let item = parse_item_line("NOTE\tline 1\\nline 2\tC:\\\\Users", &tsv, "notes").unwrap();
+        assert_eq!(item.name, "line 1\\nline 2");
+        assert_eq!(item.data, "C:\\\\Users");
+    }
+
+    #[test]
+    fn decodes_provider_output_in_the_spell_encoding() {
+        let latin1 = line_format(&spell(
+            "{name: Files, id: files, enabled: true, provider: '', encoding: latin1}",
+        ));
+        let bytes = b"FILE\tcaf\xe9\t/tmp/caf\xe9";
+        assert_eq!(
+            decode_output(bytes, &latin1),
+            "FILE\tcaf\u{e9}\t/tmp/caf\u{e9}"
+        );
+
+        let default = format_of(ItemFormat::Tsv, "\t");
+        assert_eq!(
+            decode_output(bytes, &default),
+            "FILE\tcaf\u{fffd}\t/tmp/caf\u{fffd}"
+        );
+    }
+
+    #[test]
+    fn filtering_a_large_list_keeps_only_the_top_matches() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '', search: {field: 2}}",
+            "file-49999",
+        );
+        let items: Vec<Item> = (0..50_000)
+            .map(|i| Item::from_line(&format!("FILE\tfile-{i}.txt\t/tmp/file-{i}.txt")).unwrap())
+            .collect();
+        state.inner.write().unwrap().stack[0].all_items = items;
+
+        assert!(state.filter_items());
+        {
+            let inner = state.inner.read().unwrap();
+            let frame = &inner.stack[0];
+            assert_eq!(frame.all_items.len(), 50_000);
+            assert!(frame.filtered_items.len() <= TOP_ITEMS_LIMIT);
+            assert_eq!(frame.filtered_items[0].name, "file-49999.txt");
+        }
+
+        state.set_query("".to_string());
+        assert!(state.filter_items());
+        let inner = state.inner.read().unwrap();
+        let names: Vec<_> = inner.stack[0].filtered_items[..2]
+            .iter()
+            .map(|item| item.name.as_str())
+            .collect();
+        assert_eq!(inner.stack[0].filtered_items.len(), TOP_ITEMS_LIMIT);
+        assert_eq!(names, vec!["file-0.txt", "file-1.txt"]);
+    }
+
+    #[test]
+    fn switching_scopes_changes_the_result_set() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '', search: {field: 2},
+              scopes: [{name: All}, {name: Folders, filter: '{{context.files.selection.type}} == DIR'},
+                       {name: Files, filter: '{{context.files.selection.type}} != DIR'}]}",
+            "",
+        );
+        state.inner.write().unwrap().stack[0].all_items = vec![
+            Item::from_line("DIR\tsrc\t/repo/src").unwrap(),
+            Item::from_line("FILE\tsrc.zip\t/repo/src.zip").unwrap(),
+            Item::from_line("DIR\tdocs\t/repo/docs").unwrap(),
+        ];
+        let names = |state: &AppState| -> Vec<String> {
+            let inner = state.inner.read().unwrap();
+            inner.stack[0]
+                .filtered_items
+                .iter()
+                .map(|item| item.name.clone())
+                .collect()
+        };
+
+        assert!(state.set_scope(1).unwrap());
+        assert_eq!(names(&state), vec!["src", "docs"]);
+        assert_eq!(state.snapshot().active_scope, Some(1));
+
+        state.set_query("src".to_string());
+        state.filter_items();
+        assert_eq!(names(&state), vec!["src"]);
+
+        assert!(state.set_scope(2).unwrap());
+        assert_eq!(names(&state), vec!["src.zip"]);
+        assert!(!state.set_scope(2).unwrap());
+        assert!(state.set_scope(3).is_err());
+        assert_eq!(state.snapshot().scopes, vec!["All", "Folders", "Files"]);
+    }
+
+    #[test]
+    fn hidden_search_key_matches_without_highlighting_the_name() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '', search: {field: 4}}",
+            "report",
+        );
+        state.inner.write().unwrap().stack[0].all_items = vec![
+            Item::from_line("FILE\t[F] q3.pdf\t/tmp/q3.pdf\tquarterly report").unwrap(),
+            Item::from_line("FILE\t[F] report.txt\t/tmp/report.txt").unwrap(),
+            Item::from_line("FILE\t[F] notes.txt\t/tmp/notes.txt\tmeeting notes").unwrap(),
+        ];
+
+        assert!(state.filter_items());
+        let inner = state.inner.read().unwrap();
+        let matched: Vec<_> = inner.stack[0]
+            .filtered_items
+            .iter()
+            .map(|item| (item.name.as_str(), item.match_ranges.is_empty()))
+            .collect();
+        assert_eq!(
+            matched,
+            vec![("[F] report.txt", false), ("[F] q3.pdf", true)]
+        );
+    }
+
+    #[test]
+    fn clear_query_shows_all_items_without_filtering() {
+        let state = state_with_frame(
+            "{name: Apps, id: apps, enabled: true, provider: '', search: {field: 2}}",
+            "saf",
+        );
+        state.inner.write().unwrap().stack[0].all_items = vec![
+            Item::from_line("APP\tSafari\t/Applications/Safari.app").unwrap(),
+            Item::from_line("APP\tMail\t/Applications/Mail.app").unwrap(),
+        ];
+        assert!(state.filter_items());
+        let filters_run = state.metrics_summary().samples;
+
+        assert!(state.clear_query());
+        {
+            let inner = state.inner.read().unwrap();
+            let frame = &inner.stack[0];
+            assert_eq!(frame.query, "");
+            assert_eq!(frame.filtered_items, frame.all_items);
+            assert_eq!(frame.selected_idx, 0);
+            assert!(!frame.is_filtering);
+        }
+        assert_eq!(state.metrics_summary().samples, filters_run);
+        assert!(!state.clear_query());
+    }
+
+    #[test]
+    fn delimiter_defaults_to_tab() {
+        let tsv = line_format(&spell("{name: T, id: t, enabled: true, provider: ''}"));
+        assert_eq!(tsv.delimiter, "\t");
+    }
+
+    #[test]
+    fn rapid_queries_apply_a_single_filter() {
+        let state = state_with_spells(vec![spell(
+            "{name: QuickSpell, id: quickspell, enabled: true, provider: ''}",
+        )]);
+
+        let handles: Vec<_> = ["f", "fi", "fir"]
+            .into_iter()
+            .map(|query| {
+                state.set_query(query.to_string());
+                let generation = state.next_filter_generation();
+                let state = state.clone();
+                std::thread::spawn(move || state.filter_items_debounced(generation))
+            })
+            .collect();
+
+        let applied: Vec<bool> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(applied, vec![false, false, true]);
+
+        let inner = state.inner.read().unwrap();
+        assert_eq!(inner.recent_filters.len(), 1);
+        assert_eq!(inner.recent_filters[0].query, "fir");
+        assert!(!inner.stack[0].is_filtering);
+    }
+
+    #[test]
+    fn immediate_query_filters_before_returning() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '', search: {field: 2}}",
+            "",
+        );
+        {
+            let mut inner = state.inner.write().unwrap();
+            let items: Vec<Item> = ["notes.txt", "todo.txt", "budget.xlsx"]
+                .iter()
+                .map(|name| Item::from_line(&format!("FILE\t{name}\t/{name}")).unwrap())
+                .collect();
+            inner.stack[0].all_items = items.clone();
+            inner.stack[0].filtered_items = items;
+        }
+        let pending = state.next_filter_generation();
+
+        assert!(matches!(
+            state.set_query_immediate("budget".to_string()),
+            QueryResult::Updated
+        ));
+        let names: Vec<String> = state
+            .snapshot()
+            .top_items
+            .into_iter()
+            .map(|item| item.name)
+            .collect();
+        assert_eq!(names, vec!["budget.xlsx"]);
+        assert!(!state.filter_items_debounced(pending));
+    }
+
+    #[test]
+    fn action_urls_must_be_absolute() {
+        let url = parse_action_url(" https://github.com/search?q=rust ").unwrap();
+        assert_eq!(url.as_str(), "https://github.com/search?q=rust");
+
+        assert_eq!(
+            parse_action_url("github.com/search").unwrap_err(),
+            "resolved url is invalid: github.com/search"
+        );
+        assert_eq!(
+            parse_action_url("").unwrap_err(),
+            "resolved url is invalid: "
+        );
+    }
+
+    #[test]
+    fn open_url_action_parses_from_yaml() {
+        let action: Action = serde_yaml::from_str(
+            "{type: OPEN_URL, name: Open, url: '{{context.bookmarks.selection.data}}'}",
+        )
+        .unwrap();
+        assert!(matches!(action, Action::OpenUrl { ref url, .. } if url.contains("bookmarks")));
+        assert!(matches!(ActionType::from(&action), ActionType::OpenUrl));
+    }
+
+    fn frame_with(spell_id: &str, lines: &[&str], selected_idx: usize, query: &str) -> Frame {
+        let items: Vec<Item> = lines.iter().filter_map(|l| Item::from_line(l)).collect();
+        Frame {
+            id: 0,
+            spell_id: spell_id.to_string(),
+            query: query.to_string(),
+            all_items: items.clone(),
+            filtered_items: items,
+            is_filtering: false,
+            is_loading_more: false,
+            selected_idx,
+            selected_set: HashSet::new(),
+            scope_idx: 0,
+            load_cancelled: tokio::sync::watch::Sender::new(false),
+            reload_error: None,
+            type_counts: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn provider_env_exposes_parent_selection() {
+        let stack = vec![
+            frame_with(
+                "folders",
+                &["DIR\tdocs\t/Users/me/docs", "DIR\tsrc\t/Users/me/src"],
+                1,
+                "",
+            ),
+            frame_with("files_in_folder", &[], 0, "main"),
+        ];
+
+        let env: HashMap<_, _> = provider_env(&stack).into_iter().collect();
+        assert_eq!(env["QUICKSPELL_QUERY"], "main");
+        assert_eq!(env["QUICKSPELL_PARENT_SPELL_ID"], "folders");
+        assert_eq!(env["QUICKSPELL_PARENT_SELECTION_DATA"], "/Users/me/src");
+    }
+
+    #[test]
+    fn provider_env_is_empty_without_parent() {
+        let stack = vec![frame_with("quickspell", &[], 0, "")];
+
+        let env: HashMap<_, _> = provider_env(&stack).into_iter().collect();
+        assert_eq!(env.len(), 3);
+        assert!(env.values().all(|value| value.is_empty()));
+
+        let stack = vec![
+            frame_with("folders", &[], 0, ""),
+            frame_with("files", &[], 0, ""),
+        ];
+        let env: HashMap<_, _> = provider_env(&stack).into_iter().collect();
+        assert_eq!(env["QUICKSPELL_PARENT_SPELL_ID"], "folders");
+        assert_eq!(env["QUICKSPELL_PARENT_SELECTION_DATA"], "");
+    }
+
+    #[test]
+    fn provider_command_exposes_resource_dirs() {
+        let state = state_with_frame("{name: Z, id: z, enabled: true, provider: 'echo'}", "");
+        let job = state.provider_job().unwrap().unwrap();
+        let resources_dir = Path::new("/tmp/quickspell-resources");
+
+        let command = provider_command(&job, resources_dir);
+        let env: HashMap<_, _> = command
+            .get_envs()
+            .filter_map(|(key, value)| Some((key.to_str()?, value?.to_str()?)))
+            .collect();
+        assert_eq!(env["QUICKSPELL_RESOURCES_DIR"], "/tmp/quickspell-resources");
+        assert_eq!(
+            env["QUICKSPELL_PROVIDERS_DIR"],
+            "/tmp/quickspell-resources/providers"
+        );
+        assert_eq!(env["QUICKSPELL_QUERY"], "");
+    }
+
+    fn state_with_frame(spell_yaml: &str, query: &str) -> AppState {
+        let spell = spell(spell_yaml);
+        let state = AppState::new();
+        {
+            let mut inner = state.inner.write().unwrap();
+            let mut frame = new_frame(&mut inner, spell.id.clone());
+            frame.query = query.to_string();
+            inner.stack.push(frame);
+            inner.spells.insert(spell.id.clone(), spell);
+            inner.status = AppStatus::Ready;
+        }
+        state
+    }
+
+    #[test]
+    fn empty_message_shows_only_without_results() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '', empty_message: 'No results for {{query}}'}",
+            "zzz",
+        );
+        assert_eq!(
+            state.snapshot().empty_message.as_deref(),
+            Some("No results for zzz")
+        );
+
+        state.inner.write().unwrap().status = AppStatus::Loading;
+        assert_eq!(state.snapshot().empty_message, None);
+
+        {
+            let mut inner = state.inner.write().unwrap();
+            inner.status = AppStatus::Ready;
+            inner.stack[0].filtered_items = vec![Item::from_line("FILE	zzz.txt	/zzz").unwrap()];
+        }
+        assert_eq!(state.snapshot().empty_message, None);
+    }
+
+    #[test]
+    fn dynamic_spell_reruns_provider_with_query() {
+        let state = state_with_frame(
+            r#"{name: Web, id: web, enabled: true, is_dynamic: true,
+                provider: 'printf "URL\tSearch %s\thttps://example.com/?q=%s\n" "$QUICKSPELL_QUERY" "$QUICKSPELL_QUERY"'}"#,
+            "rust",
+        );
+
+        let generation = state.next_filter_generation();
+        assert!(block_on(
+            state.reload_dynamic_items(generation, &env::temp_dir())
+        ));
+
+        let inner = state.inner.read().unwrap();
+        let frame = inner.stack.last().unwrap();
+        assert_eq!(frame.filtered_items.len(), 1);
+        assert_eq!(frame.filtered_items[0].name, "Search rust");
+        assert_eq!(frame.all_items, frame.filtered_items);
+    }
+
+    #[test]
+    fn dynamic_reloads_go_through_the_transform() {
+        let state = state_with_frame(
+            r#"{name: Web, id: web, enabled: true, is_dynamic: true,
+                provider: 'printf "%s\n" "$QUICKSPELL_QUERY"',
+                transform: 'while read -r q; do printf "URL\tSearch %s\thttps://example.com\n" "$q"; done'}"#,
+            "rust",
+        );
+
+        assert!(block_on(state.reload_dynamic_items(
+            state.next_filter_generation(),
+            &env::temp_dir()
+        )));
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.reload_error, None);
+        let names: Vec<_> = snapshot.top_items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["Search rust"]);
+    }
+
+    #[test]
+    fn dynamic_reload_errors_reach_the_snapshot_until_a_run_succeeds() {
+        let state = state_with_frame(
+            r#"{name: Web, id: web, enabled: true, is_dynamic: true,
+                provider: '[ "$QUICKSPELL_QUERY" != bad ] || exit 3; printf "URL\tok\thttps://example.com\n"'}"#,
+            "bad",
+        );
+
+        assert!(block_on(state.reload_dynamic_items(
+            state.next_filter_generation(),
+            &env::temp_dir()
+        )));
+        let snapshot = state.snapshot();
+        assert!(snapshot
+            .reload_error
+            .as_deref()
+            .is_some_and(|err| err.contains("exited with status")));
+        assert!(snapshot.top_items.is_empty());
+
+        state.inner.write().unwrap().stack[0].query = "good".to_string();
+        assert!(block_on(state.reload_dynamic_items(
+            state.next_filter_generation(),
+            &env::temp_dir()
+        )));
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.reload_error, None);
+        assert_eq!(snapshot.top_items.len(), 1);
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn slow_providers_load_concurrently_on_one_thread() {
+        // Each provider blocks on a FIFO until the next one opens it, so the
+        // loads only finish when they run at the same time.
+        let dir = env::temp_dir().join(format!("quickspell-fifos-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for fifo in ["a", "b"] {
+            let made = Command::new("mkfifo").arg(dir.join(fifo)).status().unwrap();
+            assert!(made.success());
+        }
+        let states: Vec<AppState> = ["cat a >/dev/null", "echo > a; cat b >/dev/null", "echo > b"]
+            .iter()
+            .map(|wait| {
+                state_with_frame(
+                    &format!(
+                        r#"{{name: Slow, id: slow, enabled: true, cwd: '{}',
+                            provider: '{wait}; printf "A\tx\ty\n"'}}"#,
+                        dir.display()
+                    ),
+                    "",
+                )
+            })
+            .collect();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let loads: Vec<_> = states
+            .iter()
+            .cloned()
+            .map(|state| {
+                runtime
+                    .spawn(async move { state.finish_loading_with_items(&env::temp_dir()).await })
+            })
+            .collect();
+        // Only guards against a hang if the loads run one after another.
+        let finished = runtime.block_on(async {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                for load in loads {
+                    assert!(load.await.unwrap().is_ok());
+                }
+            })
+            .await
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(finished.is_ok(), "providers did not run concurrently");
+        for state in &states {
+            let inner = state.inner.read().unwrap();
+            assert_eq!(inner.stack[0].all_items.len(), 1);
+            assert_eq!(inner.status, AppStatus::Ready);
+        }
+    }
+
+    #[test]
+    fn loading_more_flag_clears_when_stream_completes() {
+        let state = state_with_frame(
+            r#"{name: Stream, id: stream, enabled: true, is_streaming: true,
+                provider: 'printf "A\tx\ty\n"; sleep 0.6; printf "A\tz\tw\n"'}"#,
+            "",
+        );
+        let seen_loading = std::cell::Cell::new(false);
+
+        let streamed = block_on(state.stream_items(&env::temp_dir(), None, |_, _| {
+            seen_loading.set(state.snapshot().is_loading_more);
+        }))
+        .unwrap();
+
+        assert!(streamed);
+        assert!(seen_loading.get());
+        let snapshot = state.snapshot();
+        assert!(!snapshot.is_loading_more);
+        assert_eq!(snapshot.total_items, 2);
+        assert_eq!(snapshot.status, AppStatus::Ready);
+    }
+
+    #[test]
+    fn cancel_loading_stops_a_stream_and_keeps_its_items() {
+        let state = state_with_frame(
+            r#"{name: Stream, id: stream, enabled: true, is_streaming: true,
+                provider: 'sleep 0.6; printf "A\tx\ty\n"; sleep 5; printf "A\tz\tw\n"'}"#,
+            "",
+        );
+        state.inner.write().unwrap().status = AppStatus::Loading;
+        assert!(!state_with_items(1).cancel_loading());
+
+        // Cancels as soon as the first batch is in; the second item would
+        // only arrive if the provider were left running.
+        let (first_batch, first_batch_in) = tokio::sync::oneshot::channel();
+        let canceller = state.clone();
+        let cancel = std::thread::spawn(move || {
+            first_batch_in.blocking_recv().unwrap();
+            canceller.cancel_loading()
+        });
+        let streamed =
+            block_on(state.stream_items(&env::temp_dir(), Some(first_batch), |_, _| {})).unwrap();
+
+        assert!(cancel.join().unwrap());
+        assert!(streamed);
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.status, AppStatus::Ready);
+        assert!(!snapshot.is_loading_more);
+        assert_eq!(snapshot.total_items, 1);
+        assert!(!state.cancel_loading());
+    }
+
+    #[test]
+    fn failing_provider_is_retried_until_it_succeeds() {
+        let marker = env::temp_dir().join(format!("quickspell-retry-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let provider = format!(
+            r#"if [ -f "{0}" ]; then printf "A\tok\t1\n"; else touch "{0}"; exit 1; fi"#,
+            marker.display()
+        );
+        let yaml = |extra: &str| {
+            format!("{{name: Flaky, id: flaky, enabled: true, provider: '{provider}'{extra}}}")
+        };
+
+        let state = state_with_frame(&yaml(""), "");
+        assert!(block_on(state.finish_loading_with_items(&env::temp_dir())).is_err());
+        let _ = std::fs::remove_file(&marker);
+
+        let state = state_with_frame(&yaml(", retries: 2, retry_delay_ms: 10"), "");
+        block_on(state.finish_loading_with_items(&env::temp_dir())).unwrap();
+        let _ = std::fs::remove_file(&marker);
+        let inner = state.inner.read().unwrap();
+        assert_eq!(inner.status, AppStatus::Ready);
+        assert_eq!(inner.stack[0].all_items.len(), 1);
+    }
+
+    #[test]
+    fn transform_reads_the_provider_output() {
+        let yaml = |transform: &str, streaming: bool| {
+            format!(
+                r#"{{name: Upper, id: upper, enabled: true, is_streaming: {streaming},
+                    provider: 'printf "a\tone\t1\na\ttwo\t2\n"', transform: '{transform}'}}"#
+            )
+        };
+        let names = |state: &AppState| -> Vec<String> {
+            let inner = state.inner.read().unwrap();
+            inner.stack[0]
+                .all_items
+                .iter()
+                .map(|i| i.name.clone())
+                .collect()
+        };
+
+        let state = state_with_frame(&yaml("tr a-z A-Z", false), "");
+        block_on(state.finish_loading_with_items(&env::temp_dir())).unwrap();
+        assert_eq!(names(&state), vec!["ONE", "TWO"]);
+
+        let state = state_with_frame(&yaml("tr a-z A-Z", true), "");
+        assert!(block_on(state.stream_items(&env::temp_dir(), None, |_, _| {})).unwrap());
+        assert_eq!(names(&state), vec!["ONE", "TWO"]);
+
+        let state = state_with_frame(&yaml("cat; exit 3", false), "");
+        let err = block_on(state.finish_loading_with_items(&env::temp_dir())).unwrap_err();
+        assert!(err.starts_with("transform for upper exited"), "{err}");
+    }
+
+    #[test]
+    fn provider_cut_off_by_the_transform_is_not_an_error() {
+        let yaml = |streaming: bool| {
+            format!(
+                r#"{{name: Head, id: head, enabled: true, is_streaming: {streaming},
+                    provider: 'while :; do printf "a\tx\t1\n"; done', transform: 'head -n 3'}}"#
+            )
+        };
+        let count = |state: &AppState| state.inner.read().unwrap().stack[0].all_items.len();
+
+        let state = state_with_frame(&yaml(false), "");
+        block_on(state.finish_loading_with_items(&env::temp_dir())).unwrap();
+        assert_eq!(count(&state), 3);
+
+        let state = state_with_frame(&yaml(true), "");
+        assert!(block_on(state.stream_items(&env::temp_dir(), None, |_, _| {})).unwrap());
+        assert_eq!(count(&state), 3);
+    }
+
+    #[test]
+    fn cached_provider_runs_once_within_ttl() {
+        let counter = env::temp_dir().join(format!("quickspell-cache-{}", std::process::id()));
+        let _ = std::fs::remove_file(&counter);
+        let state = state_with_frame(
+            &format!(
+                r#"{{name: Docker, id: docker, enabled: true, cache_ttl_ms: 60000,
+                    provider: 'echo run >> "{}"; printf "C\tweb\tabc\n"'}}"#,
+                counter.display()
+            ),
+            "",
+        );
+
+        block_on(state.finish_loading_with_items(&env::temp_dir())).unwrap();
+        block_on(state.finish_loading_with_items(&env::temp_dir())).unwrap();
+        let runs = std::fs::read_to_string(&counter).unwrap_or_default();
+        assert_eq!(runs.lines().count(), 1);
+        assert_eq!(state.inner.read().unwrap().stack[0].all_items.len(), 1);
+
+        state.replace_spells(HashMap::new()).unwrap();
+        assert!(state.inner.read().unwrap().provider_cache.is_empty());
+        let _ = std::fs::remove_file(&counter);
+    }
+
+    #[test]
+    fn provider_cache_is_keyed_on_the_parent_selection() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, cache_ttl_ms: 60000,
+              provider: 'ls \"$QUICKSPELL_PARENT_SELECTION_DATA\"'}",
+            "",
+        );
+        state.inner.write().unwrap().stack.insert(
+            0,
+            frame_with("folders", &["DIR\tdocs\t/docs", "DIR\tsrc\t/src"], 0, ""),
+        );
+        let docs = vec![Item::from_line("FILE\tREADME\t/docs/README").unwrap()];
+        state.cache_items(&state.provider_job().unwrap().unwrap(), &docs);
+        let cached = || state.cached_items(&state.provider_job().unwrap().unwrap());
+
+        assert_eq!(cached(), Some(docs));
+        state.inner.write().unwrap().stack[0].selected_idx = 1;
+        assert_eq!(cached(), None);
+    }
+
+    #[test]
+    fn newer_query_cancels_running_dynamic_provider() {
+        let state = state_with_frame(
+            "{name: Slow, id: slow, enabled: true, is_dynamic: true, provider: 'sleep 5'}",
+            "a",
+        );
+
+        let generation = state.next_filter_generation();
+        let runner = {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                block_on(state.reload_dynamic_items(generation, &env::temp_dir()))
+            })
+        };
+        std::thread::sleep(Duration::from_millis(200));
+        let started = Instant::now();
+        state.next_filter_generation();
+
+        assert!(!runner.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn list_actions_omits_actions_whose_condition_fails() {
+        let state = state_with_frame(
+            r#"{name: Apps, id: apps, enabled: true, provider: '',
+                actions: [
+                  {type: CMD, cmd: 'open {{context.apps.selection.data}}'},
+                  {type: CMD, name: REVEAL, cmd: 'open -R x',
+                   if: "{{context.apps.selection.type}} == 'APP'"}]}"#,
+            "",
+        );
+        let set_items = |line: &str| {
+            let mut inner = state.inner.write().unwrap();
+            let frame = inner.stack.last_mut().unwrap();
+            frame.filtered_items = vec![Item::from_line(line).unwrap()];
+        };
+        let labels = || -> Vec<String> {
+            state
+                .list_actions()
+                .unwrap()
+                .into_iter()
+                .map(|action| action.label)
+                .collect()
+        };
+
+        set_items("APP\tSafari\t/Applications/Safari.app");
+        assert_eq!(labels(), vec!["MAIN", "REVEAL"]);
+
+        set_items("FILE\tnotes.txt\t/tmp/notes.txt");
+        assert_eq!(labels(), vec!["MAIN"]);
+    }
+
+    #[test]
+    fn usage_ranking_lifts_frequently_launched_items() {
+        let state = state_with_frame(
+            "{name: Apps, id: apps, enabled: true, provider: '', rank_by_usage: true, search: {field: 2}}",
+            "",
+        );
+        {
+            let mut inner = state.inner.write().unwrap();
+            inner.stack.last_mut().unwrap().all_items = vec![
+                Item::from_line("APP\tMail\t/Applications/Mail.app").unwrap(),
+                Item::from_line("APP\tMaps\t/Applications/Maps.app").unwrap(),
+            ];
+            inner
+                .usage
+                .record("apps", "/Applications/Maps.app", usage::now_secs());
+        }
+        let first = || {
+            let inner = state.inner.read().unwrap();
+            inner.stack.last().unwrap().filtered_items[0].name.clone()
+        };
+
+        assert!(state.filter_items());
+        assert_eq!(first(), "Maps");
+
+        state.set_query("ma".to_string());
+        assert!(state.filter_items());
+        assert_eq!(first(), "Maps");
+    }
+
+    #[test]
+    fn loaded_and_cleared_lists_are_ranked_by_usage() {
+        let state = state_with_frame(
+            r#"{name: Apps, id: apps, enabled: true, rank_by_usage: true, search: {field: 2},
+                provider: 'printf "APP\tMail\tmail\nAPP\tMaps\tmaps\n"'}"#,
+            "",
+        );
+        state
+            .inner
+            .write()
+            .unwrap()
+            .usage
+            .record("apps", "maps", usage::now_secs());
+        let first = || {
+            let inner = state.inner.read().unwrap();
+            inner.stack.last().unwrap().filtered_items[0].name.clone()
+        };
+
+        block_on(state.finish_loading_with_items(&env::temp_dir())).unwrap();
+        assert_eq!(first(), "Maps");
+
+        state.set_query_immediate("mail".to_string());
+        assert_eq!(first(), "Mail");
+        assert!(matches!(state.handle_escape(), EscapeResult::ClearedQuery));
+        assert_eq!(first(), "Maps");
+    }
+
+    #[test]
+    fn closer_matches_report_higher_scores() {
+        let state = state_with_frame(
+            "{name: Apps, id: apps, enabled: true, provider: '', search: {field: 2}}",
+            "",
+        );
+        state.inner.write().unwrap().stack[0].all_items = vec![
+            Item::from_line("APP\tSafari\t/Applications/Safari.app").unwrap(),
+            Item::from_line("APP\tScreen Capture Folder\t/Applications/Capture.app").unwrap(),
+        ];
+        assert!(state.filter_items());
+        assert_eq!(state.snapshot().selected_score, None);
+
+        state.set_query("saf".to_string());
+        assert!(state.filter_items());
+        let closer = state.snapshot().selected_score.unwrap();
+        assert!(state.set_selection_index(1));
+        let looser = state.snapshot().selected_score.unwrap();
+        assert!(closer > looser, "{closer} <= {looser}");
+    }
+
+    #[test]
+    fn remembered_query_seeds_reopened_spell_until_escape() {
+        let state = state_with_spells(vec![
+            spell("{name: Files, id: files, enabled: true, provider: '', remember_query: true}"),
+            spell("{name: Apps, id: apps, enabled: true, provider: ''}"),
+        ]);
+        let reopen = |spell_id: &str| {
+            let mut inner = state.inner.write().unwrap();
+            inner.stack.truncate(1);
+            let frame = new_frame(&mut inner, spell_id.to_string());
+            let query = frame.query.clone();
+            inner.stack.push(frame);
+            query
+        };
+
+        reopen("files");
+        state.set_query("report".to_string());
+        state.set_query(String::new());
+        assert_eq!(reopen("files"), "report");
+
+        reopen("apps");
+        state.set_query("mail".to_string());
+        assert_eq!(reopen("apps"), "");
+
+        reopen("files");
+        assert!(matches!(state.handle_escape(), EscapeResult::ClearedQuery));
+        assert_eq!(reopen("files"), "");
+    }
+
+    #[test]
+    fn conditions_compare_numbers_and_fall_back_to_strings() {
+        let passes = |condition: &str| condition_passes(Some(condition), &[]).unwrap();
+
+        assert!(passes("5 > 3"));
+        assert!(!passes("3 > 5"));
+        assert!(passes("2 >= 2"));
+        assert!(passes("'10' > 9"));
+        assert!(passes("1.5 <= 2"));
+        assert!(!passes("10 < 9"));
+        assert!(passes("apple < banana"));
+        assert!(!passes("10 != 10"));
+        assert!(passes("a != b"));
+    }
+
+    #[test]
+    fn conditions_combine_with_and_binding_tighter_than_or() {
+        let frames = [frame_with(
+            "files",
+            &["FILE\tnotes.txt\t/tmp/notes.txt"],
+            0,
+            "no",
+        )];
+        let passes = |condition: &str| condition_passes(Some(condition), &frames).unwrap();
+
+        assert!(passes(
+            "{{context.files.selection.type}} == 'FILE' && {{context.files.query}} != ''"
+        ));
+        assert!(!passes(
+            "{{context.files.selection.type}} == 'APP' && {{context.files.query}} != ''"
+        ));
+        assert!(passes("1 == 2 || 3 > 2"));
+        assert!(passes("false && false || true"));
+        assert!(!passes("true && false || false"));
+    }
+
+    #[test]
+    fn spell_shell_overrides_settings_shell() {
+        let state = state_with_frame(
+            "{name: Z, id: z, enabled: true, provider: 'echo', shell: bash}",
+            "",
+        );
+        state.set_settings(Settings {
+            shell: Some("no-such-shell-quickspell".to_string()),
+            ..Settings::default()
+        });
+        assert_eq!(
+            state.provider_job().unwrap().unwrap().shell.to_string(),
+            "bash"
+        );
+
+        state
+            .inner
+            .write()
+            .unwrap()
+            .spells
+            .get_mut("z")
+            .unwrap()
+            .shell = None;
+        let err = state.provider_job().err().unwrap();
+        assert!(err.contains("no-such-shell-quickspell"));
+    }
+
+    #[test]
+    fn replacing_spells_keeps_stack_and_query() {
+        let state = state_with_frame("{name: Old, id: files, enabled: true, provider: ''}", "rep");
+        let renamed = spell("{name: New, id: files, enabled: true, provider: ''}");
+
+        state
+            .replace_spells(HashMap::from([(renamed.id.clone(), renamed)]))
+            .unwrap();
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.spell_names, vec!["New"]);
+        assert_eq!(snapshot.query, "rep");
+    }
+
+    #[test]
+    fn placeholders_show_until_the_query_is_non_empty() {
+        let state = state_with_frame(
+            r#"{name: Web, id: web, enabled: true, provider: '', search: {field: 2},
+                placeholder_items: ["HINT\tType to search…\t"]}"#,
+            "",
+        );
+        state.inner.write().unwrap().stack[0].all_items =
+            vec![Item::from_line("URL\trust docs\thttps://doc.rust-lang.org").unwrap()];
+        let names = || -> Vec<String> {
+            let inner = state.inner.read().unwrap();
+            let frame = inner.stack.last().unwrap();
+            frame
+                .filtered_items
+                .iter()
+                .map(|i| i.name.clone())
+                .collect()
+        };
+
+        assert!(state.filter_items());
+        assert_eq!(names(), vec!["Type to search…"]);
+
+        state.set_query("rust".to_string());
+        assert!(state.filter_items());
+        assert_eq!(names(), vec!["rust docs"]);
+
+        state.handle_escape();
+        assert_eq!(names(), vec!["Type to search…"]);
+    }
+
+    #[test]
+    fn placeholders_are_never_selected() {
+        let state = state_with_frame(
+            r#"{name: Web, id: web, enabled: true, provider: '', search: {field: 2},
+                actions: [{type: CMD, cmd: 'open {{context.web.selection.data}}'}],
+                fallback_action: {type: CMD, cmd: 'open https://duckduckgo.com'},
+                placeholder_items: ["HINT\tType to search…\thttps://example.com"]}"#,
+            "",
+        );
+        assert!(state.filter_items());
+
+        assert!(!state.set_selection_index(0));
+        assert!(!state.toggle_mark(0));
+        assert!(state.snapshot().selected_item.is_none());
+        assert_eq!(
+            state.preview_action("MAIN").unwrap(),
+            "open https://duckduckgo.com"
+        );
+    }
+
+    #[test]
+    fn action_outcome_propagates_keep_open() {
+        let actions = spell(
+            r#"{name: Wifi, id: wifi, enabled: true, provider: '',
+                actions: [
+                  {type: CMD, cmd: 'networksetup -setairportpower en0 on', keep_open: true},
+                  {type: CMD, name: OFF, cmd: 'networksetup -setairportpower en0 off'},
+                  {type: OPEN_URL, name: HELP, url: 'https://support.apple.com', keep_open: true}]}"#,
+        )
+        .actions;
+
+        let keep_open: Vec<bool> = actions
+            .iter()
+            .map(|action| action_outcome(action).keep_open)
+            .collect();
+        assert_eq!(keep_open, vec![true, false, true]);
+    }
+
+    #[test]
+    fn preview_action_resolves_without_running() {
+        let marker = env::temp_dir().join(format!("quickspell-dry-run-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let state = state_with_frame(
+            &format!(
+                r#"{{name: Files, id: files, enabled: true, provider: '',
+                    actions: [
+                      {{type: CMD, cmd: 'touch "{}" {{{{shellquote context.files.selection.data}}}}'}},
+                      {{type: OPEN_URL, name: WEB, url: 'https://example.com/{{{{context.files.selection.label}}}}'}},
+                      {{type: SPELL, name: OPEN, spell: 'open_with'}},
+                      {{type: CMD, name: DIRS, if: "{{{{context.files.selection.type}}}} == 'DIR'", cmd: 'ls'}}]}}"#,
+                marker.display()
+            ),
+            "",
+        );
+        state.inner.write().unwrap().stack[0].filtered_items =
+            vec![Item::from_line("FILE	notes	/tmp/my notes.txt").unwrap()];
+
+        assert_eq!(
+            state.preview_action("MAIN").unwrap(),
+            format!(r#"touch "{}" '/tmp/my notes.txt'"#, marker.display())
+        );
+        assert!(!marker.exists());
+        assert_eq!(
+            state.preview_action("WEB").unwrap(),
+            "https://example.com/notes"
+        );
+        assert_eq!(state.preview_action("OPEN").unwrap(), "open_with");
+        let main = state.preview_action("MAIN").unwrap();
+        assert_eq!(state.preview_action("DIRS").unwrap(), main);
+        assert_eq!(state.preview_action("NOPE").unwrap(), main);
+    }
+
+    #[test]
+    fn empty_list_runs_the_fallback_action() {
+        let state = state_with_frame(
+            "{name: Web, id: web, enabled: true, provider: '',
+              actions: [{type: CMD, cmd: 'open {{context.web.selection.data}}'}],
+              fallback_action: {type: CMD, cmd: 'open https://duckduckgo.com/?q={{context.web.query}}'}}",
+            "rust",
+        );
+
+        assert_eq!(
+            state.preview_action("MAIN").unwrap(),
+            "open https://duckduckgo.com/?q=rust"
+        );
+        assert_eq!(
+            state.preview_action("OTHER").unwrap(),
+            "open https://duckduckgo.com/?q=rust"
+        );
+
+        state.inner.write().unwrap().stack[0].filtered_items =
+            vec![Item::from_line("LINK\tdocs\thttps://docs.rs").unwrap()];
+        assert_eq!(
+            state.preview_action("MAIN").unwrap(),
+            "open https://docs.rs"
+        );
+    }
+
+    #[test]
+    fn expand_env_applies_to_provider_and_cmd_actions() {
+        let yaml = "{name: Env, id: env, enabled: true, provider: 'ls ${PATH}',
+                     actions: [{type: CMD, cmd: 'echo ${PATH} {{query}}'}]}";
+        let path = env::var("PATH").unwrap();
+
+        let state = state_with_frame(yaml, "q");
+        assert_eq!(state.provider_job().unwrap().unwrap().command, "ls ${PATH}");
+
+        let state = state_with_frame(
+            &yaml.replace("enabled: true", "enabled: true, expand_env: true"),
+            "q",
+        );
+        assert_eq!(
+            state.provider_job().unwrap().unwrap().command,
+            format!("ls {path}")
+        );
+        assert_eq!(
+            state.preview_action("MAIN").unwrap(),
+            format!("echo {path} q")
+        );
+    }
+
+    #[test]
+    fn alt_falls_back_to_main_without_an_alt_action() {
+        let yaml = "{name: Files, id: files, enabled: true, provider: '',
+                     actions: [
+                       {type: CMD, cmd: 'open {{context.files.selection.data}}'},
+                       {type: CMD, name: REVEAL, cmd: 'open -R {{context.files.selection.data}}'}]}";
+        let with_items = |yaml: &str| {
+            let state = state_with_frame(yaml, "");
+            state.inner.write().unwrap().stack[0].filtered_items =
+                vec![Item::from_line("FILE\tnotes\t/tmp/notes.txt").unwrap()];
+            state
+        };
+
+        let state = with_items(yaml);
+        assert_eq!(state.preview_action("ALT").unwrap(), "open /tmp/notes.txt");
+        assert_eq!(state.preview_action("NOPE").unwrap(), "open /tmp/notes.txt");
+
+        let state = with_items(&yaml.replace("name: REVEAL", "name: ALT"));
+        assert_eq!(
+            state.preview_action("ALT").unwrap(),
+            "open -R /tmp/notes.txt"
+        );
+
+        let state = with_items(&yaml.replace(
+            "name: REVEAL",
+            r#"name: ALT, if: "{{context.files.selection.type}} == 'DIR'""#,
+        ));
+        assert_eq!(state.preview_action("ALT").unwrap(), "open /tmp/notes.txt");
+    }
+
+    #[test]
+    fn confirm_prompt_is_rendered_instead_of_running() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '',
+              actions: [
+                {type: CMD, cmd: 'rm {{shellquote context.files.selection.data}}',
+                 confirm: 'Delete {{context.files.selection.label}}?'},
+                {type: CMD, name: SHOW, cmd: 'open {{context.files.selection.data}}'}]}",
+            "",
+        );
+        state.inner.write().unwrap().stack[0].filtered_items =
+            vec![Item::from_line("FILE\tnotes\t/tmp/notes.txt").unwrap()];
+
+        let resolution = state.resolve_action("MAIN").unwrap();
+        assert_eq!(
+            confirmation_outcome(&resolution),
+            Some(ActionOutcome {
+                keep_open: true,
+                needs_confirm: true,
+                prompt: Some("Delete notes?".to_string()),
+            })
+        );
+        let ResolvedAction::Cmd { cmds, .. } = resolution.action else {
+            panic!("expected a command");
+        };
+        assert_eq!(cmds, vec!["rm '/tmp/notes.txt'"]);
+
+        assert_eq!(
+            confirmation_outcome(&state.resolve_action("SHOW").unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn confirm_refuses_once_the_selection_changed() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '',
+              actions: [
+                {type: CMD, cmd: 'rm {{shellquote context.files.selection.data}}',
+                 confirm: 'Delete {{context.files.selection.label}}?'}]}",
+            "",
+        );
+        state.inner.write().unwrap().stack[0].filtered_items = vec![
+            Item::from_line("FILE\tnotes\t/tmp/notes.txt").unwrap(),
+            Item::from_line("FILE\ttodo\t/tmp/todo.txt").unwrap(),
+        ];
+
+        assert!(matches!(
+            state.invocation("MAIN").unwrap(),
+            Invocation::Prompt(_)
+        ));
+        let resolution = state.confirmed_resolution("MAIN").unwrap();
+        let ResolvedAction::Cmd { cmds, .. } = resolution.action else {
+            panic!("expected a command");
+        };
+        assert_eq!(cmds, vec!["rm '/tmp/notes.txt'"]);
+        assert!(state.confirmed_resolution("MAIN").is_err());
+
+        state.invocation("MAIN").unwrap();
+        state.inner.write().unwrap().stack[0]
+            .filtered_items
+            .remove(0);
+        assert_eq!(
+            state.confirmed_resolution("MAIN").err().as_deref(),
+            Some("selection changed since the confirmation was asked")
+        );
+    }
+
+    #[test]
+    fn chained_spell_actions_see_loaded_items() {
+        let state = state_with_spells(vec![
+            spell(
+                "{name: QuickSpell, id: quickspell, enabled: true, provider: '',
+                  actions: [{type: SPELL, spell: repos}]}",
+            ),
+            spell(
+                r#"{name: Repos, id: repos, enabled: true, is_streaming: true,
+                    provider: 'sleep 0.6; printf "REPO\tquickspell\tbranches\n"; sleep 2',
+                    actions: [{type: SPELL, spell: '{{context.repos.selection.data}}'}]}"#,
+            ),
+            spell(
+                r#"{name: Branches, id: branches, enabled: true,
+                    provider: 'sleep 0.2; printf "BRANCH\tmain\tmain\n"'}"#,
+            ),
+        ]);
+        // Loads on its own thread like `spawn_load`, so MAIN returns on the
+        // first batch while Repos is still streaming.
+        let open_main = || {
+            block_on(state.invoke_blocking_with(
+                "MAIN",
+                |_| unreachable!("only SPELL actions"),
+                |first_batch| {
+                    let state = state.clone();
+                    std::thread::spawn(move || {
+                        block_on(state.load_frame(&env::temp_dir(), Some(first_batch), |_, _| {}))
+                    });
+                },
+            ))
+            .unwrap()
+        };
+
+        open_main();
+        assert!(state.snapshot().is_loading_more);
+        open_main();
+
+        let snapshot = state.snapshot();
+        assert_eq!(
+            snapshot.spell_names,
+            vec!["QuickSpell", "Repos", "Branches"]
+        );
+        assert_eq!(snapshot.status, AppStatus::Ready);
+        assert_eq!(snapshot.top_items[0].name, "main");
+    }
+
+    #[test]
+    fn streamed_batches_respect_the_current_query() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '', search: {field: 2}}",
+            "note",
+        );
+        let lines = [
+            "FILE\tnotes.txt\t/a",
+            "FILE\ttodo.txt\t/b",
+            "FILE\tnote-2.md\t/c",
+            "FILE\treadme.md\t/d",
+            "FILE\tbudget.xlsx\t/e",
+        ];
+        let mut inner = state.inner.write().unwrap();
+        let frame_uid = inner.stack[0].id;
+        append_items_for_frame(
+            &mut inner,
+            frame_uid,
+            lines.iter().map(|l| Item::from_line(l).unwrap()).collect(),
+        );
+
+        let frame = &inner.stack[0];
+        assert_eq!(frame.all_items.len(), 5);
+        let mut names: Vec<&str> = frame
+            .filtered_items
+            .iter()
+            .map(|i| i.name.as_str())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["note-2.md", "notes.txt"]);
+    }
 
-        let mut child = Command::new("sh")
-            .arg("-c")
-            .arg(&provider_cmd)
-            .current_dir(resources_dir)
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(|e| format!("failed to spawn provider for {frame_id}: {e}"))?;
+    #[test]
+    fn action_commands_report_empty_and_failing_commands() {
+        let dir = std::env::temp_dir();
+        assert_eq!(
+            run_action_command(None, "  ", &dir),
+            Err("resolved command is empty".to_string())
+        );
+        assert!(run_action_command(None, "true", &dir).is_ok());
+        let err = run_action_command(None, "false", &dir).unwrap_err();
+        assert!(
+            err.starts_with("action command exited with status"),
+            "{err}"
+        );
+    }
 
-        let stdout = child.stdout.take().ok_or("no stdout handle")?;
-        let reader = BufReader::new(stdout);
+    #[test]
+    fn provider_cwd_expands_home_and_resolves_relative_paths() {
+        let resources = Path::new("/res");
+        let home = Some(Path::new("/Users/me"));
 
-        let mut batch: Vec<Item> = Vec::new();
-        let mut last_emit = Instant::now();
-        let throttle = Duration::from_millis(500);
+        assert_eq!(provider_cwd(None, resources, home), PathBuf::from("/res"));
+        assert_eq!(
+            provider_cwd(Some("~"), resources, home),
+            PathBuf::from("/Users/me")
+        );
+        assert_eq!(
+            provider_cwd(Some("~/code"), resources, home),
+            PathBuf::from("/Users/me/code")
+        );
+        assert_eq!(
+            provider_cwd(Some("providers"), resources, home),
+            PathBuf::from("/res/providers")
+        );
+        assert_eq!(
+            provider_cwd(Some("/tmp"), resources, home),
+            PathBuf::from("/tmp")
+        );
+        assert_eq!(
+            provider_cwd(Some("~other"), resources, home),
+            PathBuf::from("/res/~other")
+        );
+    }
 
-        for line in reader.lines().map_while(Result::ok) {
-            if let Some(item) = parse_item_line(&line, &frame_id) {
-                batch.push(item);
-            }
-            if last_emit.elapsed() >= throttle {
-                if self.is_current_frame(frame_uid) {
-                    self.append_items_for_frame(frame_uid, std::mem::take(&mut batch));
-                    let _ = self.emit_snapshot(app);
-                } else {
-                    batch.clear();
-                }
-                last_emit = Instant::now();
-            }
-        }
+    #[test]
+    fn snapshot_reports_filter_timing_only_in_debug_mode() {
+        let state = state_with_frame("{name: F, id: f, enabled: true, provider: ''}", "");
+        state.record_filter_metrics(metrics("a", 42, 3, 950));
+        assert_eq!(state.snapshot().last_filter_micros, None);
 
-        if !batch.is_empty() && self.is_current_frame(frame_uid) {
-            self.append_items_for_frame(frame_uid, batch);
-        }
+        state.set_settings(Settings {
+            debug_metrics: Some(true),
+            ..Settings::default()
+        });
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.last_filter_micros, Some(950));
+        assert_eq!(snapshot.last_filter_item_count, Some(42));
+    }
 
-        if self.is_current_frame(frame_uid) {
-            self.set_ready();
-            let _ = self.emit_snapshot(app);
+    fn state_with_items(count: usize) -> AppState {
+        let state = state_with_frame(
+            "{name: Numbers, id: numbers, enabled: true, provider: ''}",
+            "",
+        );
+        {
+            let mut inner = state.inner.write().unwrap();
+            let frame = inner.stack.last_mut().unwrap();
+            frame.filtered_items = (0..count)
+                .map(|i| Item::from_line(&format!("NUM\t{i}\t{i}")).unwrap())
+                .collect();
         }
-        let _ = child.wait();
-        Ok(())
+        state
     }
 
-    pub fn invoke_action(
-        &self,
-        label: &str,
-        resources_dir: &Path,
-        app: &AppHandle,
-    ) -> Result<(), String> {
-        let (frames, actions) = {
-            let inner = self.inner.read().map_err(|_| "state lock poisoned")?;
-            let frames = inner.stack.clone();
-            let spell = inner
-                .stack
-                .last()
-                .and_then(|frame| inner.spells.get(&frame.spell_id))
-                .ok_or_else(|| "no active spell".to_string())?;
-            (frames, spell.actions.clone())
-        };
-
-        for action in actions {
-            let action_label = action_name(&action).unwrap_or("MAIN");
-            if action_label != label {
-                continue;
-            }
+    #[test]
+    fn selection_skips_header_items() {
+        let state = state_with_frame(
+            "{name: Mixed, id: mixed, enabled: true, provider: '', search: {field: 2}}",
+            "",
+        );
+        state.inner.write().unwrap().stack[0].all_items = [
+            "HEADER\tApps\t",
+            "APP\tSafari\t/Applications/Safari.app",
+            "APP\tMail\t/Applications/Mail.app",
+            "HEADER\tFiles\t",
+            "FILE\tapps.txt\t/tmp/apps.txt",
+        ]
+        .iter()
+        .map(|line| Item::from_line(line).unwrap())
+        .collect();
+        state.filter_items();
+        let selected = || state.snapshot().selected_item.unwrap().index;
 
-            if !condition_passes(action_condition(&action), &frames)? {
-                continue;
-            }
+        assert_eq!(state.snapshot().top_items.len(), 5);
+        assert_eq!(selected(), 1);
+        state.set_selection_delta(1);
+        state.set_selection_delta(1);
+        assert_eq!(selected(), 4);
+        state.set_selection_delta(-1);
+        assert_eq!(selected(), 2);
+        state.set_selection_delta(10);
+        state.set_selection_index(3);
+        assert_eq!(selected(), 4);
+        state.set_selection_index(0);
+        assert_eq!(selected(), 1);
+        assert!(!state.toggle_mark(0));
 
-            match action {
-                Action::Spell { spell, .. } => {
-                    let rendered_spell =
-                        template::resolve_template(&spell, &frames).map_err(|e| match e {
-                            template::TemplateError::Render(err) => err,
-                        })?;
+        state.set_query_immediate("apps".into());
+        let names: Vec<_> = state
+            .snapshot()
+            .top_items
+            .iter()
+            .map(|item| item.name.clone())
+            .collect();
+        assert_eq!(names, vec!["apps.txt"]);
+    }
 
-                    let target_spell_id = rendered_spell.trim();
-                    if target_spell_id.is_empty() {
-                        return Err("resolved spell id is empty".to_string());
-                    }
+    #[test]
+    fn dispatch_applies_a_batch_with_one_snapshot() {
+        let state = state_with_frame(
+            "{name: Fruit, id: fruit, enabled: true, provider: '', search: {field: 2}}",
+            "",
+        );
+        state.inner.write().unwrap().stack[0].all_items = ["apple", "apricot", "banana"]
+            .iter()
+            .map(|name| Item::from_line(&format!("FRUIT\t{name}\t{name}")).unwrap())
+            .collect();
 
-                    {
-                        let mut inner = self.inner.write().map_err(|_| "state lock poisoned")?;
-                        if !inner.spells.contains_key(target_spell_id) {
-                            return Err(format!("spell {target_spell_id} not found"));
-                        }
-                        let frame = new_frame(&mut inner, target_spell_id.to_string());
-                        inner.stack.push(frame);
-                        inner.status = AppStatus::Loading;
-                    }
+        let mut snapshots = Vec::new();
+        let actions = vec![
+            UiAction::SetQuery { query: "ap".into() },
+            UiAction::SelectDelta { delta: 1 },
+        ];
+        let result = state
+            .dispatch(
+                actions,
+                |_| unreachable!("no Invoke in the batch"),
+                |snapshot| snapshots.push(snapshot),
+            )
+            .unwrap();
 
-                    let _ = self.emit_snapshot(app);
-
-                    let state = self.clone();
-                    let resources_dir = resources_dir.to_path_buf();
-                    let app_handle = app.clone();
-                    async_runtime::spawn_blocking(move || {
-                        let is_streaming = state
-                            .get_current_spell()
-                            .and_then(|s| s.is_streaming)
-                            .unwrap_or(false);
-
-                        let result = if is_streaming {
-                            state.stream_items_for_current_frame(&resources_dir, &app_handle)
-                        } else {
-                            state.finish_loading_with_items(&resources_dir)
-                        };
-
-                        match result {
-                            Ok(()) => {
-                                let _ = state.emit_snapshot(&app_handle);
-                            }
-                            Err(err) => {
-                                state.set_error();
-                                let _ = state.emit_snapshot(&app_handle);
-                                eprintln!("failed to load items: {err}");
-                            }
-                        }
-                    });
+        assert!(result.query_changed && !result.pushed_spell);
+        assert_eq!(snapshots.len(), 1);
+        let snapshot = &snapshots[0];
+        assert_eq!(snapshot.query, "ap");
+        assert_eq!(snapshot.top_items.len(), 2);
+        assert_eq!(snapshot.selected_item.as_ref().unwrap().index, 1);
+    }
 
-                    return Ok(());
-                }
-                Action::Cmd { cmd, .. } => {
-                    let rendered_cmd =
-                        template::resolve_template(&cmd, &frames).map_err(|e| match e {
-                            template::TemplateError::Render(err) => err,
-                        })?;
+    #[test]
+    fn invalid_regex_query_empties_the_list_and_shows_the_error() {
+        let state = state_with_frame(
+            "{name: Logs, id: logs, enabled: true, provider: '', search: {field: 2, mode: regex}}",
+            "[unclosed",
+        );
+        state.inner.write().unwrap().stack[0].all_items =
+            vec![Item::from_line("LOG\t[unclosed bracket\t1").unwrap()];
 
-                    if rendered_cmd.trim().is_empty() {
-                        return Err("resolved command is empty".to_string());
-                    }
+        assert!(state.filter_items());
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.total_items, 0);
+        assert!(!snapshot.is_filtering);
+        assert!(snapshot
+            .query_error
+            .is_some_and(|err| err.contains("unclosed")));
 
-                    let argv = shell_words::split(&rendered_cmd)
-                        .map_err(|err| format!("failed to parse action command: {err}"))?;
+        state.set_query(r"\[unclosed".to_string());
+        assert!(state.filter_items());
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.total_items, 1);
+        assert_eq!(snapshot.query_error, None);
+    }
 
-                    let (program, args) = argv
-                        .split_first()
-                        .ok_or_else(|| "resolved command is empty".to_string())?;
+    #[test]
+    fn visible_rows_are_reported_only_with_auto_height() {
+        let state = state_with_items(3);
+        assert_eq!(state.snapshot().visible_rows, None);
 
-                    let status = std::process::Command::new(program)
-                        .args(args)
-                        .current_dir(resources_dir)
-                        .status()
-                        .map_err(|err| format!("failed to run action command: {err}"))?;
+        state.set_settings(Settings {
+            auto_height: Some(true),
+            ..Settings::default()
+        });
+        assert_eq!(state.snapshot().visible_rows, Some(3));
+    }
 
-                    if status.success() {
-                        hide_main_window(app);
-                        self.reset_to_root(resources_dir, app)?;
-                        return Ok(());
-                    } else {
-                        return Err(format!("action command exited with status {status}"));
-                    }
-                }
-            }
+    #[test]
+    fn type_counts_cover_every_match_past_the_list_limit() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '', search: {field: 2}}",
+            "",
+        );
+        {
+            let mut inner = state.inner.write().unwrap();
+            let frame = inner.stack.last_mut().unwrap();
+            frame.all_items = (0..150)
+                .map(|i| {
+                    let kind = if i % 3 == 0 { "APP" } else { "FILE" };
+                    Item::from_line(&format!("{kind}\titem {i}\t{i}")).unwrap()
+                })
+                .chain(Item::from_line("APP\tother\tother"))
+                .collect();
         }
 
-        Err(format!("no matching action for label {label}"))
-    }
+        state.set_query_immediate("item".to_string());
 
-    fn is_current_frame(&self, frame_uid: u64) -> bool {
-        if let Ok(inner) = self.inner.read() {
-            is_current_frame(&inner, frame_uid)
-        } else {
-            false
-        }
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.top_items.len(), TOP_ITEMS_LIMIT);
+        assert_eq!(snapshot.type_counts.len(), 2);
+        assert_eq!(snapshot.type_counts["APP"], 50);
+        assert_eq!(snapshot.type_counts["FILE"], 100);
     }
 
-    fn append_items_for_frame(&self, frame_uid: u64, new_items: Vec<Item>) {
-        if let Ok(mut inner) = self.inner.write() {
-            append_items_for_frame(&mut inner, frame_uid, new_items);
-        }
+    fn selected_idx(state: &AppState) -> usize {
+        state
+            .inner
+            .read()
+            .unwrap()
+            .stack
+            .last()
+            .unwrap()
+            .selected_idx
     }
-}
 
-impl Default for AppState {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+    #[test]
+    fn selection_index_clamps_to_the_list() {
+        let state = state_with_items(5);
+        assert!(state.set_selection_index(3));
+        assert_eq!(selected_idx(&state), 3);
 
-fn is_current_frame(inner: &AppInner, frame_uid: u64) -> bool {
-    inner
-        .stack
-        .last()
-        .map(|frame| frame.id == frame_uid)
-        .unwrap_or(false)
-}
+        assert!(state.set_selection_index(42));
+        assert_eq!(selected_idx(&state), 4);
+        assert!(!state.set_selection_index(7));
 
-fn append_items_for_frame(inner: &mut AppInner, frame_uid: u64, new_items: Vec<Item>) {
-    if let Some(frame) = inner.stack.last_mut() {
-        if frame.id == frame_uid {
-            frame.all_items.extend(new_items.clone());
-            frame.filtered_items.extend(new_items);
-        }
+        let empty = state_with_items(0);
+        assert!(!empty.set_selection_index(2));
+        assert_eq!(selected_idx(&empty), 0);
     }
-}
-
-impl AppState {
-    fn reset_to_root(&self, resources_dir: &Path, app: &AppHandle) -> Result<(), String> {
-        {
-            let mut inner = self.inner.write().map_err(|_| "state lock poisoned")?;
-            if !inner.spells.contains_key(STARTING_SPELL_ID) {
-                return Err(format!("spell {STARTING_SPELL_ID} not found"));
-            }
-            let frame = new_frame(&mut inner, STARTING_SPELL_ID.to_string());
-            inner.stack.clear();
-            inner.stack.push(frame);
-            inner.status = AppStatus::Loading;
-        }
 
-        let _ = self.emit_snapshot(app);
+    #[test]
+    fn selection_pages_clamp_at_both_ends() {
+        let state = state_with_items(250);
+        let max_idx = TOP_ITEMS_LIMIT - 1;
 
-        let state = self.clone();
-        let resources_dir = resources_dir.to_path_buf();
-        let app_handle = app.clone();
-        async_runtime::spawn_blocking(move || {
-            let is_streaming = state
-                .get_current_spell()
-                .and_then(|s| s.is_streaming)
-                .unwrap_or(false);
+        assert!(state.set_selection_page(1, 40));
+        assert_eq!(selected_idx(&state), 40);
+        state.set_selection_page(1, 40);
+        state.set_selection_page(1, 40);
+        assert_eq!(selected_idx(&state), max_idx);
 
-            let result = if is_streaming {
-                state.stream_items_for_current_frame(&resources_dir, &app_handle)
-            } else {
-                state.finish_loading_with_items(&resources_dir)
-            };
+        state.set_selection_page(-1, 40);
+        assert_eq!(selected_idx(&state), max_idx - 40);
+        state.set_selection_page(-1, 500);
+        assert_eq!(selected_idx(&state), 0);
+    }
 
-            match result {
-                Ok(()) => {
-                    let _ = state.emit_snapshot(&app_handle);
-                }
-                Err(err) => {
-                    state.set_error();
-                    let _ = state.emit_snapshot(&app_handle);
-                    eprintln!("failed to load items after reset: {err}");
-                }
-            }
+    #[test]
+    fn wrap_selection_continues_from_the_other_end() {
+        let state = state_with_items(5);
+        state.set_settings(Settings {
+            wrap_selection: Some(true),
+            ..Settings::default()
         });
 
-        Ok(())
-    }
-}
+        assert!(state.set_selection_delta(-1));
+        assert_eq!(selected_idx(&state), 4);
+        assert!(state.set_selection_delta(1));
+        assert_eq!(selected_idx(&state), 0);
 
-fn hide_main_window(app: &AppHandle) {
-    if let Some(window) = app.get_webview_window(MAIN_WINDOW_LABEL) {
-        let _ = window.hide();
+        let unwrapped = state_with_items(5);
+        unwrapped.set_selection_delta(-1);
+        assert_eq!(selected_idx(&unwrapped), 0);
+
+        let empty = state_with_items(0);
+        empty.set_settings(state.settings());
+        empty.set_selection_delta(-1);
+        assert_eq!(selected_idx(&empty), 0);
     }
-}
 
-fn matching_actions(inner: &AppInner) -> Vec<AvailableAction> {
-    let frames = &inner.stack;
-    let Some(frame) = frames.last() else {
-        return Vec::new();
-    };
-    let Some(spell) = inner.spells.get(&frame.spell_id) else {
-        return Vec::new();
-    };
+    #[test]
+    fn items_page_clamps_offset_and_limit() {
+        let state = state_with_items(250);
+        state.set_selection_index(7);
 
-    spell
-        .actions
-        .iter()
-        .filter_map(
-            |action| match condition_passes(action_condition(action), frames) {
-                Ok(true) => Some(AvailableAction {
-                    label: action_name(action).unwrap_or("MAIN").to_string(),
-                    action_type: ActionType::from(action),
-                }),
-                Ok(false) => None,
-                Err(err) => {
-                    eprintln!("failed to evaluate action condition: {err}");
-                    None
-                }
-            },
-        )
-        .collect()
-}
+        let page = state.items_page(120, 50);
+        assert_eq!(page.total, 250);
+        assert_eq!(page.items.len(), 50);
+        assert_eq!(page.items[0].name, "120");
 
-fn new_frame(inner: &mut AppInner, spell_id: String) -> Frame {
-    let id = inner.next_frame_id;
-    inner.next_frame_id = inner.next_frame_id.wrapping_add(1);
-    Frame {
-        id,
-        spell_id,
-        query: String::new(),
-        all_items: Vec::new(),
-        filtered_items: Vec::new(),
-        is_filtering: false,
-        selected_idx: 0,
+        let tail = state.items_page(240, 50);
+        assert_eq!(tail.items.len(), 10);
+        assert_eq!(tail.items[9].name, "249");
+        assert!(state.items_page(250, 10).items.is_empty());
+        assert!(state.items_page(usize::MAX, usize::MAX).items.is_empty());
+        assert_eq!(state.items_page(0, usize::MAX).items.len(), 250);
+        assert_eq!(selected_idx(&state), 7);
     }
-}
 
-fn parse_item_line(line: &str, frame_id: &str) -> Option<Item> {
-    if line.trim().is_empty() {
-        return None;
+    #[test]
+    fn toggle_mark_adds_and_removes_indices() {
+        let state = state_with_items(5);
+        assert!(state.toggle_mark(3));
+        assert!(state.toggle_mark(1));
+        assert_eq!(state.snapshot().marked_indices, vec![1, 3]);
+
+        assert!(state.toggle_mark(3));
+        assert_eq!(state.snapshot().marked_indices, vec![1]);
+        assert!(!state.toggle_mark(5));
+        assert_eq!(state.snapshot().marked_indices, vec![1]);
     }
 
-    match Item::from_line(line) {
-        Some(item) => Some(item),
-        None => {
-            eprintln!("skipping malformed item for frame {frame_id}: {line}");
-            None
+    #[test]
+    fn marks_clear_on_frame_change() {
+        let state = state_with_spells(vec![
+            spell("{name: QuickSpell, id: quickspell, enabled: true, provider: ''}"),
+            spell("{name: GitHub, id: github, enabled: true, alias: gh, provider: ''}"),
+        ]);
+        {
+            let mut inner = state.inner.write().unwrap();
+            inner.stack[0].filtered_items = vec![
+                Item::from_line("A	a	1").unwrap(),
+                Item::from_line("A	b	2").unwrap(),
+            ];
         }
+        assert!(state.toggle_mark(1));
+
+        state.set_query("gh ".to_string());
+        assert!(state.snapshot().marked_indices.is_empty());
+        {
+            let mut inner = state.inner.write().unwrap();
+            inner.stack[0].selected_set.insert(0);
+            inner.stack[1].filtered_items = vec![Item::from_line("R	repo	x").unwrap()];
+        }
+        assert!(state.toggle_mark(0));
+
+        assert!(state.pop_frame());
+        let inner = state.inner.read().unwrap();
+        assert!(inner.stack[0].selected_set.is_empty());
     }
-}
 
-fn log_filter_metrics(
-    query: &str,
-    items: usize,
-    results: usize,
-    applied: bool,
-    elapsed: Duration,
-) -> std::io::Result<()> {
-    let log_path = resolve_log_path()?;
-    if let Some(parent) = log_path.parent() {
-        create_dir_all(parent)?;
-    }
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(log_path)?;
-
-    writeln!(
-        file,
-        "[filter] query={query:?} items={items} results={results} applied={applied} time={elapsed:?}"
-    )
-}
+    #[test]
+    fn multi_templates_resolve_once_per_marked_item() {
+        let state = state_with_items(4);
+        state.set_selection_index(2);
+        state.toggle_mark(3);
+        state.toggle_mark(0);
+        let mut frames = state.inner.read().unwrap().stack.clone();
+        let template = "rm {{context.numbers.selection.data}}";
+
+        let rendered = resolve_per_item(template, &mut frames, true).unwrap();
+        assert_eq!(rendered, vec!["rm 0", "rm 3"]);
+        assert_eq!(frames[0].selected_idx, 2);
+
+        let single = resolve_per_item(template, &mut frames, false).unwrap();
+        assert_eq!(single, vec!["rm 2"]);
+        frames[0].selected_set.clear();
+        let unmarked = resolve_per_item(template, &mut frames, true).unwrap();
+        assert_eq!(unmarked, vec!["rm 2"]);
+    }
 
-fn resolve_log_path() -> std::io::Result<std::path::PathBuf> {
-    let base = if cfg!(target_os = "macos") {
-        env::var_os("HOME").map(std::path::PathBuf::from).map(|p| {
-            p.join("Library")
-                .join("Application Support")
-                .join(BUNDLE_IDENTIFIER)
+    #[test]
+    fn snapshot_recovers_from_a_poisoned_lock() {
+        let state = state_with_items(3);
+        let poisoner = state.clone();
+        let _ = std::thread::spawn(move || {
+            let _inner = poisoner.inner.write().unwrap();
+            panic!("simulated panic while holding the state lock");
         })
-    } else if cfg!(target_os = "windows") {
-        env::var_os("APPDATA")
-            .map(std::path::PathBuf::from)
-            .map(|p| p.join(BUNDLE_IDENTIFIER))
-    } else {
-        env::var_os("XDG_DATA_HOME")
-            .map(std::path::PathBuf::from)
-            .or_else(|| {
-                env::var_os("HOME").map(|p| std::path::PathBuf::from(p).join(".local/share"))
-            })
-            .map(|p| p.join(BUNDLE_IDENTIFIER))
-    };
+        .join();
+        assert!(state.inner.is_poisoned());
 
-    base.map(|p| p.join("quickspell.log"))
-        .ok_or_else(|| std::io::Error::other("could not resolve log directory for quickspell"))
-}
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.status, AppStatus::Error);
+        assert_eq!(
+            snapshot.error_message.as_deref(),
+            Some(POISON_RECOVERY_MESSAGE)
+        );
+        assert_eq!(snapshot.total_items, 0);
 
-fn max_selectable_index(len: usize) -> Option<usize> {
-    if len == 0 {
-        None
-    } else {
-        Some(len.saturating_sub(1).min(TOP_ITEMS_LIMIT.saturating_sub(1)))
+        assert!(!state.inner.is_poisoned());
+        let inner = state.inner.read().unwrap();
+        assert_eq!(inner.stack.len(), 1);
+        assert_eq!(inner.stack[0].spell_id, "numbers");
     }
-}
 
-fn clamp_selection(frame: &mut Frame) {
-    if frame.filtered_items.is_empty() {
-        frame.selected_idx = 0;
-    } else if let Some(max_idx) = max_selectable_index(frame.filtered_items.len()) {
-        frame.selected_idx = frame.selected_idx.min(max_idx);
-    } else {
-        frame.selected_idx = 0;
+    #[test]
+    fn mutators_recover_from_a_poisoned_lock() {
+        let state = state_with_items(3);
+        let poison = || {
+            let poisoner = state.clone();
+            let _ = std::thread::spawn(move || {
+                let _inner = poisoner.inner.write().unwrap();
+                panic!("simulated panic while holding the state lock");
+            })
+            .join();
+        };
+
+        poison();
+        assert!(matches!(
+            state.set_query("2".to_string()),
+            QueryResult::Updated
+        ));
+        assert!(!state.inner.is_poisoned());
+        {
+            let inner = state.inner.read().unwrap();
+            assert_eq!(inner.status, AppStatus::Error);
+            assert_eq!(inner.stack.len(), 1);
+            assert_eq!(inner.stack[0].query, "2");
+        }
+
+        // Recovery already reset the query, so there is nothing left to clear.
+        poison();
+        assert!(!state.clear_query());
+        assert!(!state.inner.is_poisoned());
+
+        poison();
+        assert_eq!(
+            state.confirmed_resolution("MAIN").err().as_deref(),
+            Some("no confirmation pending for MAIN")
+        );
+        assert!(!state.inner.is_poisoned());
     }
-}
 
-impl From<&Action> for ActionType {
-    fn from(action: &Action) -> Self {
-        match action {
-            Action::Cmd { .. } => ActionType::Cmd,
-            Action::Spell { .. } => ActionType::Spell,
+    fn state_with_preview(preview_cmd: &str) -> AppState {
+        let state = state_with_frame(
+            &format!("{{name: Files, id: files, enabled: true, provider: '', preview_cmd: '{preview_cmd}'}}"),
+            "",
+        );
+        {
+            let mut inner = state.inner.write().unwrap();
+            let frame = inner.stack.last_mut().unwrap();
+            frame.filtered_items = vec![
+                Item::from_line("FILE\ta\t/tmp/a").unwrap(),
+                Item::from_line("FILE\tb\t/tmp/b").unwrap(),
+            ];
         }
+        state
     }
-}
 
-fn action_name(action: &Action) -> Option<&str> {
-    match action {
-        Action::Cmd { name, .. } | Action::Spell { name, .. } => name.as_deref(),
+    #[test]
+    fn preview_cmd_output_is_cached_per_item() {
+        let state = state_with_preview(r#"echo "preview {{context.files.selection.data}}""#);
+
+        let generation = state.next_preview_generation();
+        assert!(block_on(state.load_preview(generation, &env::temp_dir())));
+        assert_eq!(
+            state.snapshot().preview.as_deref(),
+            Some("preview /tmp/a\n")
+        );
+
+        let generation = state.next_preview_generation();
+        assert!(!block_on(state.load_preview(generation, &env::temp_dir())));
+
+        state.set_selection_delta(1);
+        assert_eq!(state.snapshot().preview, None);
+        state.set_selection_delta(-1);
+        assert_eq!(
+            state.snapshot().preview.as_deref(),
+            Some("preview /tmp/a\n")
+        );
     }
-}
 
-fn action_condition(action: &Action) -> Option<&str> {
-    match action {
-        Action::Cmd { condition, .. } | Action::Spell { condition, .. } => condition.as_deref(),
+    #[test]
+    fn get_preview_resolves_the_template_or_runs_the_command() {
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '',
+              preview: 'Path: {{context.files.selection.data}}'}",
+            "",
+        );
+        state.inner.write().unwrap().stack[0].filtered_items =
+            vec![Item::from_line("FILE\ta\t/tmp/a").unwrap()];
+        assert_eq!(
+            block_on(state.get_preview(&env::temp_dir()))
+                .unwrap()
+                .as_deref(),
+            Some("Path: /tmp/a")
+        );
+
+        let state = state_with_preview(r#"echo "preview {{context.files.selection.data}}""#);
+        assert_eq!(
+            block_on(state.get_preview(&env::temp_dir()))
+                .unwrap()
+                .as_deref(),
+            Some("preview /tmp/a\n")
+        );
+        assert_eq!(
+            state.snapshot().preview.as_deref(),
+            Some("preview /tmp/a\n")
+        );
+
+        let state = state_with_items(2);
+        assert_eq!(block_on(state.get_preview(&env::temp_dir())).unwrap(), None);
     }
-}
 
-fn condition_passes(condition: Option<&str>, frames: &[Frame]) -> Result<bool, String> {
-    let Some(raw) = condition else {
-        return Ok(true);
-    };
+    #[test]
+    fn preview_cmd_runs_in_the_spell_cwd() {
+        let dir = env::temp_dir().canonicalize().unwrap();
+        let state = state_with_frame(
+            "{name: Files, id: files, enabled: true, provider: '', preview_cmd: 'pwd -P',
+              cwd: '{{context.files.selection.data}}'}",
+            "",
+        );
+        state.inner.write().unwrap().stack[0].filtered_items =
+            vec![Item::from_line(&format!("DIR\ttmp\t{}", dir.display())).unwrap()];
 
-    let rendered = template::resolve_template(raw, frames).map_err(|e| match e {
-        template::TemplateError::Render(err) => err,
-    })?;
+        assert_eq!(
+            block_on(state.get_preview(Path::new("/"))).unwrap(),
+            Some(format!("{}\n", dir.display()))
+        );
+    }
 
-    let text = rendered.trim();
+    #[test]
+    fn failing_preview_cmd_reports_the_error() {
+        let state = state_with_preview("exit 3");
 
-    if text.is_empty() {
-        return Ok(true);
+        let generation = state.next_preview_generation();
+        assert!(block_on(state.load_preview(generation, &env::temp_dir())));
+        let preview = state.snapshot().preview.unwrap();
+        assert!(
+            preview.starts_with("preview failed: preview command exited"),
+            "{preview}"
+        );
     }
 
-    if let Some((lhs, rhs)) = text.split_once("==") {
-        return Ok(normalize_condition_value(lhs) == normalize_condition_value(rhs));
+    #[test]
+    fn superseded_preview_is_dropped() {
+        let state = state_with_preview("sleep 5");
+
+        let generation = state.next_preview_generation();
+        let runner = {
+            let state = state.clone();
+            std::thread::spawn(move || block_on(state.load_preview(generation, &env::temp_dir())))
+        };
+        std::thread::sleep(Duration::from_millis(200));
+        let started = Instant::now();
+        state.next_preview_generation();
+
+        assert!(!runner.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(state.inner.read().unwrap().preview_cache.is_empty());
     }
 
-    if let Some((lhs, rhs)) = text.split_once("!=") {
-        return Ok(normalize_condition_value(lhs) != normalize_condition_value(rhs));
+    #[test]
+    fn set_error_exposes_message_until_ready() {
+        let state = AppState::new();
+        state.set_error("provider for search_files exited with status 127".to_string());
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.status, AppStatus::Error);
+        assert_eq!(
+            snapshot.error_message.as_deref(),
+            Some("provider for search_files exited with status 127")
+        );
+
+        state.set_ready();
+        assert_eq!(state.snapshot().error_message, None);
     }
 
-    match text.to_ascii_lowercase().as_str() {
-        "true" | "1" | "yes" | "y" => Ok(true),
-        "false" | "0" | "no" | "n" => Ok(false),
-        _ => Ok(!text.is_empty()),
+    #[test]
+    fn summarizes_recent_filter_metrics() {
+        let recent: VecDeque<FilterMetrics> = (1..=10)
+            .map(|i| metrics(&format!("q{i}"), 100 * i, i, i as u64 * 10))
+            .collect();
+
+        let summary = summarize_filter_metrics(&recent);
+        assert_eq!(summary.samples, 10);
+        assert_eq!(summary.avg_elapsed_micros, 55);
+        assert_eq!(summary.p50_elapsed_micros, 50);
+        assert_eq!(summary.p95_elapsed_micros, 100);
+        assert_eq!(summary.max_elapsed_micros, 100);
+        assert_eq!(summary.avg_items, 550);
+        assert_eq!(summary.avg_results, 5);
+        assert_eq!(summary.slowest.map(|m| m.query), Some("q10".to_string()));
     }
-}
 
-fn normalize_condition_value(value: &str) -> String {
-    strip_matching_quotes(value.trim()).to_string()
-}
+    #[test]
+    fn empty_metrics_summary_is_zeroed() {
+        let summary = summarize_filter_metrics(&VecDeque::new());
+        assert_eq!(summary.samples, 0);
+        assert!(summary.slowest.is_none());
+    }
 
-fn strip_matching_quotes(value: &str) -> &str {
-    if value.len() >= 2 {
-        let bytes = value.as_bytes();
-        if (bytes[0] == b'"' && bytes[value.len() - 1] == b'"')
-            || (bytes[0] == b'\'' && bytes[value.len() - 1] == b'\'')
-        {
-            return &value[1..value.len() - 1];
+    #[test]
+    fn filter_metrics_ring_is_bounded() {
+        let state = AppState::new();
+        for i in 0..FILTER_METRICS_LIMIT + 5 {
+            state.record_filter_metrics(metrics("q", i, 0, 1));
         }
+
+        let inner = state.inner.read().unwrap();
+        assert_eq!(inner.recent_filters.len(), FILTER_METRICS_LIMIT);
+        assert_eq!(inner.recent_filters.front().map(|m| m.items), Some(5));
     }
-    value
 }
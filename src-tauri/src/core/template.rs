@@ -1,10 +1,12 @@
 use std::collections::HashMap;
 
-use handlebars::Handlebars;
+use handlebars::{handlebars_helper, Handlebars};
 use serde::Serialize;
 
 use crate::api::types::{Frame, Item};
 
+const ITEM_TEMPLATE_NAME: &str = "item";
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum TemplateError {
     Render(String),
@@ -24,11 +26,12 @@ impl SelectionContext {
     fn from_item(item: Option<&Item>) -> Self {
         let (raw, kind, label, data, fields) = match item {
             Some(value) => {
-                let fields = vec![
+                let mut fields = vec![
                     value.item_type.clone(),
                     value.name.clone(),
                     value.data.clone(),
                 ];
+                fields.extend(value.extra.iter().cloned());
                 (
                     value.raw(),
                     value.item_type.clone(),
@@ -67,17 +70,112 @@ struct FrameContext {
 #[derive(Debug, Serialize)]
 struct TemplateContext {
     context: HashMap<String, FrameContext>,
+    /// The top frame's query, as `{{query}}`.
+    query: String,
 }
 
-pub fn resolve_template(template: &str, frames: &[Frame]) -> Result<String, TemplateError> {
+// `{{field context.<spell>.selection 3}}`: the selection's column by 0-based index.
+handlebars_helper!(field: |selection: object, index: u64| {
+    selection
+        .get("fields")
+        .and_then(|fields| fields.get(index as usize))
+        .and_then(|value| value.as_str())
+        .unwrap_or("")
+        .to_string()
+});
+
+handlebars_helper!(lower: |value: str| value.to_lowercase());
+handlebars_helper!(upper: |value: str| value.to_uppercase());
+handlebars_helper!(trim: |value: str| value.trim().to_string());
+handlebars_helper!(shellquote: |value: str| shell_quote(value));
+// For `{{#if (eq context.files.selection.type "FILE")}}…{{/if}}`.
+handlebars_helper!(eq: |left: Json, right: Json| left == right);
+handlebars_helper!(ne: |left: Json, right: Json| left != right);
+// `{{default <value> "fallback"}}`: the fallback when the value is missing or blank.
+handlebars_helper!(default: |value: Json, fallback: str| {
+    let value = match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    if value.trim().is_empty() {
+        fallback.to_string()
+    } else {
+        value
+    }
+});
+
+fn handlebars() -> Handlebars<'static> {
     let mut hb = Handlebars::new();
     hb.register_escape_fn(handlebars::no_escape);
+    hb.register_helper("field", Box::new(field));
+    hb.register_helper("lower", Box::new(lower));
+    hb.register_helper("upper", Box::new(upper));
+    hb.register_helper("trim", Box::new(trim));
+    hb.register_helper("shellquote", Box::new(shellquote));
+    hb.register_helper("default", Box::new(default));
+    hb.register_helper("eq", Box::new(eq));
+    hb.register_helper("ne", Box::new(ne));
+    hb
+}
 
-    let data = TemplateContext {
+fn template_context(frames: &[Frame]) -> TemplateContext {
+    TemplateContext {
         context: build_context(frames),
-    };
+        query: frames.last().map(|f| f.query.clone()).unwrap_or_default(),
+    }
+}
 
-    hb.render_template(template, &data)
+pub fn resolve_template(template: &str, frames: &[Frame]) -> Result<String, TemplateError> {
+    handlebars()
+        .render_template(template, &template_context(frames))
+        .map_err(|err| TemplateError::Render(err.to_string()))
+}
+
+/// A template compiled once and rendered per item, with each item standing in
+/// for the top frame's selection. For per-item filters over whole lists.
+pub struct ItemTemplate {
+    hb: Handlebars<'static>,
+    data: TemplateContext,
+    spell_id: String,
+}
+
+impl ItemTemplate {
+    pub fn new(template: &str, frames: &[Frame]) -> Result<Self, TemplateError> {
+        let mut hb = handlebars();
+        hb.register_template_string(ITEM_TEMPLATE_NAME, template)
+            .map_err(|err| TemplateError::Render(err.to_string()))?;
+        Ok(Self {
+            hb,
+            data: template_context(frames),
+            spell_id: frames
+                .last()
+                .map(|f| f.spell_id.clone())
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn render(&mut self, item: &Item) -> Result<String, TemplateError> {
+        let frame = self
+            .data
+            .context
+            .entry(self.spell_id.clone())
+            .or_insert_with(|| FrameContext {
+                selection: SelectionContext::from_item(None),
+                query: String::new(),
+                spell_id: self.spell_id.clone(),
+            });
+        frame.selection = SelectionContext::from_item(Some(item));
+        self.hb
+            .render(ITEM_TEMPLATE_NAME, &self.data)
+            .map_err(|err| TemplateError::Render(err.to_string()))
+    }
+}
+
+/// Compiles `template` without rendering it, to catch syntax errors early.
+pub fn check_template(template: &str) -> Result<(), TemplateError> {
+    Handlebars::new()
+        .register_template_string("check", template)
         .map_err(|err| TemplateError::Render(err.to_string()))
 }
 
@@ -100,14 +198,23 @@ fn build_context(frames: &[Frame]) -> HashMap<String, FrameContext> {
     ctx
 }
 
-fn selected_item(frame: &Frame) -> Option<&Item> {
+// Always single-quoted so the value stays one token through `shell_words::split`.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// `None` while the list is empty or only has headers and placeholders.
+pub fn selected_item(frame: &Frame) -> Option<&Item> {
     if frame.filtered_items.is_empty() {
         return None;
     }
     let idx = frame
         .selected_idx
         .min(frame.filtered_items.len().saturating_sub(1));
-    frame.filtered_items.get(idx)
+    frame
+        .filtered_items
+        .get(idx)
+        .filter(|item| item.is_selectable())
 }
 
 #[cfg(test)]
@@ -127,7 +234,13 @@ mod tests {
             all_items: parsed_items.clone(),
             filtered_items: parsed_items,
             is_filtering: false,
+            is_loading_more: false,
             selected_idx,
+            selected_set: Default::default(),
+            scope_idx: 0,
+            load_cancelled: tokio::sync::watch::Sender::new(false),
+            reload_error: None,
+            type_counts: HashMap::new(),
         }
     }
 
@@ -158,6 +271,37 @@ mod tests {
         assert_eq!(out, "APP == 'APP'");
     }
 
+    #[test]
+    fn resolves_top_frame_query() {
+        let frames = vec![
+            frame("quickspell", Vec::new(), 0, "files"),
+            frame("search_files", Vec::new(), 0, "notes"),
+        ];
+
+        let out = resolve_template("No results for {{query}}", &frames).unwrap();
+        assert_eq!(out, "No results for notes");
+    }
+
+    #[test]
+    fn item_template_renders_each_item_as_the_selection() {
+        let frames = vec![frame("files", vec!["DIR\tsrc\t/repo/src"], 0, "")];
+        let mut template =
+            ItemTemplate::new("{{context.files.selection.type}}:{{query}}", &frames).unwrap();
+
+        let file = Item::from_line("FILE\tmain.rs\t/repo/main.rs").unwrap();
+        assert_eq!(template.render(&file).unwrap(), "FILE:");
+        let dir = Item::from_line("DIR\tdocs\t/repo/docs").unwrap();
+        assert_eq!(template.render(&dir).unwrap(), "DIR:");
+    }
+
+    #[test]
+    fn check_template_reports_syntax_errors() {
+        assert!(check_template("open {{shellquote context.files.selection.data}}").is_ok());
+        assert!(check_template("{{#if (eq a \"b\")}}x{{/if}}").is_ok());
+        assert!(check_template("open {{context.files.selection.data").is_err());
+        assert!(check_template("{{#if a}}x").is_err());
+    }
+
     #[test]
     fn handles_missing_selection() {
         let frames = vec![frame("search_files", Vec::new(), 0, "")];
@@ -166,6 +310,120 @@ mod tests {
         assert_eq!(out, "");
     }
 
+    #[test]
+    fn resolves_field_by_index() {
+        let frames = vec![frame(
+            "search_files",
+            vec!["FILE\t[F] notes.txt\t/Users/me/notes.txt"],
+            0,
+            "",
+        )];
+
+        let out = resolve_template(
+            "{{field context.search_files.selection 0}}:{{field context.search_files.selection 2}}",
+            &frames,
+        )
+        .unwrap();
+        assert_eq!(out, "FILE:/Users/me/notes.txt");
+
+        let missing =
+            resolve_template("{{field context.search_files.selection 7}}", &frames).unwrap();
+        assert_eq!(missing, "");
+    }
+
+    #[test]
+    fn exposes_extra_columns_as_fields() {
+        let frames = vec![frame(
+            "search_files",
+            vec!["FILE\tnotes.txt\t/Users/me/notes.txt\t2 KB\t2024-05-01"],
+            0,
+            "",
+        )];
+
+        let out = resolve_template(
+            "{{field context.search_files.selection 3}} {{context.search_files.selection.fields.[4]}}",
+            &frames,
+        )
+        .unwrap();
+        assert_eq!(out, "2 KB 2024-05-01");
+
+        let raw = resolve_template("{{context.search_files.selection.raw}}", &frames).unwrap();
+        assert_eq!(
+            raw,
+            "FILE\tnotes.txt\t/Users/me/notes.txt\t2 KB\t2024-05-01"
+        );
+    }
+
+    #[test]
+    fn resolves_case_helpers() {
+        let frames = vec![frame(
+            "quickspell",
+            vec!["APP\tVisual Studio Code\tcode"],
+            0,
+            "",
+        )];
+
+        let out = resolve_template("{{lower context.quickspell.selection.label}}", &frames);
+        assert_eq!(out.unwrap(), "visual studio code");
+
+        let out = resolve_template("{{upper context.quickspell.selection.data}}", &frames);
+        assert_eq!(out.unwrap(), "CODE");
+    }
+
+    #[test]
+    fn resolves_trim_helper() {
+        let frames = vec![frame("quickspell", vec!["APP\t  Notes \tnotes"], 0, "")];
+
+        let out = resolve_template("[{{trim context.quickspell.selection.label}}]", &frames);
+        assert_eq!(out.unwrap(), "[Notes]");
+    }
+
+    #[test]
+    fn default_helper_replaces_blank_values() {
+        let template = r#"{{default context.files.selection.data "none"}}"#;
+        for (line, expected) in [
+            ("FILE	x	/tmp/a", "/tmp/a"),
+            ("FILE	x	", "none"),
+            ("FILE	x	   ", "none"),
+        ] {
+            let frames = vec![frame("files", vec![line], 0, "")];
+            assert_eq!(resolve_template(template, &frames).unwrap(), expected);
+        }
+
+        let empty = vec![frame("files", Vec::new(), 0, "")];
+        assert_eq!(resolve_template(template, &empty).unwrap(), "none");
+        let missing = resolve_template(r#"{{default context.nope.query "none"}}"#, &empty);
+        assert_eq!(missing.unwrap(), "none");
+    }
+
+    #[test]
+    fn eq_and_ne_drive_if_blocks() {
+        let template = r#"{{#if (eq context.files.selection.type "FILE")}}open {{else}}cd {{/if}}{{context.files.selection.data}}"#;
+        let file = vec![frame("files", vec!["FILE	x	/tmp/a&b <c>"], 0, "")];
+        assert_eq!(
+            resolve_template(template, &file).unwrap(),
+            "open /tmp/a&b <c>"
+        );
+        let dir = vec![frame("files", vec!["DIR	x	/tmp/d"], 0, "")];
+        assert_eq!(resolve_template(template, &dir).unwrap(), "cd /tmp/d");
+
+        let negated = r#"{{#if (ne context.files.selection.type "FILE")}}dir{{else}}file{{/if}}"#;
+        assert_eq!(resolve_template(negated, &dir).unwrap(), "dir");
+        assert_eq!(resolve_template(negated, &file).unwrap(), "file");
+    }
+
+    #[test]
+    fn shellquote_keeps_untrusted_data_one_token() {
+        for data in ["my notes.txt", "it's here", "$(rm -rf ~)", "a'b\"c d"] {
+            let line = format!("FILE\tx\t{data}");
+            let frames = vec![frame("files", vec![line.as_str()], 0, "")];
+
+            let out = resolve_template("open {{shellquote context.files.selection.data}}", &frames);
+            let argv = shell_words::split(&out.unwrap()).unwrap();
+            assert_eq!(argv, vec!["open", data]);
+        }
+    }
+
     #[test]
     fn resolves_multi_frame_context() {
         let frames = vec![
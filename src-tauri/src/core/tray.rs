@@ -0,0 +1,52 @@
+use crate::api::types::AppStatus;
+
+const APP_NAME: &str = "QuickSpell";
+const ERROR_TOOLTIP_LIMIT: usize = 100;
+
+/// Tray tooltip for `status`; errors include their (shortened) message.
+pub fn tooltip(status: AppStatus, error: Option<&str>) -> String {
+    match status {
+        AppStatus::NotStarted | AppStatus::Booting => format!("{APP_NAME} — Starting"),
+        AppStatus::Loading => format!("{APP_NAME} — Loading"),
+        AppStatus::Ready => APP_NAME.to_string(),
+        AppStatus::Error => match error.map(str::trim).filter(|e| !e.is_empty()) {
+            Some(error) if error.chars().count() > ERROR_TOOLTIP_LIMIT => {
+                let short: String = error.chars().take(ERROR_TOOLTIP_LIMIT).collect();
+                format!("{APP_NAME} — Error: {short}…")
+            }
+            Some(error) => format!("{APP_NAME} — Error: {error}"),
+            None => format!("{APP_NAME} — Error"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tooltip_reflects_each_status() {
+        assert_eq!(
+            tooltip(AppStatus::NotStarted, None),
+            "QuickSpell — Starting"
+        );
+        assert_eq!(tooltip(AppStatus::Booting, None), "QuickSpell — Starting");
+        assert_eq!(tooltip(AppStatus::Loading, None), "QuickSpell — Loading");
+        assert_eq!(tooltip(AppStatus::Ready, Some("stale")), "QuickSpell");
+        assert_eq!(
+            tooltip(AppStatus::Error, Some("provider failed")),
+            "QuickSpell — Error: provider failed"
+        );
+        assert_eq!(tooltip(AppStatus::Error, Some(" ")), "QuickSpell — Error");
+    }
+
+    #[test]
+    fn long_errors_are_shortened() {
+        let tooltip = tooltip(AppStatus::Error, Some(&"x".repeat(300)));
+        assert!(tooltip.ends_with('…'));
+        assert_eq!(
+            tooltip.chars().count(),
+            "QuickSpell — Error: ".chars().count() + 101
+        );
+    }
+}
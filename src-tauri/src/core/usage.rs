@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+pub const USAGE_FILE_NAME: &str = "usage.json";
+
+const MAX_FREQUENCY_BONUS: u32 = 16;
+const RECENT_SPELLS_LIMIT: usize = 20;
+const HOUR_SECS: u64 = 60 * 60;
+const DAY_SECS: u64 = 24 * HOUR_SECS;
+const WEEK_SECS: u64 = 7 * DAY_SECS;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageEntry {
+    pub count: u32,
+    pub last_used: u64, // unix seconds
+}
+
+/// Launch counts keyed by `spell_id` + item data, plus the recently opened
+/// spells, persisted as JSON.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageStore {
+    #[serde(default)]
+    entries: HashMap<String, UsageEntry>,
+    /// Spell ids, most recently opened first.
+    #[serde(default)]
+    recent_spells: Vec<String>,
+}
+
+impl UsageStore {
+    pub fn load(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                eprintln!("ignoring malformed usage file {}: {err}", path.display());
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let content = serde_json::to_string(self)
+            .map_err(|err| format!("failed to serialize usage: {err}"))?;
+        fs::write(path, content)
+            .map_err(|err| format!("failed to write usage file {}: {err}", path.display()))
+    }
+
+    pub fn record(&mut self, spell_id: &str, data: &str, now: u64) {
+        let entry = self.entries.entry(usage_key(spell_id, data)).or_default();
+        entry.count = entry.count.saturating_add(1);
+        entry.last_used = now;
+    }
+
+    /// Moves `spell_id` to the front of the recent spells.
+    pub fn record_spell(&mut self, spell_id: &str) {
+        self.recent_spells.retain(|id| id != spell_id);
+        self.recent_spells.insert(0, spell_id.to_string());
+        self.recent_spells.truncate(RECENT_SPELLS_LIMIT);
+    }
+
+    pub fn recent_spells(&self) -> &[String] {
+        &self.recent_spells
+    }
+
+    /// Ranking bonus per item data for one spell; items never launched are absent.
+    pub fn bonuses_for(&self, spell_id: &str, now: u64) -> HashMap<String, u32> {
+        let prefix = usage_key(spell_id, "");
+        self.entries
+            .iter()
+            .filter_map(|(key, entry)| {
+                key.strip_prefix(&prefix)
+                    .map(|data| (data.to_string(), usage_bonus(entry, now)))
+            })
+            .collect()
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn usage_key(spell_id: &str, data: &str) -> String {
+    format!("{spell_id}\t{data}")
+}
+
+// Small enough to only reorder matches of similar quality.
+fn usage_bonus(entry: &UsageEntry, now: u64) -> u32 {
+    let frequency = entry.count.min(MAX_FREQUENCY_BONUS);
+    let recency = match now.saturating_sub(entry.last_used) {
+        age if age <= HOUR_SECS => 8,
+        age if age <= DAY_SECS => 4,
+        age if age <= WEEK_SECS => 2,
+        _ => 0,
+    };
+    frequency + recency
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bonuses_are_scoped_to_spell_and_grow_with_use() {
+        let mut store = UsageStore::default();
+        store.record("apps", "/Applications/Safari.app", 1_000);
+        store.record("apps", "/Applications/Safari.app", 1_000);
+        store.record("apps", "/Applications/Mail.app", 1_000);
+        store.record("files", "/tmp/notes.txt", 1_000);
+
+        let bonuses = store.bonuses_for("apps", 1_000);
+        assert_eq!(bonuses.len(), 2);
+        assert!(bonuses["/Applications/Safari.app"] > bonuses["/Applications/Mail.app"]);
+    }
+
+    #[test]
+    fn recency_bonus_decays() {
+        let entry = UsageEntry {
+            count: 1,
+            last_used: 0,
+        };
+        assert!(usage_bonus(&entry, 60) > usage_bonus(&entry, 2 * DAY_SECS));
+        assert_eq!(usage_bonus(&entry, 30 * DAY_SECS), 1);
+    }
+
+    #[test]
+    fn recent_spells_are_deduplicated_and_capped() {
+        let mut store = UsageStore::default();
+        for i in 0..RECENT_SPELLS_LIMIT + 5 {
+            store.record_spell(&format!("spell-{i}"));
+        }
+        store.record_spell("spell-10");
+
+        let recent = store.recent_spells();
+        assert_eq!(recent.len(), RECENT_SPELLS_LIMIT);
+        assert_eq!(recent[0], "spell-10");
+        assert_eq!(recent[1], "spell-24");
+        assert_eq!(recent.iter().filter(|id| *id == "spell-10").count(), 1);
+    }
+
+    #[test]
+    fn round_trips_through_file() {
+        let path =
+            std::env::temp_dir().join(format!("quickspell-usage-{}.json", std::process::id()));
+        let mut store = UsageStore::default();
+        store.record("apps", "Safari", 5);
+        store.record_spell("apps");
+        store.save(&path).unwrap();
+
+        let loaded = UsageStore::load(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded.bonuses_for("apps", 5), store.bonuses_for("apps", 5));
+        assert_eq!(loaded.recent_spells(), ["apps"]);
+    }
+}
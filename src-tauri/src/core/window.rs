@@ -0,0 +1,185 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::settings::Settings;
+
+pub const WINDOW_STATE_FILE_NAME: &str = "window.json";
+pub const DEFAULT_ROW_HEIGHT: u32 = 44;
+
+/// Search box, breadcrumb and padding around the rows, in logical pixels.
+const AUTO_HEIGHT_CHROME: u32 = 96;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowGeometry {
+    pub width: u32,
+    pub height: u32,
+    /// `None` leaves the window where the platform put it.
+    pub position: Option<(i32, i32)>,
+}
+
+/// Configured size (half the screen when unset), clamped to the monitor.
+pub fn initial_geometry(
+    settings: &Settings,
+    screen_width: u32,
+    screen_height: u32,
+) -> WindowGeometry {
+    let width = settings
+        .window_width
+        .unwrap_or(screen_width / 2)
+        .clamp(1, screen_width.max(1));
+    let height = settings
+        .window_height
+        .unwrap_or(screen_height / 2)
+        .clamp(1, screen_height.max(1));
+
+    let position = settings.window_center.unwrap_or(true).then(|| {
+        (
+            i32::try_from(screen_width.saturating_sub(width) / 2).unwrap_or(0),
+            i32::try_from(screen_height.saturating_sub(height) / 2).unwrap_or(0),
+        )
+    });
+
+    WindowGeometry {
+        width,
+        height,
+        position,
+    }
+}
+
+/// Height that fits `rows` result rows (at least one), capped at `max_height`.
+pub fn height_for_rows(rows: usize, row_height: u32, max_height: u32) -> u32 {
+    let rows = u32::try_from(rows.max(1)).unwrap_or(u32::MAX);
+    let min_height = AUTO_HEIGHT_CHROME.saturating_add(row_height);
+    AUTO_HEIGHT_CHROME
+        .saturating_add(rows.saturating_mul(row_height))
+        .clamp(min_height.min(max_height), max_height)
+}
+
+/// Outer position and size saved when the window hides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MonitorBounds {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl MonitorBounds {
+    fn contains(&self, x: i32, y: i32) -> bool {
+        let (x, y) = (i64::from(x), i64::from(y));
+        let (left, top) = (i64::from(self.x), i64::from(self.y));
+        x >= left
+            && y >= top
+            && x < left + i64::from(self.width)
+            && y < top + i64::from(self.height)
+    }
+}
+
+impl SavedGeometry {
+    pub fn load(path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let content = serde_json::to_string(self)
+            .map_err(|err| format!("failed to serialize window geometry: {err}"))?;
+        fs::write(path, content)
+            .map_err(|err| format!("failed to write window state {}: {err}", path.display()))
+    }
+
+    /// False when the saved top-left corner is on no connected monitor,
+    /// e.g. after unplugging an external display.
+    pub fn is_visible_on(&self, monitors: &[MonitorBounds]) -> bool {
+        monitors
+            .iter()
+            .any(|monitor| monitor.contains(self.x, self.y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_to_centered_half_screen() {
+        let geometry = initial_geometry(&Settings::default(), 2000, 1000);
+        assert_eq!(
+            geometry,
+            WindowGeometry {
+                width: 1000,
+                height: 500,
+                position: Some((500, 250)),
+            }
+        );
+    }
+
+    #[test]
+    fn clamps_configured_size_to_monitor() {
+        let settings = Settings {
+            window_width: Some(5000),
+            window_height: Some(0),
+            window_center: Some(true),
+            ..Settings::default()
+        };
+        let geometry = initial_geometry(&settings, 3440, 1440);
+        assert_eq!((geometry.width, geometry.height), (3440, 1));
+        assert_eq!(geometry.position, Some((0, 719)));
+    }
+
+    #[test]
+    fn height_fits_rows_between_one_row_and_the_maximum() {
+        assert_eq!(height_for_rows(2, 40, 600), AUTO_HEIGHT_CHROME + 80);
+        assert_eq!(height_for_rows(0, 40, 600), AUTO_HEIGHT_CHROME + 40);
+        assert_eq!(height_for_rows(100, 40, 600), 600);
+        assert_eq!(height_for_rows(1, 40, 50), 50);
+    }
+
+    #[test]
+    fn saved_geometry_must_land_on_a_connected_monitor() {
+        let laptop = MonitorBounds {
+            x: 0,
+            y: 0,
+            width: 1512,
+            height: 982,
+        };
+        let external = MonitorBounds {
+            x: 1512,
+            y: -200,
+            width: 2560,
+            height: 1440,
+        };
+        let saved = SavedGeometry {
+            x: 2000,
+            y: 100,
+            width: 800,
+            height: 400,
+        };
+
+        assert!(saved.is_visible_on(&[laptop, external]));
+        assert!(!saved.is_visible_on(&[laptop]));
+        assert!(!saved.is_visible_on(&[]));
+    }
+
+    #[test]
+    fn skips_positioning_when_not_centered() {
+        let settings = Settings {
+            window_width: Some(900),
+            window_center: Some(false),
+            ..Settings::default()
+        };
+        let geometry = initial_geometry(&settings, 3440, 1440);
+        assert_eq!((geometry.width, geometry.height), (900, 720));
+        assert_eq!(geometry.position, None);
+    }
+}
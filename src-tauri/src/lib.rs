@@ -2,6 +2,7 @@ mod api;
 mod core;
 
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
 use tauri::{
     menu::MenuBuilder, tray::TrayIconBuilder, ActivationPolicy, AppHandle, Manager, RunEvent,
@@ -9,16 +10,22 @@ use tauri::{
 };
 #[cfg(desktop)]
 use tauri_plugin_global_shortcut::{Builder as ShortcutBuilder, ShortcutState};
+use tauri_plugin_opener::OpenerExt;
 
-use api::types::AppState;
+use crate::core::window::{MonitorBounds, SavedGeometry};
+use api::types::{AppState, AppStatus};
 
 const MAIN_WINDOW_LABEL: &str = "main";
 const MAIN_TRAY_ID: &str = "main-tray";
 const TRAY_MENU_SHOW: &str = "tray-show";
+const TRAY_MENU_PIN: &str = "tray-pin";
+const TRAY_MENU_SPELLS: &str = "tray-spells";
 const TRAY_MENU_QUIT: &str = "tray-quit";
 const GLOBAL_HOTKEY_TOGGLE: &str = "ctrl+space";
 
 static ALLOW_APP_EXIT: AtomicBool = AtomicBool::new(false);
+static PINNED: AtomicBool = AtomicBool::new(false);
+static TRAY_TOOLTIP: Mutex<String> = Mutex::new(String::new());
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
@@ -39,25 +46,26 @@ pub fn run() {
                 app.set_activation_policy(ActivationPolicy::Accessory);
                 app.set_dock_visibility(false);
             }
+            let settings = core::app::load_settings(app.handle());
+            app.state::<AppState>().set_settings(settings.clone());
             #[cfg(desktop)]
             if let Some(window) = app.get_webview_window(MAIN_WINDOW_LABEL) {
-                if let Some(monitor) = app.primary_monitor()? {
+                let restored = restore_window_geometry(app.handle());
+                if let Some(monitor) = app.primary_monitor()?.filter(|_| !restored) {
                     let screen = monitor.size();
-                    let target_width = screen.width / 2;
-                    let target_height = screen.height / 2;
+                    let geometry =
+                        core::window::initial_geometry(&settings, screen.width, screen.height);
 
                     let _ = window.set_size(tauri::Size::Physical(tauri::PhysicalSize::new(
-                        target_width,
-                        target_height,
+                        geometry.width,
+                        geometry.height,
                     )));
 
-                    let pos_x =
-                        i32::try_from((screen.width.saturating_sub(target_width)) / 2).unwrap_or(0);
-                    let pos_y = i32::try_from((screen.height.saturating_sub(target_height)) / 2)
-                        .unwrap_or(0);
-                    let _ = window.set_position(tauri::Position::Physical(
-                        tauri::PhysicalPosition::new(pos_x, pos_y),
-                    ));
+                    if let Some((pos_x, pos_y)) = geometry.position {
+                        let _ = window.set_position(tauri::Position::Physical(
+                            tauri::PhysicalPosition::new(pos_x, pos_y),
+                        ));
+                    }
                 }
             }
             #[cfg(desktop)]
@@ -82,12 +90,21 @@ pub fn run() {
             }
 
             match event {
+                // Pinned windows stay open until unpinned.
+                WindowEvent::CloseRequested { api, .. } if PINNED.load(Ordering::Relaxed) => {
+                    api.prevent_close();
+                }
+                WindowEvent::Focused(false) if PINNED.load(Ordering::Relaxed) => {}
                 WindowEvent::CloseRequested { api, .. } => {
+                    save_window_geometry(window.app_handle());
+                    clear_on_hide(window.app_handle());
                     let _ = window.hide();
                     api.prevent_close();
                     update_tray_menu(window.app_handle(), false);
                 }
                 WindowEvent::Focused(false) => {
+                    save_window_geometry(window.app_handle());
+                    clear_on_hide(window.app_handle());
                     let _ = window.hide();
                     update_tray_menu(window.app_handle(), false);
                 }
@@ -96,11 +113,37 @@ pub fn run() {
         })
         .invoke_handler(tauri::generate_handler![
             api::commands::get_state_snapshot,
+            api::commands::get_metrics_summary,
+            api::commands::get_items_page,
             api::commands::start_app,
+            api::commands::reload_spells,
+            api::commands::refresh,
+            api::commands::cancel_loading,
             api::commands::set_query,
+            api::commands::set_query_immediate,
+            api::commands::clear_query,
             api::commands::set_selection_delta,
+            api::commands::set_selection_index,
+            api::commands::set_scope,
+            api::commands::dispatch,
+            api::commands::toggle_mark,
+            api::commands::set_selection_page,
+            api::commands::list_actions,
+            api::commands::list_spells,
+            api::commands::set_spell_enabled,
             api::commands::invoke_action,
+            api::commands::confirm_action,
+            api::commands::invoke_action_blocking,
+            api::commands::preview_action,
+            api::commands::get_preview,
             api::commands::handle_escape,
+            api::commands::push_spell,
+            api::commands::pop_frame,
+            api::commands::reset_to_root,
+            api::commands::set_pinned,
+            api::commands::resize_to_rows,
+            api::commands::get_settings,
+            api::commands::update_settings,
         ])
         .build(tauri::generate_context!())
         .expect("error while building tauri application");
@@ -117,13 +160,18 @@ pub fn run() {
 fn setup_tray(app: &tauri::App) -> tauri::Result<()> {
     let tray_menu = MenuBuilder::new(app)
         .text(TRAY_MENU_SHOW, "Show")
+        .text(TRAY_MENU_PIN, pin_menu_text())
+        .text(TRAY_MENU_SPELLS, "Open Spells Folder")
         .text(TRAY_MENU_QUIT, "Quit")
         .build()?;
 
     let mut tray_builder = TrayIconBuilder::with_id(MAIN_TRAY_ID)
         .menu(&tray_menu)
+        .tooltip(core::tray::tooltip(AppStatus::NotStarted, None))
         .on_menu_event(|app, event| match event.id.as_ref() {
             TRAY_MENU_SHOW => toggle_main_window(app),
+            TRAY_MENU_PIN => set_pinned(app, !PINNED.load(Ordering::Relaxed)),
+            TRAY_MENU_SPELLS => open_spells_folder(app),
             TRAY_MENU_QUIT => {
                 ALLOW_APP_EXIT.store(true, Ordering::Relaxed);
                 app.exit(0);
@@ -140,11 +188,20 @@ fn setup_tray(app: &tauri::App) -> tauri::Result<()> {
     Ok(())
 }
 
+fn open_spells_folder(app: &AppHandle) {
+    let dir = core::app::resolve_spells_folder(app);
+    if let Err(err) = app.opener().open_path(dir.to_string_lossy(), None::<&str>) {
+        eprintln!("failed to open spells folder {}: {err}", dir.display());
+    }
+}
+
 fn update_tray_menu(app: &AppHandle, is_visible: bool) {
     if let Some(tray) = app.tray_by_id(MAIN_TRAY_ID) {
         let show_text = if is_visible { "Hide" } else { "Show" };
         if let Ok(menu) = MenuBuilder::new(app)
             .text(TRAY_MENU_SHOW, show_text)
+            .text(TRAY_MENU_PIN, pin_menu_text())
+            .text(TRAY_MENU_SPELLS, "Open Spells Folder")
             .text(TRAY_MENU_QUIT, "Quit")
             .build()
         {
@@ -153,14 +210,80 @@ fn update_tray_menu(app: &AppHandle, is_visible: bool) {
     }
 }
 
+/// Shows the app status in the tray tooltip; called with every snapshot.
+pub(crate) fn update_tray_status(app: &AppHandle, status: AppStatus, error: Option<&str>) {
+    let tooltip = core::tray::tooltip(status, error);
+    let Ok(mut current) = TRAY_TOOLTIP.lock() else {
+        return;
+    };
+    if *current == tooltip {
+        return;
+    }
+    if let Some(tray) = app.tray_by_id(MAIN_TRAY_ID) {
+        let _ = tray.set_tooltip(Some(&tooltip));
+        *current = tooltip;
+    }
+}
+
+fn pin_menu_text() -> &'static str {
+    if PINNED.load(Ordering::Relaxed) {
+        "Unpin"
+    } else {
+        "Pin"
+    }
+}
+
+/// While pinned, the main window no longer hides on blur or close.
+pub(crate) fn set_pinned(app: &AppHandle, pinned: bool) {
+    PINNED.store(pinned, Ordering::Relaxed);
+    let is_visible = app
+        .get_webview_window(MAIN_WINDOW_LABEL)
+        .and_then(|window| window.is_visible().ok())
+        .unwrap_or(false);
+    update_tray_menu(app, is_visible);
+}
+
+/// With `auto_height`, fits the main window to `rows` result rows, keeping its
+/// width and never growing past the configured height.
+pub(crate) fn resize_to_rows(app: &AppHandle, rows: usize) {
+    let settings = app.state::<AppState>().settings();
+    if !settings.auto_height.unwrap_or(false) {
+        return;
+    }
+    let Some(window) = app.get_webview_window(MAIN_WINDOW_LABEL) else {
+        return;
+    };
+    let (Ok(Some(monitor)), Ok(size)) = (window.current_monitor(), window.inner_size()) else {
+        return;
+    };
+    let scale = monitor.scale_factor();
+    let screen = monitor.size();
+    let max_height = core::window::initial_geometry(&settings, screen.width, screen.height).height;
+    let height = core::window::height_for_rows(
+        rows,
+        settings
+            .row_height
+            .unwrap_or(core::window::DEFAULT_ROW_HEIGHT),
+        (f64::from(max_height) / scale) as u32,
+    );
+    let width = size.to_logical::<f64>(scale).width;
+    let _ = window.set_size(tauri::Size::Logical(tauri::LogicalSize::new(
+        width,
+        f64::from(height),
+    )));
+}
+
 fn toggle_main_window(app: &AppHandle) {
     if let Some(window) = app.get_webview_window(MAIN_WINDOW_LABEL) {
         match window.is_visible() {
             Ok(true) => {
+                save_window_geometry(app);
+                clear_on_hide(app);
                 let _ = window.hide();
                 update_tray_menu(app, false);
             }
             _ => {
+                restore_window_geometry(app);
                 let _ = window.show();
                 let _ = window.set_focus();
                 update_tray_menu(app, true);
@@ -168,3 +291,87 @@ fn toggle_main_window(app: &AppHandle) {
         }
     }
 }
+
+// With the `clear_on_hide` setting, the next show starts from a fresh root frame.
+fn clear_on_hide(app: &AppHandle) {
+    let state = app.state::<AppState>();
+    if !state.settings().clear_on_hide.unwrap_or(false) {
+        return;
+    }
+    match state.collapse_to_root() {
+        Ok(()) => {
+            let _ = api::events::emit_state_snapshot(app, state.snapshot());
+        }
+        Err(err) => eprintln!("failed to reset on hide: {err}"),
+    }
+}
+
+fn restores_window_geometry(app: &AppHandle) -> bool {
+    app.state::<AppState>()
+        .settings()
+        .restore_window_geometry
+        .unwrap_or(false)
+}
+
+fn save_window_geometry(app: &AppHandle) {
+    if !restores_window_geometry(app) {
+        return;
+    }
+    let Some(window) = app.get_webview_window(MAIN_WINDOW_LABEL) else {
+        return;
+    };
+    let (Ok(position), Ok(size)) = (window.outer_position(), window.outer_size()) else {
+        return;
+    };
+
+    let geometry = SavedGeometry {
+        x: position.x,
+        y: position.y,
+        width: size.width,
+        height: size.height,
+    };
+    let result = core::app::resolve_window_state_path(app).and_then(|path| geometry.save(&path));
+    if let Err(err) = result {
+        eprintln!("failed to save window geometry: {err}");
+    }
+}
+
+// Falls back (returns false) when nothing was saved or its monitor is gone.
+fn restore_window_geometry(app: &AppHandle) -> bool {
+    if !restores_window_geometry(app) {
+        return false;
+    }
+    let Some(window) = app.get_webview_window(MAIN_WINDOW_LABEL) else {
+        return false;
+    };
+    let Some(geometry) = core::app::resolve_window_state_path(app)
+        .ok()
+        .and_then(|path| SavedGeometry::load(&path))
+    else {
+        return false;
+    };
+
+    let monitors: Vec<MonitorBounds> = window
+        .available_monitors()
+        .unwrap_or_default()
+        .iter()
+        .map(|monitor| MonitorBounds {
+            x: monitor.position().x,
+            y: monitor.position().y,
+            width: monitor.size().width,
+            height: monitor.size().height,
+        })
+        .collect();
+    if !geometry.is_visible_on(&monitors) {
+        return false;
+    }
+
+    let _ = window.set_size(tauri::Size::Physical(tauri::PhysicalSize::new(
+        geometry.width,
+        geometry.height,
+    )));
+    let _ = window.set_position(tauri::Position::Physical(tauri::PhysicalPosition::new(
+        geometry.x, geometry.y,
+    )));
+    true
+}
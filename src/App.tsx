@@ -1,7 +1,13 @@
 import React, { useCallback, useEffect, useMemo, useRef, useState } from "react";
 import { invoke } from "@tauri-apps/api/core";
-import type { Item as SpellItem, StateSnapshot } from "./events";
-import { listenEvent } from "./events";
+import type {
+  ActionOutcome,
+  FrameSummary,
+  Item as SpellItem,
+  StateSnapshot,
+  UiAction,
+} from "./events";
+import { HEADER_ITEM_TYPE, listenEvent } from "./events";
 import { useOsTheme } from "./hooks/use-os-theme";
 import {
   Breadcrumb,
@@ -18,25 +24,53 @@ import { usePaginationLayout } from "./hooks/use-pagination-layout";
 import { cn } from "./lib/utils";
 import { Spinner } from "./components/ui/spinner";
 
+// Mirrors TOP_ITEMS_LIMIT in the backend.
+const TOP_ITEMS_LIMIT = 100;
+
 const DEFAULT_SNAPSHOT: StateSnapshot = {
   status: "loading",
   noOfSpells: 0,
   totalItems: 0,
   spellNames: [],
+  frames: [],
   topItems: [],
   query: "",
   isFiltering: false,
   selectedItem: null,
+  errorMessage: null,
+  spellErrors: [],
+  preview: null,
+  isLoadingMore: false,
+  markedIndices: [],
+  emptyMessage: null,
+  selectedScore: null,
+  scopes: [],
+  activeScope: null,
+  typeCounts: {},
+  queryError: null,
+  reloadError: null,
+  visibleRows: null,
 };
 
+function frameTitle(frame: FrameSummary | undefined): string | undefined {
+  if (!frame) return undefined;
+  const count = `${frame.itemCount.toLocaleString()} items`;
+  return frame.query ? `"${frame.query}" · ${count}` : count;
+}
+
 function App() {
   const [snapshot, setSnapshot] = useState<StateSnapshot>(DEFAULT_SNAPSHOT);
   const [isActionsOpen, setIsActionsOpen] = useState(false);
   const [dialogItem, setDialogItem] = useState<SpellItem | null>(null);
   const [actionQuery, setActionQuery] = useState("");
   const [actionIndex, setActionIndex] = useState(0);
+  const [pendingConfirm, setPendingConfirm] = useState<{ label: string; prompt: string } | null>(
+    null
+  );
   const searchRef = useRef<HTMLInputElement | null>(null);
   const actionSearchRef = useRef<HTMLInputElement | null>(null);
+  // Set by onPaste so the following onChange filters without debounce.
+  const pastedRef = useRef(false);
 
   useOsTheme();
   const { containerRef, measureItemRef, pageSize } = usePaginationLayout({
@@ -77,10 +111,27 @@ function App() {
 
     const bootstrap = async () => {
       try {
-        const unlisten = await listenEvent("state-snapshot", (payload) => {
+        const unlistenSnapshot = await listenEvent("state-snapshot", (payload) => {
           setSnapshot(payload);
         });
-        cleanup = unlisten;
+        const unlistenAppended = await listenEvent("items-appended", ({ frameId, items }) => {
+          setSnapshot((prev) => {
+            // A batch from a frame that was since popped or replaced.
+            if (prev.frames[prev.frames.length - 1]?.id !== frameId) return prev;
+            return {
+              ...prev,
+              topItems:
+                prev.topItems.length < TOP_ITEMS_LIMIT
+                  ? [...prev.topItems, ...items].slice(0, TOP_ITEMS_LIMIT)
+                  : prev.topItems,
+              totalItems: prev.totalItems + items.length,
+            };
+          });
+        });
+        cleanup = () => {
+          unlistenSnapshot();
+          unlistenAppended();
+        };
 
         await invoke("start_app");
 
@@ -113,15 +164,24 @@ function App() {
     });
   }, [filteredActions]);
 
+  // Actions with a `confirm` prompt come back unrun; ask before confirming them.
+  const runAction = useCallback((label: string) => {
+    return invoke<ActionOutcome>("invoke_action", { label }).then((outcome) => {
+      if (outcome.needsConfirm) {
+        setPendingConfirm({ label, prompt: outcome.prompt ?? label });
+      }
+    });
+  }, []);
+
   const invokeOptionalAction = useCallback(
     (action?: (typeof filteredActions)[number]) => {
       if (!action) return;
       closeActionsDialog();
-      void invoke("invoke_action", { label: action.label }).catch((err) => {
+      void runAction(action.label).catch((err) => {
         console.error("failed to invoke optional action", err);
       });
     },
-    [closeActionsDialog, filteredActions]
+    [closeActionsDialog, filteredActions, runAction]
   );
 
   useEffect(() => {
@@ -134,8 +194,30 @@ function App() {
     searchRef.current?.focus();
   }, []);
 
+  useEffect(() => {
+    if (snapshot.visibleRows === null) return;
+    invoke("resize_to_rows", { rows: snapshot.visibleRows }).catch((err) => {
+      console.error("failed to resize window", err);
+    });
+  }, [snapshot.visibleRows]);
+
   useEffect(() => {
     const handleKeyDown = (e: KeyboardEvent) => {
+      if (pendingConfirm) {
+        if (e.key === "Enter") {
+          e.preventDefault();
+          void invoke("confirm_action", { label: pendingConfirm.label }).catch((err) => {
+            console.error("failed to confirm action", err);
+          });
+          setPendingConfirm(null);
+        }
+        if (e.key === "Escape") {
+          e.preventDefault();
+          setPendingConfirm(null);
+        }
+        return;
+      }
+
       if (isActionsOpen) {
         if (e.key === "Escape") {
           e.preventDefault();
@@ -163,6 +245,32 @@ function App() {
         return;
       }
 
+      const scopeIndex = Number(e.key) - 1;
+      if ((e.ctrlKey || e.metaKey) && scopeIndex >= 0 && scopeIndex < snapshot.scopes.length) {
+        e.preventDefault();
+        invoke("set_scope", { index: scopeIndex }).catch((err) => {
+          console.error("failed to set scope", err);
+        });
+        return;
+      }
+
+      const isLoading = snapshot.status === "loading" || snapshot.isLoadingMore;
+      if (e.ctrlKey && (e.key === "c" || e.key === "C") && isLoading) {
+        e.preventDefault();
+        invoke("cancel_loading").catch((err) => {
+          console.error("failed to cancel loading", err);
+        });
+        return;
+      }
+
+      if ((e.ctrlKey || e.metaKey) && (e.key === "r" || e.key === "R")) {
+        e.preventDefault();
+        invoke("refresh").catch((err) => {
+          console.error("failed to refresh", err);
+        });
+        return;
+      }
+
       if (e.key === "ArrowDown" || e.key === "ArrowUp") {
         e.preventDefault();
         const delta = e.key === "ArrowDown" ? 1 : -1;
@@ -170,6 +278,42 @@ function App() {
         return;
       }
 
+      if (e.key === "PageDown" || e.key === "PageUp") {
+        e.preventDefault();
+        const direction = e.key === "PageDown" ? 1 : -1;
+        invoke("set_selection_page", { direction, pageSize: Math.max(1, pageSize) });
+        return;
+      }
+
+      if (e.key === "Tab" && !e.shiftKey) {
+        e.preventDefault();
+        if (snapshot.selectedItem) {
+          const actions: UiAction[] = [
+            { type: "ToggleMark", index: snapshot.selectedItem.index },
+            { type: "SelectDelta", delta: 1 },
+          ];
+          invoke("dispatch", { actions }).catch((err) => {
+            console.error("failed to mark item", err);
+          });
+        }
+        return;
+      }
+
+      if (e.key === "Home" || e.key === "End") {
+        e.preventDefault();
+        const index = e.key === "Home" ? 0 : Math.max(0, snapshot.topItems.length - 1);
+        invoke("set_selection_index", { index });
+        return;
+      }
+
+      if (e.key === "Escape" && e.shiftKey) {
+        e.preventDefault();
+        invoke("reset_to_root").catch((err) => {
+          console.error("failed to reset to root", err);
+        });
+        return;
+      }
+
       if (e.key === "Escape") {
         e.preventDefault();
         invoke("handle_escape").catch((err) => {
@@ -180,8 +324,10 @@ function App() {
 
       if (e.key === "Enter") {
         e.preventDefault();
-        void invoke("invoke_action", { label: "MAIN" }).catch((err) => {
-          console.error("failed to invoke MAIN action", err);
+        // ALT runs MAIN on spells that don't define it.
+        const label = e.altKey || e.metaKey ? "ALT" : "MAIN";
+        void runAction(label).catch((err) => {
+          console.error(`failed to invoke ${label} action`, err);
         });
       }
     };
@@ -195,6 +341,14 @@ function App() {
     isActionsOpen,
     invokeOptionalAction,
     openActionsDialog,
+    pageSize,
+    pendingConfirm,
+    runAction,
+    snapshot.isLoadingMore,
+    snapshot.scopes.length,
+    snapshot.selectedItem,
+    snapshot.status,
+    snapshot.topItems.length,
   ]);
 
   const handleSearchBlur = () => {
@@ -211,7 +365,9 @@ function App() {
   const pageStart = currentPage * effectivePageSize;
   const pageItems = totalItems ? items.slice(pageStart, pageStart + effectivePageSize) : [];
   const showSpinner =
-    snapshot.status === "booting" || snapshot.status === "loading" || snapshot.isFiltering;
+    snapshot.status === "booting" || snapshot.status === "loading" ||
+    snapshot.isFiltering ||
+    snapshot.isLoadingMore;
 
   return (
     <main className="bg-background text-foreground flex h-screen w-full flex-col overflow-hidden p-3 sm:p-4">
@@ -227,9 +383,25 @@ function App() {
                 )}
                 {spellNames.map((name, idx) => (
                   <React.Fragment key={name}>
-                    <BreadcrumbItem>
+                    <BreadcrumbItem title={frameTitle(snapshot.frames[idx])}>
                       {idx === spellNames.length - 1 ? (
                         <BreadcrumbPage>{name}</BreadcrumbPage>
+                      ) : idx === spellNames.length - 2 ? (
+                        <button
+                          type="button"
+                          className="text-foreground/80 hover:text-foreground text-sm"
+                          onClick={() => invoke("pop_frame")}
+                        >
+                          {name}
+                        </button>
+                      ) : idx === 0 ? (
+                        <button
+                          type="button"
+                          className="text-foreground/80 hover:text-foreground text-sm"
+                          onClick={() => invoke("reset_to_root")}
+                        >
+                          {name}
+                        </button>
                       ) : (
                         <span className="text-foreground/80 text-sm">{name}</span>
                       )}
@@ -255,18 +427,64 @@ function App() {
               placeholder="Type to search..."
               onBlur={handleSearchBlur}
               value={snapshot.query}
+              onPaste={() => {
+                pastedRef.current = true;
+              }}
               onChange={(e) => {
                 const value = e.target.value;
                 // Optimistically update local snapshot to keep typing responsive.
                 setSnapshot((prev) => ({ ...prev, query: value }));
-                invoke("set_query", { query: value });
+                const command = pastedRef.current ? "set_query_immediate" : "set_query";
+                pastedRef.current = false;
+                if (value === "") {
+                  invoke("clear_query");
+                } else {
+                  invoke(command, { query: value });
+                }
               }}
             />
             <span className="text-muted-foreground pointer-events-none absolute top-1/2 right-3 -translate-y-1/2 text-xs font-medium select-none">
-              {snapshot.totalItems}
+              {snapshot.isLoadingMore
+                ? `${snapshot.totalItems.toLocaleString()} items…`
+                : snapshot.totalItems}
             </span>
           </div>
 
+          {snapshot.spellErrors.length > 0 && (
+            <div
+              className="text-destructive text-xs"
+              title={snapshot.spellErrors.map(([path, message]) => `${path}: ${message}`).join("\n")}
+            >
+              {snapshot.spellErrors.length === 1
+                ? "1 spell failed to load"
+                : `${snapshot.spellErrors.length} spells failed to load`}
+            </div>
+          )}
+
+          {snapshot.reloadError !== null && (
+            <div className="text-destructive text-xs" title={snapshot.reloadError}>
+              Provider failed: {snapshot.reloadError}
+            </div>
+          )}
+
+          {snapshot.scopes.length > 0 && (
+            <div className="flex gap-1 text-xs" role="tablist">
+              {snapshot.scopes.map((name, idx) => (
+                <button
+                  key={name}
+                  type="button"
+                  role="tab"
+                  aria-selected={snapshot.activeScope === idx}
+                  className="aria-selected:bg-primary/10 aria-selected:border-primary/50 border-border/80 border px-2 py-0.5"
+                  title={idx < 9 ? `Ctrl+${idx + 1}` : undefined}
+                  onClick={() => invoke("set_scope", { index: idx })}
+                >
+                  {name}
+                </button>
+              ))}
+            </div>
+          )}
+
           <section className="flex min-h-0 flex-1 flex-col">
             {pageItems.length ? (
               <div
@@ -276,6 +494,17 @@ function App() {
                 <ItemGroup className="gap-2">
                   {pageItems.map((item, idx) => {
                     const absoluteIdx = pageStart + idx;
+                    if (item.Type === HEADER_ITEM_TYPE) {
+                      return (
+                        <div
+                          key={`${item.Type}-${item.Name}-${absoluteIdx}`}
+                          role="separator"
+                          className="text-muted-foreground border-border/80 border-b px-1 pt-2 pb-1 text-[11px] font-medium tracking-wide uppercase"
+                        >
+                          {item.Name}
+                        </div>
+                      );
+                    }
                     return (
                       <Item
                         key={`${item.Type}-${item.Data}-${absoluteIdx}`}
@@ -285,8 +514,18 @@ function App() {
                         className="data-[selected=true]:bg-primary/10 data-[selected=true]:border-primary/50 border-border/80 border px-3 py-2"
                         data-selected={selectedIndex === absoluteIdx}
                         aria-selected={selectedIndex === absoluteIdx}
+                        onClick={() => invoke("set_selection_index", { index: absoluteIdx })}
                       >
                         <ItemTitle className="w-full min-w-0 gap-2">
+                          {snapshot.markedIndices.length > 0 && (
+                            <input
+                              type="checkbox"
+                              className="shrink-0"
+                              checked={snapshot.markedIndices.includes(absoluteIdx)}
+                              onClick={(e) => e.stopPropagation()}
+                              onChange={() => invoke("toggle_mark", { index: absoluteIdx })}
+                            />
+                          )}
                           <span className="truncate font-mono text-xs">{item.Name}</span>
                           <span className="text-muted-foreground truncate text-[11px] leading-snug font-normal">
                             {item.Data}
@@ -297,9 +536,20 @@ function App() {
                   })}
                 </ItemGroup>
               </div>
+            ) : snapshot.status === "error" ? (
+              <div className="text-destructive text-sm">
+                {snapshot.errorMessage ?? "Something went wrong"}
+              </div>
             ) : (
-              <div className="text-muted-foreground text-sm">No items loaded</div>
+              <div className="text-muted-foreground text-sm">
+                {snapshot.queryError ?? snapshot.emptyMessage ?? "No items loaded"}
+              </div>
             )}
+            {snapshot.preview !== null ? (
+              <pre className="bg-muted/40 text-muted-foreground mt-2 max-h-48 overflow-auto p-2 font-mono text-[11px] whitespace-pre-wrap">
+                {snapshot.preview}
+              </pre>
+            ) : null}
             {pageCount > 1 ? (
               <div className="flex items-center gap-2 pt-2">
                 <nav aria-label="Pagination" className="flex items-center gap-2">
@@ -409,6 +659,26 @@ function App() {
             </div>
           </div>
         ) : null}
+        {pendingConfirm ? (
+          <div className="bg-background/70 fixed inset-0 z-50 flex items-center justify-center backdrop-blur">
+            <div
+              role="alertdialog"
+              aria-modal="true"
+              aria-label={pendingConfirm.prompt}
+              className="border-border/80 bg-card text-card-foreground w-full max-w-md space-y-3 rounded-lg border px-4 py-3 shadow-lg"
+            >
+              <p className="text-foreground text-sm">{pendingConfirm.prompt}</p>
+              <div className="text-muted-foreground flex items-center gap-4 text-xs">
+                <span className="flex items-center gap-2">
+                  <Kbd>Enter</Kbd> Confirm
+                </span>
+                <span className="flex items-center gap-2">
+                  <Kbd>Esc</Kbd> Cancel
+                </span>
+              </div>
+            </div>
+          </div>
+        ) : null}
       </div>
     </main>
   );
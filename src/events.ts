@@ -2,38 +2,149 @@ import { listen, type UnlistenFn } from "@tauri-apps/api/event";
 
 export type AppStatus = "notStarted" | "booting" | "loading" | "ready" | "error";
 
+/** `Type` of section separator rows; they can't be selected. */
+export const HEADER_ITEM_TYPE = "HEADER";
+
 export interface Item {
   Type: string;
   Name: string;
   Data: string;
+  /** Provider columns after Data. */
+  Extra: string[];
+  /** Byte ranges [start, end) of matched characters in the searched field. */
+  matchRanges: [number, number][];
 }
 
-export type ActionType = "CMD" | "SPELL";
+export type ActionType = "CMD" | "SPELL" | "OPEN_URL";
 
 export interface AvailableAction {
   label: string;
   type: ActionType;
 }
 
+/** Returned by the `invoke_action` command. */
+export interface ActionOutcome {
+  keepOpen: boolean;
+  /** The action has a `confirm` prompt; run it with `confirm_action` if accepted. */
+  needsConfirm: boolean;
+  prompt: string | null;
+}
+
+/** One step of a `dispatch` batch; the batch emits a single snapshot. */
+export type UiAction =
+  | { type: "SetQuery"; query: string }
+  | { type: "SelectDelta"; delta: number }
+  | { type: "SelectIndex"; index: number }
+  | { type: "ToggleMark"; index: number }
+  | { type: "Invoke"; label: string };
+
+/** Returned by the `list_actions` command. */
+export interface ActionInfo {
+  label: string;
+  kind: ActionType;
+}
+
+/** Returned by the `list_spells` command; `provider` only with `includeProvider`. */
+export interface SpellInfo {
+  id: string;
+  name: string;
+  alias: string | null;
+  tags: string[];
+  enabled: boolean;
+  isStreaming: boolean;
+  provider?: string;
+}
+
+/** Returned by the `get_items_page` command. */
+export interface ItemsPage {
+  items: Item[];
+  total: number;
+}
+
+/**
+ * Returned by `get_settings` / `update_settings`; keys match `settings.yml`.
+ * `update_settings` takes a partial object, with `null` removing a key.
+ */
+export interface Settings {
+  window_width: number | null;
+  window_height: number | null;
+  window_center: boolean | null;
+  restore_window_geometry: boolean | null;
+  shell: string | null;
+  debug_metrics: boolean | null;
+  log_format: "text" | "json";
+  verbose_log: boolean | null;
+  wrap_selection: boolean | null;
+  start_spell: string | null;
+  clear_on_hide: boolean | null;
+  max_stack_depth: number | null;
+}
+
 export interface SelectedItem {
   index: number;
   details: Item;
   actions: AvailableAction[];
 }
 
+export interface FrameSummary {
+  /** Frame uid, the one `items-appended` batches carry. */
+  id: number;
+  spellId: string;
+  spellName: string;
+  query: string;
+  itemCount: number;
+}
+
 export interface StateSnapshot {
   status: AppStatus;
   noOfSpells: number;
   totalItems: number;
   spellNames: string[];
+  /** One entry per stack frame, root first. */
+  frames: FrameSummary[];
   topItems: Item[];
   query: string;
   isFiltering: boolean;
   selectedItem: SelectedItem | null;
+  errorMessage: string | null;
+  /** Present only with the `debug_metrics` setting. */
+  lastFilterMicros?: number;
+  lastFilterItemCount?: number;
+  /** `[path, message]` per spell file that failed to load. */
+  spellErrors: [string, string][];
+  /** `preview_cmd` output for the selected item, once it has run. */
+  preview: string | null;
+  /** Streamed items are still arriving; `totalItems` is the count so far. */
+  isLoadingMore: boolean;
+  /** Marked indices into `topItems`, ascending. */
+  markedIndices: number[];
+  /** The spell's `empty_message` while it is ready with no items to show. */
+  emptyMessage: string | null;
+  /** Fuzzy score of the selected item; `null` with an empty query. */
+  selectedScore: number | null;
+  /** Names of the spell's `scopes`, in order. */
+  scopes: string[];
+  /** Index into `scopes`; `null` when the spell has none. */
+  activeScope: number | null;
+  /** Items per `Type` over the whole filtered list, not just `topItems`. */
+  typeCounts: Record<string, number>;
+  /** Why the query matches nothing, e.g. an invalid pattern in `regex` mode. */
+  queryError: string | null;
+  /** Why the dynamic spell's provider failed on the last query change. */
+  reloadError: string | null;
+  /** Rows the window should fit; only set with the `auto_height` setting. */
+  visibleRows: number | null;
+}
+
+/** Streamed batch appended to the current frame; the next snapshot supersedes it. */
+export interface ItemsAppended {
+  frameId: number;
+  items: Item[];
 }
 
 interface EventMap {
   "state-snapshot": StateSnapshot;
+  "items-appended": ItemsAppended;
 }
 
 export function listenEvent<K extends keyof EventMap>(